@@ -21,6 +21,7 @@ SYS_RESET: ds 1
 DELAY_CNT: ds 2
 
   psect shared_ram,class=BANK7,space=SPACE_DATA,noexec
+STKPTR_SAVE: ds 1
 RCON_SAVE: ds 1
 RESET_MAGIC: ds 1
 
@@ -28,6 +29,7 @@ RESET_MAGIC: ds 1
 
 bootloader_init:
   movff RCON, RCON_SAVE
+  movff STKPTR, STKPTR_SAVE
 
   ; use SFR register bank for banked accesses (e.g. ANSELx)
   banksel(ANSELA)
@@ -4,190 +4,321 @@
 
 use eyre::Report;
 use gb_cartpp_fwupd::{
-    BootloaderDriver, FirmwareArchive, FirmwareVersion, Unclaimed, Usb, UsbDevice, UsbDeviceKind,
-    VerifyResult,
+    DeviceInfo, DryRunReport, FirmwareImage, UpdateError, UpdateEvent, UpdateOutcome, UpdateRegion,
+    UpdateStage, Updater, Usb, UsbDeviceKind, FLASH_BLOCK_SIZE,
 };
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
-use log::{debug, error, info, log_enabled};
-use std::{
-    process,
-    rc::Rc,
-    thread,
-    time::{Duration, Instant},
-};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::{fmt, sync::Arc, time::Duration};
+
+use crate::output::{emit_json, emit_json_result, LogFormat};
 
-fn poll_after_reset<F: Fn(&UsbDevice<Unclaimed>) -> bool>(
-    usb: &Rc<Usb>,
-    f: F,
-) -> Result<UsbDevice<Unclaimed>, Report> {
-    let start_time = Instant::now();
-    loop {
-        thread::sleep(Duration::from_millis(200));
-        if let Ok(device) = Usb::list_devices(usb)?.into_iter().filter(&f).exactly_one() {
-            break Ok(device);
+pub fn update_firmware(
+    usb: &Arc<Usb>,
+    fw: &FirmwareImage,
+    dry_run: bool,
+    allow_downgrade: bool,
+    log_format: LogFormat,
+) -> Result<(), Report> {
+    let updater = Updater::new(usb)
+        .dry_run(dry_run)
+        .allow_downgrade(allow_downgrade);
+    if log_format.is_json() {
+        let mut ranges = RangeAggregator::default();
+        let result = updater.update(fw, |event| {
+            let (ended, merged) = ranges.push(&event);
+            if let Some(ended) = ended {
+                emit_json(&ended);
+            }
+            if !merged {
+                emit_json(&event);
+            }
+        });
+        if let Some(aggregated) = ranges.flush() {
+            emit_json(&aggregated);
         }
-        if start_time.elapsed() > Duration::from_secs(10) {
-            error!("Failed to detect device after reset");
-            process::exit(1);
+        emit_json_result(&result);
+        result?;
+        return Ok(());
+    }
+
+    let mut reporter = TextReporter::new()?;
+    let result = updater.update(fw, |event| reporter.handle(event));
+    reporter.flush_ranges();
+    if let Some(progress) = reporter.progress.take() {
+        progress.abandon();
+    }
+    match result {
+        Ok(UpdateOutcome::UpToDate(_)) => {
+            info!("No update is necessary, the device already has identical firmware")
+        }
+        Ok(UpdateOutcome::Updated(info)) => match info.kind {
+            UsbDeviceKind::Firmware { fw_version, .. } => {
+                info!("Firmware updated to v{}", fw_version)
+            }
+            _ => info!("Firmware updated"),
+        },
+        Ok(UpdateOutcome::DryRun(_, report)) => print_dry_run_report(&report),
+        Err(UpdateError::NoDevices) => {
+            for info in &reporter.unusable {
+                error!("Detected but unusable {}", info);
+            }
+            return Err(UpdateError::NoDevices.into());
+        }
+        Err(err @ UpdateError::Downgrade { .. }) => {
+            error!("Use --allow-downgrade to install older firmware anyway");
+            return Err(err.into());
         }
+        Err(err) => return Err(err.into()),
     }
+    Ok(())
 }
 
-pub fn update_firmware(fw: FirmwareArchive) -> Result<(), Report> {
-    let fw = fw.decode()?;
+struct TextReporter {
+    style: ProgressStyle,
+    error_style: ProgressStyle,
+    progress: Option<ProgressBar>,
+    unusable: Vec<DeviceInfo>,
+    ranges: RangeAggregator,
+}
 
-    let usb = Usb::init()?;
-    let devices = Usb::list_devices(&usb)?;
-    debug!("Detected {} candidate devices", devices.len());
-    let ready_devices = devices
-        .iter()
-        .map(|dev| match dev.kind {
-            UsbDeviceKind::Bootloader { .. } | UsbDeviceKind::Firmware { .. } => 1,
-            _ => 0,
+impl TextReporter {
+    fn new() -> Result<TextReporter, Report> {
+        Ok(TextReporter {
+            style: ProgressStyle::default_bar().template("{msg} {bar} {percent} %")?,
+            error_style: ProgressStyle::default_bar()
+                .template("{msg} {bar:.red} {percent} % {prefix:.red}")?,
+            progress: None,
+            unusable: Vec::new(),
+            ranges: RangeAggregator::default(),
         })
-        .sum::<u32>();
-    if ready_devices == 0 {
-        error!("No GB-CARTPP-XC devices detected");
-        for device in &devices {
-            error!("Detected but unusable {}", device);
-        }
-        process::exit(1);
-    } else if ready_devices > 1 {
-        error!(
-            "{} GB-CARTPP-XC devices detected, but only one can be connected during firmware update",
-            ready_devices
-        );
-        process::exit(1);
     }
-    if log_enabled!(log::Level::Debug) {
-        for device in &devices {
-            debug!("{}", device);
+    fn flush_ranges(&mut self) {
+        if let Some(aggregated) = self.ranges.flush() {
+            debug!("{}", aggregated);
         }
     }
-    let mut device = devices
-        .into_iter()
-        .filter(|dev| {
-            matches!(
-                dev.kind,
-                UsbDeviceKind::Bootloader { .. } | UsbDeviceKind::Firmware { .. }
-            )
-        })
-        .exactly_one()
-        .unwrap();
-    info!("Using {}", device);
-    if !device.kind.is_bootloader() {
-        debug!("Resetting {}", device);
-        let address_before_reset = device.usb_address();
-        let _ = device.enter_bootloader();
-        device = poll_after_reset(&usb, |d| {
-            d.usb_address() != address_before_reset && d.kind.is_bootloader()
-        })?;
+    fn handle(&mut self, event: UpdateEvent) {
+        let (ended, merged) = self.ranges.push(&event);
+        if let Some(ended) = ended {
+            debug!("{}", ended);
+        }
+        if merged {
+            return;
+        }
+        if let UpdateEvent::StageStarted { .. } = event {
+            if let Some(progress) = self.progress.take() {
+                progress.finish();
+            }
+        }
+        match event {
+            UpdateEvent::DeviceDetected(info) => {
+                debug!("{}", info);
+                if info.kind == UsbDeviceKind::Unusable {
+                    self.unusable.push(info);
+                }
+            }
+            UpdateEvent::DeviceSelected(info) => info!("Using {}", info),
+            UpdateEvent::StageStarted {
+                stage: stage @ (UpdateStage::WriteFlash | UpdateStage::VerifyFlash),
+            } => {
+                let bar = ProgressBar::new(0).with_style(self.style.clone());
+                bar.enable_steady_tick(Duration::from_millis(16));
+                bar.set_message(format!("{}:", stage));
+                self.progress = Some(bar);
+            }
+            UpdateEvent::StageStarted {
+                stage: stage @ UpdateStage::EnterBootloader,
+            } => debug!("{}", stage),
+            UpdateEvent::StageStarted {
+                stage: UpdateStage::Checksum,
+            } => (),
+            UpdateEvent::StageStarted { stage } => info!("{}", stage),
+            UpdateEvent::BlockWritten { .. } | UpdateEvent::VerifyError { .. } => (),
+            UpdateEvent::Progress {
+                done,
+                total,
+                errors,
+                ..
+            } => {
+                if let Some(progress) = &self.progress {
+                    if errors > 0 && progress.prefix().is_empty() {
+                        progress.set_style(self.error_style.clone());
+                        progress.set_prefix("errors detected");
+                    }
+                    progress.set_length(total as u64);
+                    progress.set_position(done as u64);
+                }
+            }
+            UpdateEvent::Compared {
+                image_version,
+                image_checksum,
+                device_version,
+                device_checksum,
+            } => {
+                info!(
+                    "Firmware image: v{} (checksum 0x{:04x})",
+                    image_version, image_checksum
+                );
+                info!(
+                    "Device:         v{} (checksum 0x{:04x})",
+                    device_version, device_checksum
+                );
+                if !image_version.is_unknown() && image_version < device_version {
+                    warn!("The firmware image is older than the firmware on the device");
+                }
+            }
+            UpdateEvent::InvalidImageRomCrc(crc) => warn!(
+                "Firmware image has an invalid ROM CRC ({}), so the device will stay in the bootloader after the update",
+                crc
+            ),
+            UpdateEvent::ConfigMismatch {
+                addr,
+                expected,
+                actual,
+            } => warn!(
+                "Config byte {:#08x} differs from the image and will not be updated: {:02x} on the device, {:02x} in the image",
+                addr, actual, expected
+            ),
+        }
     }
-    let address = device.usb_address();
-    let drv = BootloaderDriver::initialize(device)?;
-    let fw_checksum = drv.calc_flash_checksum()?;
+}
 
-    let image_checksum = crc16::State::<crc16::XMODEM>::calculate(&fw.flash[0x800..]);
-    let image_version = FirmwareVersion {
-        major: fw.id[3],
-        minor: fw.id[2],
-    };
-    info!(
-        "Firmware image: v{} (checksum 0x{:04x})",
-        image_version, image_checksum
-    );
-    info!(
-        "Device:         v{} (checksum 0x{:04x})",
-        drv.firmware_version(),
-        fw_checksum
-    );
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+struct AddrRange {
+    start: u32,
+    /// Inclusive
+    end: u32,
+}
 
-    if drv.firmware_version() == image_version && fw_checksum == image_checksum {
-        info!("No update is necessary");
-        drv.reset()?;
-        poll_after_reset(&usb, |d| d.usb_address() != address && d.kind.is_firmware())?;
-        return Ok(());
-    }
+/// Consecutive `BlockWritten` or `VerifyError` events, merged into address ranges
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum RangeEvent {
+    BlocksWritten {
+        blocks: u32,
+        ranges: Vec<AddrRange>,
+    },
+    VerifyErrors {
+        region: UpdateRegion,
+        errors: u32,
+        ranges: Vec<AddrRange>,
+    },
+}
 
-    let progress = ProgressBar::new(0x8000 - 0x800)
-        .with_style(ProgressStyle::default_bar().template("{msg} {bar} {percent} %")?);
-    progress.enable_steady_tick(Duration::from_millis(16));
-    progress.set_message("Updating flash: ");
-    drv.write_flash(&fw, |addr| {
-        progress.set_position((addr - 0x800) as u64);
-    })?;
-    progress.finish();
+impl fmt::Display for RangeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ranges = |ranges: &[AddrRange]| {
+            ranges
+                .iter()
+                .map(|range| format!("{:#06x}-{:#06x}", range.start, range.end))
+                .join(", ")
+        };
+        match self {
+            RangeEvent::BlocksWritten { blocks, ranges: r } => {
+                write!(f, "Wrote {} flash blocks at {}", blocks, ranges(r))
+            }
+            RangeEvent::VerifyErrors {
+                region,
+                errors,
+                ranges: r,
+            } => write!(f, "{} verify errors in {} at {}", errors, region, ranges(r)),
+        }
+    }
+}
 
-    info!("Updating ID bytes");
-    drv.write_id(&fw)?;
+/// Merges consecutive `BlockWritten` and `VerifyError` events. Progress events don't end a range,
+/// but all other events do
+#[derive(Debug, Default)]
+struct RangeAggregator {
+    pending: Option<RangeEvent>,
+}
 
-    let style = ProgressStyle::default_bar().template("{msg} {bar} {percent} %")?;
-    let error_style =
-        ProgressStyle::default_bar().template("{msg} {bar:.red} {percent} % {prefix:.red}")?;
-    let progress = ProgressBar::new(0x8000 - 0x800).with_style(style);
-    progress.enable_steady_tick(Duration::from_millis(16));
-    progress.set_message("Verifying flash:");
-    let mut errored = false;
-    let result = drv.verify_flash(&fw, |addr, result| {
-        if let VerifyResult::Invalid { .. } = result {
-            if !errored {
-                progress.set_style(error_style.clone());
-                progress.set_prefix("errors detected");
-                errored = true;
-            }
+impl RangeAggregator {
+    /// Returns the range that ended before the event, and whether the event was merged into a
+    /// range instead of having to be handled by the caller
+    fn push(&mut self, event: &UpdateEvent) -> (Option<RangeEvent>, bool) {
+        let (region, addr, len) = match *event {
+            UpdateEvent::BlockWritten { addr } => (None, addr, FLASH_BLOCK_SIZE as u32),
+            UpdateEvent::VerifyError { region, addr, .. } => (Some(region), addr, 1),
+            UpdateEvent::Progress { .. } => return (None, false),
+            _ => return (self.pending.take(), false),
+        };
+        let continues = match &self.pending {
+            Some(RangeEvent::BlocksWritten { .. }) => region.is_none(),
+            Some(RangeEvent::VerifyErrors {
+                region: pending_region,
+                ..
+            }) => region == Some(*pending_region),
+            None => false,
+        };
+        let ended = if continues { None } else { self.pending.take() };
+        let (count, ranges) = match self.pending.get_or_insert_with(|| match region {
+            None => RangeEvent::BlocksWritten {
+                blocks: 0,
+                ranges: Vec::new(),
+            },
+            Some(region) => RangeEvent::VerifyErrors {
+                region,
+                errors: 0,
+                ranges: Vec::new(),
+            },
+        }) {
+            RangeEvent::BlocksWritten { blocks, ranges } => (blocks, ranges),
+            RangeEvent::VerifyErrors { errors, ranges, .. } => (errors, ranges),
+        };
+        *count += 1;
+        let end = addr + (len - 1);
+        match ranges.last_mut() {
+            Some(range) if range.end.checked_add(1) == Some(addr) => range.end = end,
+            _ => ranges.push(AddrRange { start: addr, end }),
         }
-        progress.set_position((addr - 0x800) as u64);
-    })?;
-    progress.finish();
-    if let VerifyResult::Invalid {
-        errors,
-        first_error_addr,
-    } = result
-    {
-        error!(
-            "Updating flash failed: {} errors, starting at {:#06x}",
-            errors, first_error_addr
-        );
-        process::exit(1);
+        (ended, true)
+    }
+    fn flush(&mut self) -> Option<RangeEvent> {
+        self.pending.take()
     }
+}
 
-    info!("Verifying ID bytes");
-    let result = drv.verify_id(&fw)?;
-    if let VerifyResult::Invalid {
-        errors,
-        first_error_addr,
-    } = result
-    {
-        error!(
-            "Updating ID bytes failed: {} errors, starting at {:#06x}",
-            errors, first_error_addr
+fn print_dry_run_report(report: &DryRunReport) {
+    for change in &report.config_changes {
+        warn!(
+            "Config byte {:#08x} differs from the image: {:02x} on the device, {:02x} in the image",
+            change.addr, change.current, change.new
         );
-        process::exit(1);
     }
-
-    info!("Verifying config bytes");
-    let result = drv.verify_cfg(&fw)?;
-    if let VerifyResult::Invalid {
-        errors,
-        first_error_addr,
-    } = result
-    {
-        error!(
-            "Invalid config bytes: {} errors, starting at {:#06x}",
-            errors, first_error_addr
+    if report.is_up_to_date() {
+        info!("Dry run: no update is necessary");
+        return;
+    }
+    for block in &report.flash_changes {
+        info!(
+            "Flash block {:#06x}: {} bytes would change{}",
+            block.addr,
+            block.bytes.len(),
+            if block.erase_only { " (erase)" } else { "" }
+        );
+        for change in &block.bytes {
+            debug!(
+                "  {:#06x}: {:02x} -> {:02x}",
+                change.addr, change.current, change.new
+            );
+        }
+    }
+    for change in &report.id_changes {
+        info!(
+            "ID byte {:#08x}: {:02x} -> {:02x}",
+            change.addr, change.current, change.new
         );
-        process::exit(1);
     }
-
-    info!("Resetting device");
-    drv.reset()?;
-    device = poll_after_reset(&usb, |d| d.usb_address() != address && d.kind.is_firmware())?;
     info!(
-        "Firmware updated to v{}.{}",
-        device.version().0,
-        device.version().1
+        "Dry run: {} bytes in {} flash blocks and {} ID bytes would change (checksum 0x{:04x} -> 0x{:04x})",
+        report.changed_bytes(),
+        report.flash_changes.len(),
+        report.id_changes.len(),
+        report.device_checksum,
+        report.image_checksum
     );
-
-    Ok(())
 }
@@ -0,0 +1,44 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use eyre::{bail, Context, Report};
+use gb_cartpp_fwupd::{device_guid, CabRelease, FirmwareFile};
+use log::{info, warn};
+use std::{fs::File, path::PathBuf};
+
+use crate::update::read_firmware;
+
+pub fn export_cab_cmd(
+    input: &PathBuf,
+    output: &PathBuf,
+    release_notes: String,
+    date: Option<String>,
+) -> Result<(), Report> {
+    let archive = match read_firmware(input)? {
+        FirmwareFile::Archive(archive) => archive,
+        FirmwareFile::Raw { format, .. } => {
+            bail!(
+                "Only firmware archives can be exported, not {} files",
+                format
+            )
+        }
+    };
+    if !archive.has_signature() {
+        warn!("The firmware image has no digital signature!");
+    }
+    let release = CabRelease {
+        release_notes,
+        date,
+    };
+    let file = File::create(output).wrap_err("Failed to create cabinet file")?;
+    archive
+        .to_cab(&release, file)
+        .wrap_err("Failed to write cabinet file")?;
+    info!(
+        "Wrote {} for device GUID {}",
+        output.display(),
+        device_guid()
+    );
+    Ok(())
+}
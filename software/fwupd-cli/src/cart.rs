@@ -0,0 +1,93 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use eyre::{eyre, Context, Report};
+use gb_cartpp_fwupd::{CartDriver, CartStatus, CartVerdict, Updater, Usb};
+use log::info;
+use std::{io, thread, time::Duration};
+
+pub fn cart_power_cmd(action: &str, delay: Duration) -> Result<(), Report> {
+    let usb = Usb::init().wrap_err("Failed to initialize libusb")?;
+    let device = Updater::new(&usb).select_device(|_| ())?;
+    if !device.kind.is_firmware() {
+        return Err(eyre!("{} is not running the main firmware", device));
+    }
+    info!("Using {}", device);
+    let mut drv = CartDriver::initialize(device)?;
+    match action {
+        "status" => print_status(&drv.status()?),
+        "on" => {
+            drv.power_on()?;
+            print_status(&drv.status()?);
+            // the firmware powers the cartridge off as soon as the session ends
+            info!("Cartridge powered on, press Enter to power it off");
+            io::stdin().read_line(&mut String::new())?;
+        }
+        "off" => {
+            drv.power_off()?;
+            print_status(&drv.status()?);
+        }
+        "cycle" => {
+            drv.power_on()?;
+            drv.power_cycle(delay)?;
+            print_status(&drv.status()?);
+        }
+        "reset" => {
+            drv.power_on()?;
+            drv.assert_reset()?;
+            thread::sleep(delay);
+            drv.release_reset()?;
+            print_status(&drv.status()?);
+        }
+        action => return Err(eyre!("Unknown action {}", action)),
+    }
+    drv.finish()?;
+    info!("Cartridge powered off, it can be removed now");
+    Ok(())
+}
+
+fn print_status(status: &CartStatus) {
+    info!(
+        "Cartridge power: {}, /RES: {} (pin {})",
+        if status.is_powered() { "on" } else { "off" },
+        if status.is_in_reset() {
+            "asserted"
+        } else {
+            "released"
+        },
+        if status.reset_pin { "high" } else { "low" }
+    );
+}
+
+pub fn check_cart_cmd(passes: usize) -> Result<(), Report> {
+    let usb = Usb::init().wrap_err("Failed to initialize libusb")?;
+    let device = Updater::new(&usb).select_device(|_| ())?;
+    if !device.kind.is_firmware() {
+        return Err(eyre!("{} is not running the main firmware", device));
+    }
+    info!("Using {}", device);
+    let mut drv = CartDriver::initialize(device)?;
+    let check = drv.check(passes)?;
+    drv.finish()?;
+
+    let verdict = check.verdict();
+    if verdict != CartVerdict::NoCartridge {
+        let header = &check.header;
+        info!(
+            "Title: \"{}\", cartridge type {:#04x}, ROM size {}",
+            header.title(),
+            header.cart_type(),
+            header
+                .rom_size()
+                .map(|size| format!("{} KiB", size / 1024))
+                .unwrap_or_else(|| "unknown".to_string())
+        );
+    }
+    if verdict.is_ok() {
+        info!("Cartridge check: {} ({} reads)", verdict, check.passes);
+        Ok(())
+    } else {
+        Err(eyre!("Cartridge check: {}", verdict))
+    }
+}
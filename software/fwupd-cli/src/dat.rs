@@ -0,0 +1,41 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use eyre::{eyre, Context, Report};
+use gb_cartpp_fwupd::{to_hex, CartHeader, Dat, DatMatch, RomHashes};
+use log::{info, warn};
+use std::{fs, path::Path};
+
+pub fn verify_dump_cmd(input: &Path, dat: &Path) -> Result<(), Report> {
+    let data = fs::read(input).wrap_err_with(|| format!("Failed to read {}", input.display()))?;
+    if let Some(header) = CartHeader::from_rom(&data) {
+        info!("Title: \"{}\"", header.title());
+    }
+    verify_dump(&data, dat)
+}
+
+/// Prints the hashes of a dump and checks them against a DAT file
+pub fn verify_dump(data: &[u8], dat: &Path) -> Result<(), Report> {
+    let text =
+        fs::read_to_string(dat).wrap_err_with(|| format!("Failed to read {}", dat.display()))?;
+    let dat = Dat::parse(&text).wrap_err_with(|| format!("Failed to parse {}", dat.display()))?;
+    let hashes = RomHashes::calculate(data);
+    info!("Size:    {} bytes", hashes.size);
+    info!("CRC32:   {:08x}", hashes.crc32);
+    info!("MD5:     {}", to_hex(&hashes.md5));
+    info!("SHA-1:   {}", to_hex(&hashes.sha1));
+    info!("SHA-256: {}", to_hex(&hashes.sha256));
+    match dat.identify(data) {
+        result @ DatMatch::Verified { .. } => info!("Dump {}", result),
+        DatMatch::Unknown => warn!(
+            "Dump is unknown to {}",
+            dat.name.as_deref().unwrap_or("the DAT file")
+        ),
+        result @ DatMatch::Ambiguous { .. } => warn!("Dump is {}", result),
+        result @ (DatMatch::BadDump { .. } | DatMatch::KnownBadDump { .. }) => {
+            return Err(eyre!("Dump is a {}", result))
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use eyre::{bail, Context, Report};
+use gb_cartpp_fwupd::{DeviceProbe, Usb, BOOTLOADER_PRODUCT_ID, FIRMWARE_PRODUCT_ID, VENDOR_ID};
+use log::{error, info, warn};
+use std::{fs, io::ErrorKind, path::Path};
+
+pub const DEFAULT_UDEV_RULE_PATH: &str = "/etc/udev/rules.d/70-gb-cartpp.rules";
+
+fn udev_rule() -> String {
+    let mut rule = String::from("# GB-CARTPP-XC bootloader and firmware\n");
+    for product_id in [BOOTLOADER_PRODUCT_ID, FIRMWARE_PRODUCT_ID] {
+        rule.push_str(&format!(
+            "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", TAG+=\"uaccess\"\n",
+            VENDOR_ID, product_id
+        ));
+    }
+    rule
+}
+
+pub fn doctor_cmd(install_udev_rule: bool, udev_rule_path: &Path) -> Result<(), Report> {
+    if install_udev_rule {
+        install_rule(udev_rule_path)?;
+    }
+
+    let usb = Usb::init().wrap_err("Failed to initialize libusb")?;
+    let probes = Usb::probe_devices(&usb).wrap_err("Failed to list USB devices")?;
+    if probes.is_empty() {
+        warn!(
+            "No devices with USB id {:04x}:{:04x} or {:04x}:{:04x} found",
+            VENDOR_ID, FIRMWARE_PRODUCT_ID, VENDOR_ID, BOOTLOADER_PRODUCT_ID
+        );
+        warn!("Check the cable, and try another USB port or cable if the device has no power");
+    }
+    for probe in &probes {
+        report(probe);
+    }
+
+    if cfg!(target_os = "linux") {
+        let rule_installed = udev_rule_path.exists();
+        if rule_installed {
+            info!("udev rule: {}", udev_rule_path.display());
+        } else {
+            info!("udev rule: not installed at {}", udev_rule_path.display());
+        }
+        if probes.iter().any(DeviceProbe::access_denied) {
+            error!("The device can't be opened because the current user has no access to it");
+            if rule_installed {
+                error!("A udev rule is installed, so reconnect the device or reload the rules with `udevadm control --reload-rules && udevadm trigger`");
+            } else {
+                error!("Without a udev rule only root can access the device. Install one by running this command as root with --install-udev-rule, or add this to {}:", udev_rule_path.display());
+                for line in udev_rule().lines() {
+                    error!("  {}", line);
+                }
+            }
+            bail!("Insufficient permissions to access the device");
+        }
+    }
+    Ok(())
+}
+
+fn report(probe: &DeviceProbe) {
+    let mode = if probe.is_bootloader() {
+        "bootloader"
+    } else {
+        "firmware"
+    };
+    match &probe.open {
+        Ok(()) => info!(
+            "USB device {}: GB-CARTPP-XC ({}), access OK",
+            probe.id, mode
+        ),
+        Err(err) => error!(
+            "USB device {}: GB-CARTPP-XC ({}), failed to open: {}",
+            probe.id, mode, err
+        ),
+    }
+    for (interface, active) in &probe.kernel_drivers {
+        match active {
+            // the firmware is a CDC ACM device, so cdc_acm is expected to bind to it.
+            // LibusbTransport::claim_interface enables libusb auto-detach where supported, so the
+            // driver is detached when the interface is claimed
+            Ok(true) if !probe.is_bootloader() => {
+                info!("  interface {}: kernel driver attached", interface)
+            }
+            Ok(true) => warn!(
+                "  interface {}: unexpected kernel driver attached",
+                interface
+            ),
+            Ok(false) => info!("  interface {}: no kernel driver", interface),
+            Err(err) => info!(
+                "  interface {}: kernel driver state unknown ({})",
+                interface, err
+            ),
+        }
+    }
+}
+
+fn install_rule(path: &Path) -> Result<(), Report> {
+    if !cfg!(target_os = "linux") {
+        bail!("udev rules are only used on Linux");
+    }
+    let rule = udev_rule();
+    match fs::read_to_string(path) {
+        Ok(existing) if existing == rule => {
+            info!("udev rule {} is already up to date", path.display());
+            return Ok(());
+        }
+        Ok(_) => warn!("Replacing existing udev rule {}", path.display()),
+        Err(err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(err).wrap_err("Failed to read existing udev rule"),
+    }
+    match fs::write(path, rule) {
+        Ok(()) => {
+            info!("Installed udev rule {}", path.display());
+            info!("Reconnect the device, or reload the rules with `udevadm control --reload-rules && udevadm trigger`");
+            Ok(())
+        }
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            Err(err).wrap_err("Failed to install udev rule (try running as root)")
+        }
+        Err(err) => Err(err).wrap_err("Failed to install udev rule"),
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use eyre::{eyre, Context, Report};
+use gb_cartpp_fwupd::{CartDriver, DumpEvent, Mapper, ReadTiming, RomDumper, Updater, Usb};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use std::{fs, path::Path, time::Duration};
+
+use crate::dat::verify_dump;
+
+pub fn dump_rom_cmd(
+    output: &Path,
+    passes: usize,
+    max_reads: usize,
+    timing: ReadTiming,
+    force_ce_fallback: bool,
+    mapper: Option<Mapper>,
+    dat: Option<&Path>,
+) -> Result<(), Report> {
+    let usb = Usb::init().wrap_err("Failed to initialize libusb")?;
+    let device = Updater::new(&usb).select_device(|_| ())?;
+    if !device.kind.is_firmware() {
+        return Err(eyre!("{} is not running the main firmware", device));
+    }
+    info!("Using {}", device);
+    let mut drv = CartDriver::initialize(device)?;
+
+    let dumper = RomDumper::new()
+        .passes(passes)
+        .max_reads(max_reads)
+        .timing(timing)
+        .force_ce_fallback(force_ce_fallback)
+        .mapper(mapper);
+    let style = ProgressStyle::default_bar().template("{msg} {bar} {pos}/{len} banks")?;
+    let mut progress = None;
+    let result = dumper.dump(&mut drv, |event| match event {
+        DumpEvent::Started { header, banks } => {
+            info!(
+                "Title: \"{}\", {}, {} KiB",
+                header.title(),
+                header.mapper(),
+                banks * 16
+            );
+            let bar = ProgressBar::new(banks as u64).with_style(style.clone());
+            bar.enable_steady_tick(Duration::from_millis(16));
+            bar.set_message("Dumping ROM:");
+            progress = Some(bar);
+        }
+        DumpEvent::BankRead(stats) => {
+            debug!(
+                "Bank {}: {} reads, {} distinct ({} timing)",
+                stats.bank, stats.reads, stats.distinct, stats.timing
+            );
+            if let Some(bar) = &progress {
+                bar.inc(1);
+            }
+        }
+    });
+    if let Some(bar) = progress.take() {
+        bar.finish();
+    }
+    let dump = result?;
+    drv.finish()?;
+
+    for stats in dump.inconsistent_banks() {
+        warn!(
+            "Bank {} read inconsistently ({} reads, {} distinct results, {} timing)",
+            stats.bank, stats.reads, stats.distinct, stats.timing
+        );
+    }
+    fs::write(output, &dump.data)
+        .wrap_err_with(|| format!("Failed to write {}", output.display()))?;
+    info!(
+        "Saved {} KiB to {} ({} of {} banks needed retries)",
+        dump.data.len() / 1024,
+        output.display(),
+        dump.inconsistent_banks().count(),
+        dump.banks.len()
+    );
+    match dat {
+        Some(dat) => verify_dump(&dump.data, dat),
+        None => Ok(()),
+    }
+}
@@ -2,16 +2,30 @@
 //
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use clap::{builder::PathBufValueParser, Arg, ArgAction, Command};
+use clap::{
+    builder::{PathBufValueParser, PossibleValuesParser},
+    Arg, ArgAction, Command,
+};
 use eyre::{eyre, Report};
 
+use gb_cartpp_fwupd::{Mapper, ReadTiming, CHECK_PASSES, DUMP_MAX_READS, DUMP_PASSES};
 use log::error;
 use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode};
-use std::{path::PathBuf, process};
+use std::{path::PathBuf, process, time::Duration};
 
 mod bootloader;
+mod cab;
+mod cart;
+mod dat;
+mod doctor;
+mod dump;
+mod output;
+mod post_mortem;
+mod sfr;
 mod update;
 
+use crate::output::LogFormat;
+
 fn build_cmd() -> Command {
     Command::new("gbcartpp-fwupd")
         .version(env!("CARGO_PKG_VERSION"))
@@ -23,12 +37,21 @@ fn build_cmd() -> Command {
                 .help("Sets the level of verbosity")
                 .global(true),
         )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .value_parser(PossibleValuesParser::new(["text", "json"]))
+                .default_value("text")
+                .help("Sets the output format. json writes one event object per line to stdout")
+                .global(true),
+        )
         .subcommand(
             Command::new("update-firmware")
                 .about("Update the firmware of a GB-CARTPP device")
                 .arg(
                     Arg::new("input")
-                        .help("Firmware image file")
+                        .help("Firmware archive or cabinet, or an Intel HEX, binary or ELF file from a development build")
                         .value_name("FILE")
                         .value_parser(PathBufValueParser::new()),
                 )
@@ -37,6 +60,233 @@ fn build_cmd() -> Command {
                         .long("allow-invalid-signature")
                         .action(ArgAction::SetTrue)
                         .help("Allow flashing firmware without a valid signature"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Report what would change without erasing or writing anything"),
+                )
+                .arg(
+                    Arg::new("allow-downgrade")
+                        .long("allow-downgrade")
+                        .action(ArgAction::SetTrue)
+                        .help("Allow flashing firmware older than the firmware on the device"),
+                )
+                .arg(
+                    Arg::new("capture")
+                        .long("capture")
+                        .value_name("CAPTURE")
+                        .value_parser(PathBufValueParser::new())
+                        .help("Record all USB traffic to a file for bug reports"),
+                ),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Diagnose USB access and driver problems")
+                .arg(
+                    Arg::new("install-udev-rule")
+                        .long("install-udev-rule")
+                        .action(ArgAction::SetTrue)
+                        .help("Install a udev rule that gives logged-in users access to the device (Linux, requires root)"),
+                )
+                .arg(
+                    Arg::new("udev-rule-path")
+                        .long("udev-rule-path")
+                        .value_name("PATH")
+                        .value_parser(PathBufValueParser::new())
+                        .default_value(doctor::DEFAULT_UDEV_RULE_PATH)
+                        .help("Location of the udev rule file"),
+                ),
+        )
+        .subcommand(
+            Command::new("post-mortem")
+                .about("Show why a device reset into the bootloader, and the firmware panic message if there is one"),
+        )
+        .subcommand(
+            Command::new("dump-sfrs")
+                .about("Decode the special function registers of a device, or a saved dump")
+                .arg(
+                    Arg::new("input")
+                        .help("Saved SFR dump to decode instead of reading a device")
+                        .value_name("FILE")
+                        .value_parser(PathBufValueParser::new()),
+                )
+                .arg(
+                    Arg::new("diff")
+                        .long("diff")
+                        .value_name("OTHER")
+                        .value_parser(PathBufValueParser::new())
+                        .help("Only show registers that differ from another saved dump"),
+                )
+                .arg(
+                    Arg::new("save")
+                        .long("save")
+                        .value_name("PATH")
+                        .value_parser(PathBufValueParser::new())
+                        .help("Save the raw dump to a file"),
+                ),
+        )
+        .subcommand(
+            Command::new("export-cab")
+                .about("Wrap a firmware archive into a cabinet file for LVFS/fwupd")
+                .arg(
+                    Arg::new("input")
+                        .help("Firmware archive file")
+                        .value_name("FILE")
+                        .required(true)
+                        .value_parser(PathBufValueParser::new()),
+                )
+                .arg(
+                    Arg::new("output")
+                        .help("Cabinet file to create")
+                        .value_name("CAB")
+                        .required(true)
+                        .value_parser(PathBufValueParser::new()),
+                )
+                .arg(
+                    Arg::new("release-notes")
+                        .long("release-notes")
+                        .value_name("TEXT")
+                        .required(true)
+                        .help("Release notes. Separate paragraphs with blank lines"),
+                )
+                .arg(
+                    Arg::new("date")
+                        .long("date")
+                        .value_name("YYYY-MM-DD")
+                        .help("Release date"),
+                ),
+        )
+        .subcommand(
+            Command::new("cart-power")
+                .about("Control cartridge power and reset. The cartridge is powered off when the command exits")
+                .arg(
+                    Arg::new("action")
+                        .help("on keeps the cartridge powered until Enter is pressed, reset pulses /RES")
+                        .value_name("ACTION")
+                        .value_parser(PossibleValuesParser::new(["status", "on", "off", "cycle", "reset"]))
+                        .default_value("status"),
+                )
+                .arg(
+                    Arg::new("delay")
+                        .long("delay")
+                        .value_name("MS")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("500")
+                        .help("How long the cartridge stays off (cycle) or in reset (reset)"),
+                ),
+        )
+        .subcommand(
+            Command::new("check-cart")
+                .about("Check that a cartridge is inserted and its contacts are clean")
+                .arg(
+                    Arg::new("passes")
+                        .long("passes")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64).range(2..))
+                        .help("How many times the header is read"),
+                ),
+        )
+        .subcommand(
+            Command::new("dump-rom")
+                .about("Dump a cartridge ROM, reading every bank until the reads agree")
+                .arg(
+                    Arg::new("output")
+                        .help("ROM file to create")
+                        .value_name("FILE")
+                        .required(true)
+                        .value_parser(PathBufValueParser::new()),
+                )
+                .arg(
+                    Arg::new("passes")
+                        .long("passes")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64).range(2..))
+                        .help("How many times every bank is read"),
+                )
+                .arg(
+                    Arg::new("max-reads")
+                        .long("max-reads")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64).range(2..))
+                        .help("How many times an inconsistent bank is read before giving up"),
+                )
+                .arg(
+                    Arg::new("force-ce")
+                        .long("force-ce")
+                        .action(ArgAction::SetTrue)
+                        .help("Toggle chip select for every byte. Slower, but works with more cartridges"),
+                )
+                .arg(
+                    Arg::new("force-ce-fallback")
+                        .long("force-ce-fallback")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("force-ce")
+                        .help("Retry inconsistent banks with --force-ce timing"),
+                )
+                .arg(
+                    Arg::new("mapper")
+                        .long("mapper")
+                        .value_name("MAPPER")
+                        .value_parser(PossibleValuesParser::new(["none", "mbc1", "mbc2", "mbc3", "mbc5"]))
+                        .help("Overrides the mapper in the cartridge header. Required for unknown mappers"),
+                )
+                .arg(
+                    Arg::new("dat")
+                        .long("dat")
+                        .value_name("DAT")
+                        .value_parser(PathBufValueParser::new())
+                        .help("Verify the dump against a No-Intro/Logiqx DAT file"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify-dump")
+                .about("Verify a ROM file against a No-Intro/Logiqx DAT file without a device")
+                .arg(
+                    Arg::new("input")
+                        .help("ROM file")
+                        .value_name("FILE")
+                        .required(true)
+                        .value_parser(PathBufValueParser::new()),
+                )
+                .arg(
+                    Arg::new("dat")
+                        .long("dat")
+                        .value_name("DAT")
+                        .required(true)
+                        .value_parser(PathBufValueParser::new())
+                        .help("DAT file"),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Replay a firmware update from a USB traffic capture without a device")
+                .arg(
+                    Arg::new("capture")
+                        .help("USB traffic capture file")
+                        .value_name("CAPTURE")
+                        .required(true)
+                        .value_parser(PathBufValueParser::new()),
+                )
+                .arg(
+                    Arg::new("input")
+                        .help("Firmware image file used for the captured update")
+                        .value_name("FILE")
+                        .required(true)
+                        .value_parser(PathBufValueParser::new()),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("The captured update was a dry run"),
+                )
+                .arg(
+                    Arg::new("allow-downgrade")
+                        .long("allow-downgrade")
+                        .action(ArgAction::SetTrue)
+                        .help("The captured update allowed a downgrade"),
                 ),
         )
 }
@@ -58,7 +308,18 @@ fn main() -> Result<(), Report> {
         _ => (LevelFilter::Trace, simplelog::Config::default()),
     };
 
-    let _ = TermLogger::init(level_filter, config, TerminalMode::Mixed, ColorChoice::Auto);
+    let log_format = match matches.get_one::<String>("log-format").map(String::as_str) {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    // keep stdout clean for JSON events
+    let terminal_mode = if log_format.is_json() {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
+
+    let _ = TermLogger::init(level_filter, config, terminal_mode, ColorChoice::Auto);
 
     let result = {
         if let Some(matches) = matches.subcommand_matches("update-firmware") {
@@ -66,7 +327,112 @@ fn main() -> Result<(), Report> {
                 .get_one::<PathBuf>("input")
                 .ok_or_else(|| eyre!("No input file specified"))?;
             let allow_invalid_signature = matches.get_flag("allow-invalid-signature");
-            update::update_cmd(input, allow_invalid_signature)
+            let dry_run = matches.get_flag("dry-run");
+            let allow_downgrade = matches.get_flag("allow-downgrade");
+            let capture = matches.get_one::<PathBuf>("capture");
+            update::update_cmd(
+                input,
+                allow_invalid_signature,
+                dry_run,
+                allow_downgrade,
+                capture,
+                log_format,
+            )
+        } else if let Some(matches) = matches.subcommand_matches("doctor") {
+            let install_udev_rule = matches.get_flag("install-udev-rule");
+            let udev_rule_path = matches
+                .get_one::<PathBuf>("udev-rule-path")
+                .ok_or_else(|| eyre!("No udev rule path specified"))?;
+            doctor::doctor_cmd(install_udev_rule, udev_rule_path)
+        } else if matches.subcommand_matches("post-mortem").is_some() {
+            post_mortem::post_mortem_cmd()
+        } else if let Some(matches) = matches.subcommand_matches("dump-sfrs") {
+            let input = matches.get_one::<PathBuf>("input");
+            let diff = matches.get_one::<PathBuf>("diff");
+            let save = matches.get_one::<PathBuf>("save");
+            sfr::dump_sfrs_cmd(
+                input.map(PathBuf::as_path),
+                diff.map(PathBuf::as_path),
+                save.map(PathBuf::as_path),
+            )
+        } else if let Some(matches) = matches.subcommand_matches("export-cab") {
+            let input = matches
+                .get_one::<PathBuf>("input")
+                .ok_or_else(|| eyre!("No input file specified"))?;
+            let output = matches
+                .get_one::<PathBuf>("output")
+                .ok_or_else(|| eyre!("No output file specified"))?;
+            let release_notes = matches
+                .get_one::<String>("release-notes")
+                .cloned()
+                .unwrap_or_default();
+            let date = matches.get_one::<String>("date").cloned();
+            cab::export_cab_cmd(input, output, release_notes, date)
+        } else if let Some(matches) = matches.subcommand_matches("cart-power") {
+            let action = matches
+                .get_one::<String>("action")
+                .map(String::as_str)
+                .unwrap_or("status");
+            let delay = matches.get_one::<u64>("delay").copied().unwrap_or(500);
+            cart::cart_power_cmd(action, Duration::from_millis(delay))
+        } else if let Some(matches) = matches.subcommand_matches("check-cart") {
+            let passes = matches
+                .get_one::<u64>("passes")
+                .map_or(CHECK_PASSES, |&passes| passes as usize);
+            cart::check_cart_cmd(passes)
+        } else if let Some(matches) = matches.subcommand_matches("dump-rom") {
+            let output = matches
+                .get_one::<PathBuf>("output")
+                .ok_or_else(|| eyre!("No output file specified"))?;
+            let passes = matches
+                .get_one::<u64>("passes")
+                .map_or(DUMP_PASSES, |&passes| passes as usize);
+            let max_reads = matches
+                .get_one::<u64>("max-reads")
+                .map_or(DUMP_MAX_READS, |&max_reads| max_reads as usize);
+            let timing = if matches.get_flag("force-ce") {
+                ReadTiming::ForceCe
+            } else {
+                ReadTiming::Fast
+            };
+            let force_ce_fallback = matches.get_flag("force-ce-fallback");
+            let mapper = matches
+                .get_one::<String>("mapper")
+                .map(|mapper| match mapper.as_str() {
+                    "mbc1" => Mapper::Mbc1,
+                    "mbc2" => Mapper::Mbc2,
+                    "mbc3" => Mapper::Mbc3,
+                    "mbc5" => Mapper::Mbc5,
+                    _ => Mapper::None,
+                });
+            let dat = matches.get_one::<PathBuf>("dat");
+            dump::dump_rom_cmd(
+                output,
+                passes,
+                max_reads,
+                timing,
+                force_ce_fallback,
+                mapper,
+                dat.map(PathBuf::as_path),
+            )
+        } else if let Some(matches) = matches.subcommand_matches("verify-dump") {
+            let input = matches
+                .get_one::<PathBuf>("input")
+                .ok_or_else(|| eyre!("No input file specified"))?;
+            let dat = matches
+                .get_one::<PathBuf>("dat")
+                .ok_or_else(|| eyre!("No DAT file specified"))?;
+            dat::verify_dump_cmd(input, dat)
+        } else if let Some(matches) = matches.subcommand_matches("replay") {
+            let capture = matches
+                .get_one::<PathBuf>("capture")
+                .ok_or_else(|| eyre!("No capture file specified"))?;
+            let input = matches
+                .get_one::<PathBuf>("input")
+                .ok_or_else(|| eyre!("No input file specified"))?;
+            let dry_run = matches.get_flag("dry-run");
+            let allow_downgrade = matches.get_flag("allow-downgrade");
+            update::replay_cmd(capture, input, dry_run, allow_downgrade, log_format)
         } else {
             Ok(build_cmd().print_help()?)
         }
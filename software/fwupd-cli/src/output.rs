@@ -0,0 +1,55 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use serde::Serialize;
+use std::io::{self, Write};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn is_json(&self) -> bool {
+        *self == LogFormat::Json
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Finished<'a, T: Serialize> {
+    Result { outcome: &'a T },
+    Error { message: String },
+}
+
+/// Writes one event as a single line of JSON to standard output
+pub fn emit_json<T: Serialize>(event: &T) {
+    let mut stdout = io::stdout().lock();
+    if let Ok(line) = serde_json::to_string(event) {
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+}
+
+/// Writes an error that ended an operation before it produced a result
+pub fn emit_json_error<E: std::fmt::Display>(err: &E) {
+    let event: Finished<()> = Finished::Error {
+        message: err.to_string(),
+    };
+    emit_json(&event)
+}
+
+/// Writes the final result of an operation as a `result` or `error` event
+pub fn emit_json_result<T: Serialize, E: std::fmt::Display>(result: &Result<T, E>) {
+    match result {
+        Ok(outcome) => emit_json(&Finished::Result { outcome }),
+        Err(err) => {
+            let event: Finished<T> = Finished::Error {
+                message: err.to_string(),
+            };
+            emit_json(&event)
+        }
+    }
+}
@@ -0,0 +1,38 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use eyre::{Context, Report};
+use gb_cartpp_fwupd::{BootloaderDriver, Updater, Usb};
+use log::{error, info, warn};
+
+pub fn post_mortem_cmd() -> Result<(), Report> {
+    let usb = Usb::init().wrap_err("Failed to initialize libusb")?;
+    let device = Updater::new(&usb).select_device(|_| ())?;
+    if !device.kind.is_bootloader() {
+        info!(
+            "{} is running the firmware, so there is no crash to analyze",
+            device
+        );
+        return Ok(());
+    }
+    info!("Using {}", device);
+    let drv = BootloaderDriver::initialize(device)?;
+    let report = drv.post_mortem()?;
+    drv.finish()?;
+
+    if report.cause.is_unexpected() {
+        warn!("Reset cause: {}", report.cause);
+    } else {
+        info!("Reset cause: {}", report.cause);
+    }
+    info!(
+        "RCON: {:?}, STKPTR: {:?}",
+        report.diagnostics.rcon, report.diagnostics.stkptr
+    );
+    match &report.panic_message {
+        Some(message) => error!("Firmware panicked: {}", message),
+        None => info!("No panic message found (only DEBUG builds of the firmware record one)"),
+    }
+    Ok(())
+}
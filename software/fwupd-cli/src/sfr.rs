@@ -0,0 +1,66 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use eyre::{Context, Report};
+use gb_cartpp_fwupd::{BootloaderDriver, SfrDump, Updater, Usb, RESET_TIMEOUT};
+use log::info;
+use std::{fs, path::Path};
+
+pub fn dump_sfrs_cmd(
+    input: Option<&Path>,
+    diff: Option<&Path>,
+    save: Option<&Path>,
+) -> Result<(), Report> {
+    let dump = match input {
+        Some(path) => read_dump(path)?,
+        None => dump_from_device()?,
+    };
+    if let Some(path) = save {
+        fs::write(path, dump.as_bytes())
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        info!("Saved SFR dump to {}", path.display());
+    }
+    match diff {
+        Some(path) => {
+            let old = read_dump(path)?;
+            let changes = dump.diff(&old);
+            if changes.is_empty() {
+                info!("No differences to {}", path.display());
+            }
+            for change in changes {
+                info!("{}", change);
+            }
+        }
+        None => {
+            for sfr in dump.iter() {
+                info!("{}", sfr);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_dump(path: &Path) -> Result<SfrDump, Report> {
+    let data = fs::read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    Ok(SfrDump::new(data))
+}
+
+fn dump_from_device() -> Result<SfrDump, Report> {
+    let usb = Usb::init().wrap_err("Failed to initialize libusb")?;
+    let mut device = Updater::new(&usb).select_device(|_| ())?;
+    let was_firmware = device.kind.is_firmware();
+    if was_firmware {
+        info!("Entering bootloader on {}", device);
+        device = device.enter_bootloader_and_wait(RESET_TIMEOUT)?;
+    }
+    info!("Using {}", device);
+    let drv = BootloaderDriver::initialize(device)?;
+    let dump = drv.dump_sfrs()?;
+    if was_firmware {
+        drv.reset_and_wait(RESET_TIMEOUT)?;
+    } else {
+        drv.finish()?;
+    }
+    Ok(dump)
+}
@@ -3,34 +3,55 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use eyre::{bail, eyre, Context};
-use gb_cartpp_fwupd::FirmwareArchive;
-use log::{debug, error, warn};
+use gb_cartpp_fwupd::{Capture, FirmwareFile, FirmwareImage, ReplayBus, Usb};
+use log::{debug, error, info, warn};
 use std::{
     fs::File,
-    io::{self, BufReader},
+    io::{self, Read},
     path::PathBuf,
+    sync::Arc,
 };
 
-use crate::bootloader;
+use crate::{
+    bootloader,
+    output::{emit_json_error, LogFormat},
+};
 
-pub fn update_cmd(input: &PathBuf, allow_invalid_signature: bool) -> Result<(), eyre::Report> {
-    let fw: Option<FirmwareArchive> = if input.as_os_str() == "-" {
+pub fn read_firmware(input: &PathBuf) -> Result<FirmwareFile, eyre::Report> {
+    let mut data = Vec::new();
+    if input.as_os_str() == "-" {
         debug!("Reading firmware image from standard input");
-        FirmwareArchive::from_reader(BufReader::new(io::stdin()))
-            .wrap_err("Failed to read firmware image")
+        io::stdin()
+            .read_to_end(&mut data)
+            .wrap_err("Failed to read firmware image")?;
     } else {
         debug!("Reading firmware image from {}", input.display());
         File::open(input)
-            .wrap_err("Failed to open firmware file")
-            .and_then(|file| {
-                FirmwareArchive::from_reader(BufReader::new(file))
-                    .wrap_err("Failed to read firmware image")
-            })
-    }?;
+            .wrap_err("Failed to open firmware file")?
+            .read_to_end(&mut data)
+            .wrap_err("Failed to read firmware image")?;
+    }
+    let fw = FirmwareFile::from_bytes(data).wrap_err("Failed to read firmware image")?;
     let fw = fw.ok_or_else(|| eyre!("No valid firmware image detected"))?;
-    let signature_ok = if fw.has_signature() {
+    debug!("Detected {} firmware", fw.format());
+    Ok(fw)
+}
+
+fn check_signature(fw: &FirmwareFile, allow_invalid_signature: bool) -> bool {
+    let archive = match fw {
+        FirmwareFile::Archive(archive) => archive,
+        FirmwareFile::Raw { format, .. } if allow_invalid_signature => {
+            warn!("Flashing an unsigned {} firmware file!", format);
+            return true;
+        }
+        FirmwareFile::Raw { format, .. } => {
+            error!("{} firmware files have no digital signature!", format);
+            return false;
+        }
+    };
+    if archive.has_signature() {
         debug!("Validating firmware image digital signature");
-        fw.has_valid_signature().unwrap_or_else(|err| {
+        archive.has_valid_signature().unwrap_or_else(|err| {
             if allow_invalid_signature {
                 warn!("Failed to read signature: {}", err);
             } else {
@@ -44,13 +65,79 @@ pub fn update_cmd(input: &PathBuf, allow_invalid_signature: bool) -> Result<(),
     } else {
         error!("The firmware image has no digital signature!");
         false
-    };
-    if !signature_ok {
+    }
+}
+
+pub fn update_cmd(
+    input: &PathBuf,
+    allow_invalid_signature: bool,
+    dry_run: bool,
+    allow_downgrade: bool,
+    capture: Option<&PathBuf>,
+    log_format: LogFormat,
+) -> Result<(), eyre::Report> {
+    let prepared = prepare_update(input, allow_invalid_signature, capture);
+    let (usb, fw) = emit_early_error(prepared, log_format)?;
+    bootloader::update_firmware(&usb, &fw, dry_run, allow_downgrade, log_format)
+        .wrap_err("Failed to update firmware")?;
+
+    Ok(())
+}
+
+fn prepare_update(
+    input: &PathBuf,
+    allow_invalid_signature: bool,
+    capture: Option<&PathBuf>,
+) -> Result<(Arc<Usb>, FirmwareImage), eyre::Report> {
+    let fw = read_firmware(input)?;
+    if !check_signature(&fw, allow_invalid_signature) {
         error!("The firmware image is unofficial, corrupted, or has been tampered with, so flashing is prohibited");
         error!("If you are absolutely sure what you are doing, you can use --allow-invalid-signature to allow flashing anyway. *THIS IS NOT SAFE AND MAY BRICK THE DEVICE*");
         bail!("Aborting due to invalid digital signature");
     }
-    bootloader::update_firmware(fw).wrap_err("Failed to update firmware")?;
+    let usb = Usb::init()?;
+    if let Some(capture) = capture {
+        debug!("Recording USB traffic to {}", capture.display());
+        usb.record_to(Capture::create(capture).wrap_err("Failed to create capture file")?);
+    }
+    let fw = fw.decode()?;
+    Ok((usb, fw))
+}
 
-    Ok(())
+// The updater reports its own errors as JSON events, but errors before it starts need to be
+// reported here
+fn emit_early_error<T>(
+    result: Result<T, eyre::Report>,
+    log_format: LogFormat,
+) -> Result<T, eyre::Report> {
+    if let Err(err) = &result {
+        if log_format.is_json() {
+            emit_json_error(&format!("{:#}", err));
+        }
+    }
+    result
+}
+
+pub fn replay_cmd(
+    capture: &PathBuf,
+    input: &PathBuf,
+    dry_run: bool,
+    allow_downgrade: bool,
+    log_format: LogFormat,
+) -> Result<(), eyre::Report> {
+    let prepared = read_firmware(input).and_then(|fw| {
+        debug!("Replaying USB traffic from {}", capture.display());
+        let bus = ReplayBus::open_file(capture).wrap_err("Failed to read capture file")?;
+        Ok((bus, fw.decode()?))
+    });
+    let (bus, fw) = emit_early_error(prepared, log_format)?;
+    let result = bootloader::update_firmware(&bus.usb(), &fw, dry_run, allow_downgrade, log_format);
+    if let Some(divergence) = bus.divergence() {
+        bail!("Replay diverged from the capture at {}", divergence);
+    }
+    match bus.remaining() {
+        0 => info!("Replay matched the capture"),
+        remaining => warn!("Replay finished with {} captured transfers left", remaining),
+    }
+    result.wrap_err("Replayed update failed")
 }
@@ -0,0 +1,126 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Host-side emulation of the bootloader's decision whether to start the application.
+//!
+//! This mirrors `bootloader_init` in `bootloader/main.s` and `crc_check` in `bootloader/crc16.s`.
+
+use std::fmt;
+
+use crate::{Rcon, StkPtr};
+
+/// First address of the application area covered by the ROM CRC
+pub const APPLICATION_START: u32 = 0x00_0800;
+/// End address (exclusive) of the application area covered by the ROM CRC
+pub const APPLICATION_END: u32 = 0x00_8000;
+/// Address of the little-endian ROM CRC stored in the ID locations (IDLOC0/IDLOC1)
+pub const ROM_CRC_ADDR: u32 = 0x20_0000;
+
+/// Reset magic that makes the bootloader stay in bootloader mode after a reset instruction
+pub const RESET_MAGIC_BOOTLOADER: u8 = 0x42;
+/// Reset magic used to restart the application after a reset instruction
+pub const RESET_MAGIC_APPLICATION: u8 = 0x99;
+
+/// Calculates the CRC16 (XMODEM) of the application area, as done by the bootloader
+pub fn calc_rom_crc(application: &[u8]) -> u16 {
+    crc16::State::<crc16::XMODEM>::calculate(application)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RomCrc {
+    /// CRC calculated from the application area
+    pub calculated: u16,
+    /// CRC stored at `ROM_CRC_ADDR`
+    pub stored: u16,
+}
+
+impl RomCrc {
+    pub fn is_valid(&self) -> bool {
+        self.calculated == self.stored
+    }
+}
+
+impl fmt::Display for RomCrc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "calculated 0x{:04x}, stored 0x{:04x}",
+            self.calculated, self.stored
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BootloaderReason {
+    StackOverflow,
+    StackUnderflow,
+    Watchdog,
+    ResetMagic,
+    OtherReset,
+    InvalidRomCrc(RomCrc),
+}
+
+impl fmt::Display for BootloaderReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootloaderReason::StackOverflow => write!(f, "stack overflow reset"),
+            BootloaderReason::StackUnderflow => write!(f, "stack underflow reset"),
+            BootloaderReason::Watchdog => write!(f, "watchdog reset"),
+            BootloaderReason::ResetMagic => write!(f, "bootloader was requested"),
+            BootloaderReason::OtherReset => write!(f, "unrecognized reset source"),
+            BootloaderReason::InvalidRomCrc(crc) => write!(f, "invalid ROM CRC ({})", crc),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BootDecision {
+    Application,
+    Bootloader(BootloaderReason),
+}
+
+impl BootDecision {
+    pub fn is_application(&self) -> bool {
+        matches!(self, BootDecision::Application)
+    }
+}
+
+/// Decides whether the bootloader starts the application after a reset.
+///
+/// `rcon` uses the same convention as `Diagnostics::rcon`: a set RI/TO/PD/POR/BOR flag means the
+/// corresponding event has happened, even though the hardware bits are active-low.
+pub fn boot_decision(rcon: Rcon, stkptr: StkPtr, reset_magic: u8, rom_crc: RomCrc) -> BootDecision {
+    let reason = if stkptr.contains(StkPtr::STKFUL) {
+        Some(BootloaderReason::StackOverflow)
+    } else if stkptr.contains(StkPtr::STKUNF) {
+        Some(BootloaderReason::StackUnderflow)
+    } else if rcon.contains(Rcon::TO) {
+        Some(BootloaderReason::Watchdog)
+    } else if rcon.contains(Rcon::RI) {
+        if reset_magic == RESET_MAGIC_BOOTLOADER {
+            Some(BootloaderReason::ResetMagic)
+        } else {
+            None
+        }
+    } else if rcon.intersects(Rcon::POR | Rcon::BOR) {
+        None
+    } else {
+        Some(BootloaderReason::OtherReset)
+    };
+    match reason {
+        Some(reason) => BootDecision::Bootloader(reason),
+        None if rom_crc.is_valid() => BootDecision::Application,
+        None => BootDecision::Bootloader(BootloaderReason::InvalidRomCrc(rom_crc)),
+    }
+}
+
+/// Decides whether the application starts after `BootloaderDriver::reset`
+pub fn boot_decision_after_reset(rom_crc: RomCrc) -> BootDecision {
+    boot_decision(Rcon::RI, StkPtr::empty(), RESET_MAGIC_APPLICATION, rom_crc)
+}
@@ -3,41 +3,129 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::{
+    boot::{
+        boot_decision_after_reset, calc_rom_crc, BootDecision, RomCrc, APPLICATION_END,
+        APPLICATION_START, ROM_CRC_ADDR,
+    },
     fw_image::FirmwareImage,
-    usb::{BootloaderMode, Unclaimed, UsbDevice, UsbDeviceKind},
+    post_mortem::{
+        decode_panic_message, PostMortem, ResetCause, PANIC_MESSAGE_ADDR, PANIC_MESSAGE_LEN,
+        RCON_SAVE_ADDR, STKPTR_SAVE_ADDR,
+    },
+    sfr::{SfrDump, SFR_DUMP_LEN, SFR_DUMP_START},
+    usb::{BootloaderMode, Unclaimed, UsbDevice, UsbDeviceKind, LIBUSB_MAX_PAYLOAD},
     Diagnostics, DriverError, FirmwareVersion, Rcon, StkPtr, VerifyResult, FLASH_BLOCK_SIZE,
 };
+use log::debug;
+use std::time::Duration;
 
+// Read sizes tried when probing, largest first. The bootloader EP0 handler accepts any wLength,
+// but the host side may refuse large control transfers
+const READ_CHUNK_LENS: [usize; 3] = [LIBUSB_MAX_PAYLOAD, 0x100, FLASH_BLOCK_SIZE];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ByteChange {
+    pub addr: u32,
+    pub current: u8,
+    pub new: u8,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BlockChange {
+    pub addr: u32,
+    /// The block would be erased instead of written, because the new contents are all 0xff
+    pub erase_only: bool,
+    pub bytes: Vec<ByteChange>,
+}
+
+impl BlockChange {
+    /// Compares the actual contents of a block with the expected ones, returning None if they
+    /// are the same
+    pub(crate) fn compare(addr: u32, actual: &[u8], expected: &[u8]) -> Option<BlockChange> {
+        let bytes = actual
+            .iter()
+            .zip(expected.iter())
+            .enumerate()
+            .filter(|(_, (actual, expected))| actual != expected)
+            .map(|(idx, (&current, &new))| ByteChange {
+                addr: addr | (idx as u32),
+                current,
+                new,
+            })
+            .collect::<Vec<_>>();
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(BlockChange {
+                addr,
+                erase_only: expected.iter().all(|&byte| byte == 0xff),
+                bytes,
+            })
+        }
+    }
+}
+
+/// An unlocked bootloader session.
+///
+/// Dropping the driver locks the bootloader and releases the interface, ignoring errors. Use
+/// `finish` or `abort` to find out whether teardown succeeded.
 pub struct BootloaderDriver {
-    device: UsbDevice<BootloaderMode>,
+    // None once the device has been released or reset
+    device: Option<UsbDevice<BootloaderMode>>,
+    relock: bool,
     fw_version: FirmwareVersion,
     bl_version: FirmwareVersion,
+    read_chunk_len: usize,
 }
 
 impl BootloaderDriver {
     pub fn initialize(device: UsbDevice<Unclaimed>) -> Result<BootloaderDriver, DriverError> {
-        let bl;
-        let fw;
-        if let UsbDeviceKind::Bootloader {
-            bl_version,
-            fw_version,
-        } = device.kind
-        {
-            bl = bl_version;
-            fw = fw_version;
-        } else {
-            panic!("Not in bootloader mode");
-        }
-        let device = device.claim_bootloader()?;
-        device.unlock()?;
-        Ok(BootloaderDriver {
-            device,
+        let (bl, fw) = match device.kind {
+            UsbDeviceKind::Bootloader {
+                bl_version,
+                fw_version,
+            } => (bl_version, fw_version),
+            kind => return Err(DriverError::WrongMode { kind }),
+        };
+        let mut drv = BootloaderDriver {
+            device: Some(device.claim_bootloader()?),
+            relock: true,
             fw_version: fw,
             bl_version: bl,
-        })
+            read_chunk_len: FLASH_BLOCK_SIZE,
+        };
+        // from here on, dropping the driver locks the bootloader again
+        drv.device().unlock()?;
+        drv.read_chunk_len = probe_read_chunk_len(drv.device())?;
+        debug!("Using {} byte reads", drv.read_chunk_len);
+        Ok(drv)
+    }
+    /// Sets whether `finish` locks the bootloader. `abort` and drop always lock it
+    pub fn relock(mut self, relock: bool) -> BootloaderDriver {
+        self.relock = relock;
+        self
+    }
+    /// Ends the session, locking the bootloader unless disabled with `relock`
+    pub fn finish(mut self) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        let relock = self.relock;
+        teardown(self.take_device(), relock)
     }
-    pub fn deinitialize(self) -> Result<UsbDevice<Unclaimed>, DriverError> {
-        self.device.release()
+    /// Ends the session after an error. The bootloader is locked and the interface released
+    /// even if one of them fails, and the first error is returned
+    pub fn abort(mut self) -> Result<(), DriverError> {
+        teardown(self.take_device(), true).map(|_| ())
+    }
+    fn device(&self) -> &UsbDevice<BootloaderMode> {
+        self.device
+            .as_ref()
+            .expect("bootloader device used after release")
+    }
+    fn take_device(&mut self) -> UsbDevice<BootloaderMode> {
+        self.device
+            .take()
+            .expect("bootloader device used after release")
     }
     pub fn bootloader_version(&self) -> FirmwareVersion {
         self.bl_version
@@ -45,25 +133,50 @@ impl BootloaderDriver {
     pub fn firmware_version(&self) -> FirmwareVersion {
         self.fw_version
     }
-    pub fn reset(self) -> Result<(), DriverError> {
-        self.device.reset()
+    pub fn read_chunk_len(&self) -> usize {
+        self.read_chunk_len
+    }
+    pub fn reset(mut self) -> Result<(), DriverError> {
+        self.take_device().reset()
+    }
+    pub fn reset_bootloader(mut self) -> Result<(), DriverError> {
+        self.take_device().enter_bootloader()
     }
-    pub fn reset_bootloader(self) -> Result<(), DriverError> {
-        self.device.enter_bootloader()
+    /// Resets the device into the main firmware and waits until it is detected again
+    pub fn reset_and_wait(
+        mut self,
+        timeout: Duration,
+    ) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        self.take_device().reset_and_wait(timeout)
     }
+    /// Reads the RCON and STKPTR values the bootloader saved before resetting them
     pub fn read_diagnostics(&self) -> Result<Diagnostics, DriverError> {
-        let rcon = self.device.read_byte(0x8000_0fd0)?;
-        let stkptr = self.device.read_byte(0x8000_0ffc)?;
+        let rcon = self.device().read_byte(RCON_SAVE_ADDR)?;
+        let stkptr = self.device().read_byte(STKPTR_SAVE_ADDR)?;
         Ok(Diagnostics {
             rcon: Rcon::from_bits_truncate((rcon & 0b1110_0000) | (!rcon & 0b0001_1111)),
             stkptr: StkPtr::from_bits_truncate(stkptr),
         })
     }
-    pub fn dump_sfrs(&self) -> Result<Vec<u8>, DriverError> {
-        let mut buffer = vec![0; 169];
-        let len = self.device.read(0x8000_0f57, &mut buffer)?;
-        buffer.truncate(len);
-        Ok(buffer)
+    /// Reads the reset flags of the last reset and the firmware panic message, if there is one.
+    ///
+    /// After the bootloader has been entered on request, the cause is a reset instruction.
+    pub fn post_mortem(&self) -> Result<PostMortem, DriverError> {
+        let diagnostics = self.read_diagnostics()?;
+        let message = self
+            .device()
+            .read_to_vec(PANIC_MESSAGE_ADDR, PANIC_MESSAGE_LEN)?;
+        Ok(PostMortem {
+            diagnostics,
+            cause: ResetCause::from(diagnostics),
+            panic_message: decode_panic_message(&message),
+        })
+    }
+    pub fn dump_sfrs(&self) -> Result<SfrDump, DriverError> {
+        let buffer = self
+            .device()
+            .read_to_vec(0x8000_0000 | u32::from(SFR_DUMP_START), SFR_DUMP_LEN)?;
+        Ok(SfrDump::new(buffer))
     }
     pub fn write_flash<F: FnMut(u32)>(
         &self,
@@ -72,32 +185,104 @@ impl BootloaderDriver {
     ) -> Result<(), DriverError> {
         for (addr, block_data) in fw.iter_flash_blocks() {
             if block_data.iter().all(|&byte| byte == 0xff) {
-                self.device.erase_flash(addr)?;
+                self.device().erase_flash(addr)?;
             } else {
-                self.device.write_flash(addr, block_data)?;
+                self.device().write_flash(addr, block_data)?;
             }
             cb(addr);
         }
         Ok(())
     }
+    pub fn read_flash(&self, addr: u32, buffer: &mut [u8]) -> Result<(), DriverError> {
+        let mut addr = addr;
+        for chunk in buffer.chunks_mut(self.read_chunk_len) {
+            let result = self.device().read(addr, chunk);
+            match result.as_ref().map_err(DriverError::root) {
+                Ok(&len) if len == chunk.len() => (),
+                Ok(_) | Err(DriverError::UsbPipe) | Err(DriverError::UsbIo)
+                    if chunk.len() > FLASH_BLOCK_SIZE =>
+                {
+                    debug!(
+                        "Read of {} bytes at {:#06x} failed, retrying in smaller blocks",
+                        chunk.len(),
+                        addr
+                    );
+                    let mut block_addr = addr;
+                    for block in chunk.chunks_mut(FLASH_BLOCK_SIZE) {
+                        let len = self.device().read(block_addr, block)?;
+                        if len != block.len() {
+                            return Err(DriverError::ShortRead {
+                                expected: block.len(),
+                                actual: len,
+                            });
+                        }
+                        block_addr += block.len() as u32;
+                    }
+                }
+                Ok(&len) => {
+                    return Err(DriverError::ShortRead {
+                        expected: chunk.len(),
+                        actual: len,
+                    })
+                }
+                Err(_) => return result.map(|_| ()),
+            }
+            addr += chunk.len() as u32;
+        }
+        Ok(())
+    }
     pub fn calc_flash_checksum(&self) -> Result<u16, DriverError> {
-        let mut data = Vec::new();
-        let mut buffer = [0; 0x100];
-        for chunk in 0x08..0x80 {
-            self.device.read(chunk << 8, &mut buffer)?;
-            data.extend(&buffer[..]);
+        let mut data = vec![0; (APPLICATION_END - APPLICATION_START) as usize];
+        self.read_flash(APPLICATION_START, &mut data)?;
+        Ok(calc_rom_crc(&data))
+    }
+    pub fn read_rom_crc(&self) -> Result<RomCrc, DriverError> {
+        let calculated = self.calc_flash_checksum()?;
+        let stored = self.device().read_to_vec(ROM_CRC_ADDR, 2)?;
+        Ok(RomCrc {
+            calculated,
+            stored: u16::from_le_bytes([stored[0], stored[1]]),
+        })
+    }
+    pub fn boot_check(&self) -> Result<BootDecision, DriverError> {
+        Ok(boot_decision_after_reset(self.read_rom_crc()?))
+    }
+    /// Reads back the flash blocks of the image, calling `cb` with the address, the actual
+    /// contents and the expected contents of each block
+    pub fn compare_flash<F: FnMut(u32, &[u8], &[u8])>(
+        &self,
+        fw: &FirmwareImage,
+        mut cb: F,
+    ) -> Result<(), DriverError> {
+        let mut chunk = Vec::with_capacity(self.read_chunk_len);
+        let mut chunk_addr = 0;
+        for (block_addr, expected) in fw.iter_flash_blocks() {
+            let block_end = block_addr + expected.len() as u32;
+            if block_addr < chunk_addr || block_end > chunk_addr + chunk.len() as u32 {
+                let len = self
+                    .read_chunk_len
+                    .max(expected.len())
+                    .min((APPLICATION_END - block_addr) as usize);
+                chunk.resize(len, 0xff);
+                chunk_addr = block_addr;
+                self.read_flash(chunk_addr, &mut chunk)?;
+            }
+            let offset = (block_addr - chunk_addr) as usize;
+            cb(
+                block_addr,
+                &chunk[offset..offset + expected.len()],
+                expected,
+            );
         }
-        Ok(crc16::State::<crc16::XMODEM>::calculate(&data))
+        Ok(())
     }
     pub fn verify_flash<F: FnMut(u32, VerifyResult)>(
         &self,
         fw: &FirmwareImage,
         mut cb: F,
     ) -> Result<VerifyResult, DriverError> {
-        let mut actual: [u8; FLASH_BLOCK_SIZE] = [0xff; FLASH_BLOCK_SIZE];
         let mut result = VerifyResult::Valid;
-        for (block_addr, expected) in fw.iter_flash_blocks() {
-            self.device.read(block_addr, &mut actual)?;
+        self.compare_flash(fw, |block_addr, actual, expected| {
             for (idx, (actual, expected)) in actual.iter().zip(expected.iter()).enumerate() {
                 let addr = block_addr | (idx as u32);
                 if actual != expected {
@@ -105,39 +290,103 @@ impl BootloaderDriver {
                 }
             }
             cb(block_addr, result);
-        }
+        })?;
         Ok(result)
     }
+    /// Lists the flash blocks whose contents differ from the image
+    pub fn diff_flash(&self, fw: &FirmwareImage) -> Result<Vec<BlockChange>, DriverError> {
+        let mut changes = Vec::new();
+        self.compare_flash(fw, |block_addr, actual, expected| {
+            changes.extend(BlockChange::compare(block_addr, actual, expected));
+        })?;
+        Ok(changes)
+    }
     pub fn write_id(&self, fw: &FirmwareImage) -> Result<(), DriverError> {
         for (addr, byte) in fw.iter_id_bytes() {
-            self.device.write_id(addr, &[byte])?;
+            self.device().write_id(addr, &[byte])?;
         }
         Ok(())
     }
     pub fn verify_id(&self, fw: &FirmwareImage) -> Result<VerifyResult, DriverError> {
         let mut result = VerifyResult::Valid;
         for (addr, expected) in fw.iter_id_bytes() {
-            let actual = self.device.read_byte(addr)?;
+            let actual = self.device().read_byte(addr)?;
             if actual != expected {
                 result.mark_error(addr);
             }
         }
         Ok(result)
     }
+    /// Lists the ID bytes that differ from the image
+    pub fn diff_id(&self, fw: &FirmwareImage) -> Result<Vec<ByteChange>, DriverError> {
+        let mut changes = Vec::new();
+        for (addr, new) in fw.iter_id_bytes() {
+            let current = self.device().read_byte(addr)?;
+            if current != new {
+                changes.push(ByteChange { addr, current, new });
+            }
+        }
+        Ok(changes)
+    }
     pub fn write_cfg(&self, fw: &FirmwareImage) -> Result<(), DriverError> {
         for (addr, byte) in fw.iter_config_bytes() {
-            self.device.write_cfg(addr, &[byte])?;
+            self.device().write_cfg(addr, &[byte])?;
         }
         Ok(())
     }
     pub fn verify_cfg(&self, fw: &FirmwareImage) -> Result<VerifyResult, DriverError> {
         let mut result = VerifyResult::Valid;
-        for (addr, expected) in fw.iter_id_bytes() {
-            let actual = self.device.read_byte(addr)?;
+        for (addr, expected) in fw.iter_config_bytes() {
+            let actual = self.device().read_byte(addr)?;
             if actual != expected {
                 result.mark_error(addr);
             }
         }
         Ok(result)
     }
+    /// Lists the config bytes that differ from the image
+    pub fn diff_cfg(&self, fw: &FirmwareImage) -> Result<Vec<ByteChange>, DriverError> {
+        let mut changes = Vec::new();
+        for (addr, new) in fw.iter_config_bytes() {
+            let current = self.device().read_byte(addr)?;
+            if current != new {
+                changes.push(ByteChange { addr, current, new });
+            }
+        }
+        Ok(changes)
+    }
+}
+
+impl Drop for BootloaderDriver {
+    fn drop(&mut self) {
+        if let Some(device) = self.device.take() {
+            if let Err(err) = teardown(device, true) {
+                debug!("Failed to release the bootloader: {}", err);
+            }
+        }
+    }
+}
+
+fn teardown(
+    device: UsbDevice<BootloaderMode>,
+    lock: bool,
+) -> Result<UsbDevice<Unclaimed>, DriverError> {
+    let locked = if lock { device.lock() } else { Ok(()) };
+    let device = device.release()?;
+    locked.map(|_| device)
+}
+
+fn probe_read_chunk_len(device: &UsbDevice<BootloaderMode>) -> Result<usize, DriverError> {
+    let mut buffer = [0; LIBUSB_MAX_PAYLOAD];
+    for len in READ_CHUNK_LENS {
+        match device.read(APPLICATION_START, &mut buffer[..len]) {
+            Ok(read) if read == len => return Ok(len),
+            Ok(_) => debug!("Device rejected a read of {} bytes", len),
+            Err(err) if matches!(err.root(), DriverError::UsbPipe | DriverError::UsbIo) => {
+                debug!("Device rejected a read of {} bytes", len)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(FLASH_BLOCK_SIZE)
 }
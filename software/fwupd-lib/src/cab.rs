@@ -0,0 +1,328 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Microsoft cabinet files in the layout used by LVFS/fwupd: the firmware payload and a
+//! metainfo.xml describing it.
+//!
+//! Only uncompressed folders are supported.
+
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::io::{Read, Write};
+
+use crate::{
+    fw_image::{FirmwareArchive, FirmwareError, HEX_FILE_NAME, SIG_FILE_NAME},
+    usb::{FIRMWARE_PRODUCT_ID, VENDOR_ID},
+};
+
+pub const METAINFO_FILE_NAME: &str = "firmware.metainfo.xml";
+
+const CAB_SIGNATURE: &[u8; 4] = b"MSCF";
+const CAB_HEADER_LEN: usize = 36;
+const CAB_FOLDER_LEN: usize = 8;
+const CAB_FILE_LEN: usize = 16;
+const CAB_DATA_LEN: usize = 8;
+const CAB_MAX_BLOCK: usize = 0x8000;
+const CAB_FLAG_RESERVE_PRESENT: u16 = 0x0004;
+const CAB_COMPRESS_MASK: u16 = 0x000f;
+const CAB_COMPRESS_NONE: u16 = 0x0000;
+// 1980-01-01 in MS-DOS format, so exports are reproducible
+const CAB_DOS_DATE: u16 = (1 << 5) | 1;
+const CAB_ATTRIB_ARCH: u16 = 0x20;
+
+// RFC 4122 DNS namespace, used by fwupd to hash instance IDs into GUIDs
+const GUID_NAMESPACE: [u8; 16] = [
+    0x6b, 0xa7, 0xb8, 0x10, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CabFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Cabinet {
+    pub files: Vec<CabFile>,
+}
+
+impl Cabinet {
+    pub fn file(&self, name: &str) -> Option<&CabFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+    pub fn read<R: Read>(mut r: R) -> Result<Cabinet, FirmwareError> {
+        let mut cab = Vec::new();
+        r.read_to_end(&mut cab)?;
+        let u8_at = |offset: usize| -> Result<u8, FirmwareError> {
+            cab.get(offset)
+                .copied()
+                .ok_or(FirmwareError::InvalidCab("truncated file"))
+        };
+        let u16_at = |offset: usize| -> Result<u16, FirmwareError> {
+            Ok(u16::from_le_bytes([u8_at(offset)?, u8_at(offset + 1)?]))
+        };
+        let u32_at = |offset: usize| -> Result<u32, FirmwareError> {
+            Ok(u32::from(u16_at(offset)?) | (u32::from(u16_at(offset + 2)?) << 16))
+        };
+        if !cab.starts_with(CAB_SIGNATURE) {
+            return Err(FirmwareError::InvalidCab("missing MSCF signature"));
+        }
+        let files_offset = u32_at(0x10)? as usize;
+        let folder_count = usize::from(u16_at(0x1a)?);
+        let file_count = usize::from(u16_at(0x1c)?);
+        let flags = u16_at(0x1e)?;
+        let (mut offset, folder_reserve, data_reserve) = if flags & CAB_FLAG_RESERVE_PRESENT != 0 {
+            let header_reserve = usize::from(u16_at(CAB_HEADER_LEN)?);
+            (
+                CAB_HEADER_LEN + 4 + header_reserve,
+                usize::from(u8_at(CAB_HEADER_LEN + 2)?),
+                usize::from(u8_at(CAB_HEADER_LEN + 3)?),
+            )
+        } else {
+            (CAB_HEADER_LEN, 0, 0)
+        };
+
+        let mut folders = Vec::with_capacity(folder_count);
+        for _ in 0..folder_count {
+            let data_offset = u32_at(offset)? as usize;
+            let block_count = usize::from(u16_at(offset + 4)?);
+            if u16_at(offset + 6)? & CAB_COMPRESS_MASK != CAB_COMPRESS_NONE {
+                return Err(FirmwareError::InvalidCab(
+                    "compressed folders are not supported",
+                ));
+            }
+            let mut folder = Vec::new();
+            let mut block = data_offset;
+            for _ in 0..block_count {
+                let len = usize::from(u16_at(block + 4)?);
+                let start = block + CAB_DATA_LEN + data_reserve;
+                let data = cab
+                    .get(start..start + len)
+                    .ok_or(FirmwareError::InvalidCab("truncated data block"))?;
+                folder.extend_from_slice(data);
+                block = start + len;
+            }
+            folders.push(folder);
+            offset += CAB_FOLDER_LEN + folder_reserve;
+        }
+
+        let mut files = Vec::with_capacity(file_count);
+        let mut offset = files_offset;
+        for _ in 0..file_count {
+            let len = u32_at(offset)? as usize;
+            let start = u32_at(offset + 4)? as usize;
+            let folder = folders
+                .get(usize::from(u16_at(offset + 8)?))
+                .ok_or(FirmwareError::InvalidCab("file in a missing folder"))?;
+            let name_start = offset + CAB_FILE_LEN;
+            let name_len = cab
+                .get(name_start..)
+                .and_then(|name| name.iter().position(|&byte| byte == 0))
+                .ok_or(FirmwareError::InvalidCab("unterminated file name"))?;
+            let name = &cab[name_start..name_start + name_len];
+            let data = folder
+                .get(start..start + len)
+                .ok_or(FirmwareError::InvalidCab("file outside its folder"))?;
+            files.push(CabFile {
+                name: String::from_utf8_lossy(name).into_owned(),
+                data: data.to_vec(),
+            });
+            offset = name_start + name_len + 1;
+        }
+        Ok(Cabinet { files })
+    }
+    /// Writes all files into a single uncompressed folder
+    pub fn write<W: Write>(&self, mut w: W) -> Result<(), FirmwareError> {
+        let folder: Vec<u8> = self
+            .files
+            .iter()
+            .flat_map(|file| file.data.iter().copied())
+            .collect();
+        let blocks: Vec<&[u8]> = folder.chunks(CAB_MAX_BLOCK).collect();
+        let files_offset = CAB_HEADER_LEN + CAB_FOLDER_LEN;
+        let files_len: usize = self
+            .files
+            .iter()
+            .map(|file| CAB_FILE_LEN + file.name.len() + 1)
+            .sum();
+        let data_offset = files_offset + files_len;
+        let total_len = data_offset + blocks.len() * CAB_DATA_LEN + folder.len();
+
+        let mut cab = Vec::with_capacity(total_len);
+        cab.extend_from_slice(CAB_SIGNATURE);
+        cab.extend_from_slice(&0u32.to_le_bytes());
+        cab.extend_from_slice(&(total_len as u32).to_le_bytes());
+        cab.extend_from_slice(&0u32.to_le_bytes());
+        cab.extend_from_slice(&(files_offset as u32).to_le_bytes());
+        cab.extend_from_slice(&0u32.to_le_bytes());
+        // version 1.3, one folder, no flags, set ID and cabinet index 0
+        cab.extend_from_slice(&[3, 1]);
+        cab.extend_from_slice(&1u16.to_le_bytes());
+        cab.extend_from_slice(&(self.files.len() as u16).to_le_bytes());
+        cab.extend_from_slice(&[0; 6]);
+
+        cab.extend_from_slice(&(data_offset as u32).to_le_bytes());
+        cab.extend_from_slice(&(blocks.len() as u16).to_le_bytes());
+        cab.extend_from_slice(&CAB_COMPRESS_NONE.to_le_bytes());
+
+        let mut folder_offset = 0;
+        for file in &self.files {
+            cab.extend_from_slice(&(file.data.len() as u32).to_le_bytes());
+            cab.extend_from_slice(&(folder_offset as u32).to_le_bytes());
+            cab.extend_from_slice(&0u16.to_le_bytes());
+            cab.extend_from_slice(&CAB_DOS_DATE.to_le_bytes());
+            cab.extend_from_slice(&0u16.to_le_bytes());
+            cab.extend_from_slice(&CAB_ATTRIB_ARCH.to_le_bytes());
+            cab.extend_from_slice(file.name.as_bytes());
+            cab.push(0);
+            folder_offset += file.data.len();
+        }
+
+        // a zero checksum means the block has no checksum
+        for block in blocks {
+            cab.extend_from_slice(&0u32.to_le_bytes());
+            cab.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            cab.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            cab.extend_from_slice(block);
+        }
+        w.write_all(&cab)?;
+        Ok(())
+    }
+}
+
+/// Release information that is not part of the firmware image
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CabRelease {
+    /// Plain text. Paragraphs are separated by blank lines
+    pub release_notes: String,
+    /// Release date in YYYY-MM-DD format
+    pub date: Option<String>,
+}
+
+/// Returns the GUID fwupd derives from the firmware mode instance ID `USB\VID_16C0&PID_05E1`
+pub fn device_guid() -> String {
+    let instance_id = format!("USB\\VID_{:04X}&PID_{:04X}", VENDOR_ID, FIRMWARE_PRODUCT_ID);
+    let mut hasher = Sha1::new();
+    hasher.update(GUID_NAMESPACE);
+    hasher.update(instance_id.as_bytes());
+    let hash = hasher.finalize();
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(&hash[..16]);
+    uuid[6] = (uuid[6] & 0x0f) | 0x50;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    let hex: String = uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn hex_digest<D: Digest>(data: &[u8]) -> String {
+    D::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl FirmwareArchive {
+    /// Reads the firmware image and signature from a cabinet created by `to_cab`
+    pub fn from_cab<R: Read>(r: R) -> Result<Option<FirmwareArchive>, FirmwareError> {
+        let mut cab = Cabinet::read(r)?;
+        let mut take = |name| {
+            let idx = cab.files.iter().position(|file| file.name == name)?;
+            Some(cab.files.remove(idx).data)
+        };
+        let hex_file = take(HEX_FILE_NAME);
+        let sig_file = take(SIG_FILE_NAME);
+        Ok(hex_file.map(|hex_file| FirmwareArchive { hex_file, sig_file }))
+    }
+    /// Creates a cabinet for LVFS/fwupd containing the firmware image, its signature and a
+    /// generated metainfo.xml
+    pub fn to_cab<W: Write>(&self, release: &CabRelease, w: W) -> Result<(), FirmwareError> {
+        let image = FirmwareArchive {
+            hex_file: self.hex_file.clone(),
+            sig_file: None,
+        }
+        .decode()?;
+        let version = image.version();
+        if version.is_unknown() {
+            return Err(FirmwareError::MissingVersion);
+        }
+        let notes: String = release
+            .release_notes
+            .split("\n\n")
+            .map(str::trim)
+            .filter(|paragraph| !paragraph.is_empty())
+            .map(|paragraph| format!("        <p>{}</p>\n", escape_xml(paragraph)))
+            .collect();
+        let date = match &release.date {
+            Some(date) => format!(" date=\"{}\"", escape_xml(date)),
+            None => String::new(),
+        };
+        let metainfo = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<component type="firmware">
+  <id>fi.gekkio.gb_cartpp_xc.firmware</id>
+  <name>GB-CARTPP-XC</name>
+  <summary>Firmware for the GB-CARTPP-XC cartridge flasher/dumper</summary>
+  <provides>
+    <firmware type="flashed">{guid}</firmware>
+  </provides>
+  <url type="homepage">https://github.com/Gekkio/gb-cartpp</url>
+  <metadata_license>CC0-1.0</metadata_license>
+  <project_license>MIT OR Apache-2.0</project_license>
+  <developer_name>Joonas Javanainen</developer_name>
+  <releases>
+    <release urgency="medium" version="{version}"{date}>
+      <checksum filename="{hex_file}" target="content" type="sha1">{sha1}</checksum>
+      <checksum filename="{hex_file}" target="content" type="sha256">{sha256}</checksum>
+      <description>
+{notes}      </description>
+    </release>
+  </releases>
+  <custom>
+    <value key="LVFS::VersionFormat">pair</value>
+    <value key="GB-CARTPP::RomCrc">0x{rom_crc:04x}</value>
+  </custom>
+</component>
+"#,
+            guid = device_guid(),
+            version = version,
+            date = date,
+            hex_file = HEX_FILE_NAME,
+            sha1 = hex_digest::<Sha1>(&self.hex_file),
+            sha256 = hex_digest::<Sha256>(&self.hex_file),
+            notes = notes,
+            rom_crc = image.checksum(),
+        );
+
+        let mut cab = Cabinet::default();
+        cab.files.push(CabFile {
+            name: HEX_FILE_NAME.to_string(),
+            data: self.hex_file.clone(),
+        });
+        if let Some(sig_file) = &self.sig_file {
+            cab.files.push(CabFile {
+                name: SIG_FILE_NAME.to_string(),
+                data: sig_file.clone(),
+            });
+        }
+        cab.files.push(CabFile {
+            name: METAINFO_FILE_NAME.to_string(),
+            data: metainfo.into_bytes(),
+        });
+        cab.write(w)
+    }
+}
@@ -0,0 +1,371 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{
+    cmd::{CartMode, Command, CMD_FORCE_CE, UNLOCK_MAGIC},
+    header::{CartHeader, HEADER_LEN, HEADER_START, NINTENDO_LOGO},
+    usb::{FirmwareMode, Unclaimed, UsbDevice, UsbDeviceKind},
+    DriverError,
+};
+use libusb1_sys::constants::LIBUSB_ERROR_OTHER;
+use log::debug;
+use std::{fmt, thread, time::Duration};
+
+/// Time the cartridge is held in reset after power-on, so VCART and the mapper have settled
+/// before the cartridge starts responding
+pub const POWER_SETTLE_DELAY: Duration = Duration::from_millis(100);
+/// Default number of header reads in `CartDriver::check`
+pub const CHECK_PASSES: usize = 8;
+/// End of the ROM area, which is selected by A15 being low
+pub const ROM_AREA_END: u32 = 0x8000;
+
+/// Bus timing of ROM reads
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ReadTiming {
+    /// Keeps chip select asserted for the whole burst
+    #[default]
+    Fast,
+    /// Toggles chip select for every byte, which is slower but works with more cartridges
+    ForceCe,
+}
+
+impl fmt::Display for ReadTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadTiming::Fast => write!(f, "fast"),
+            ReadTiming::ForceCe => write!(f, "force CE"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CartStatus {
+    pub mode: CartMode,
+    /// Sensed level of the /RES pin. It is only high if the cartridge is powered and out of reset
+    pub reset_pin: bool,
+}
+
+impl CartStatus {
+    pub fn is_powered(&self) -> bool {
+        self.mode.contains(CartMode::VCART)
+    }
+    pub fn is_in_reset(&self) -> bool {
+        self.mode.contains(CartMode::RESET)
+    }
+}
+
+/// An unlocked firmware session with access to the cartridge slot.
+///
+/// Power is always sequenced so that /RES is asserted whenever VCART changes: the cartridge
+/// is put in reset before it is powered off, and stays in reset for the settle delay after it
+/// is powered on.
+///
+/// The firmware powers the cartridge off when the command pipe is deactivated, so it is only
+/// powered for the duration of the session. Dropping the driver powers the cartridge off and
+/// releases the interfaces, ignoring errors. Use `finish` to find out whether teardown
+/// succeeded.
+pub struct CartDriver {
+    // None once the device has been released
+    device: Option<UsbDevice<FirmwareMode>>,
+    mode: CartMode,
+    settle_delay: Duration,
+}
+
+impl CartDriver {
+    pub fn initialize(device: UsbDevice<Unclaimed>) -> Result<CartDriver, DriverError> {
+        let mut drv = CartDriver {
+            device: Some(device.claim_firmware()?),
+            mode: CartMode::RESET,
+            settle_delay: POWER_SETTLE_DELAY,
+        };
+        // from here on, dropping the driver releases the interfaces again
+        drv.unlock()?;
+        drv.mode = drv.status()?.mode;
+        debug!("Cartridge mode after unlock: {:?}", drv.mode);
+        Ok(drv)
+    }
+    /// Sets how long the cartridge is held in reset after power-on
+    pub fn settle_delay(mut self, delay: Duration) -> CartDriver {
+        self.settle_delay = delay;
+        self
+    }
+    /// Powers the cartridge off and ends the session
+    pub fn finish(mut self) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        let powered_off = self.power_off();
+        let device = self.take_device().release()?;
+        powered_off.map(|_| device)
+    }
+    pub fn kind(&self) -> UsbDeviceKind {
+        self.device().kind
+    }
+    fn device(&self) -> &UsbDevice<FirmwareMode> {
+        self.device
+            .as_ref()
+            .expect("firmware device used after release")
+    }
+    fn take_device(&mut self) -> UsbDevice<FirmwareMode> {
+        self.device
+            .take()
+            .expect("firmware device used after release")
+    }
+    fn unlock(&self) -> Result<(), DriverError> {
+        let mut command = vec![Command::Unlock as u8];
+        command.extend_from_slice(&UNLOCK_MAGIC);
+        self.device().write(&command)?;
+        let mut echo = [0; UNLOCK_MAGIC.len()];
+        self.read_exact(&mut echo)?;
+        if echo != UNLOCK_MAGIC {
+            return Err(DriverError::Other(
+                LIBUSB_ERROR_OTHER,
+                "Firmware did not accept the unlock request",
+            ));
+        }
+        Ok(())
+    }
+    fn read_exact(&self, buffer: &mut [u8]) -> Result<(), DriverError> {
+        let mut done = 0;
+        while done < buffer.len() {
+            match self.device().read(&mut buffer[done..])? {
+                0 => {
+                    return Err(DriverError::ShortRead {
+                        expected: buffer.len(),
+                        actual: done,
+                    })
+                }
+                len => done += len,
+            }
+        }
+        Ok(())
+    }
+    /// Reads the cartridge mode and the sensed /RES level from the firmware
+    pub fn status(&self) -> Result<CartStatus, DriverError> {
+        self.device().write(&[Command::GetMode as u8])?;
+        let mut response = [0; 2];
+        self.read_exact(&mut response)?;
+        Ok(CartStatus {
+            mode: CartMode::from_bits_truncate(response[0]),
+            reset_pin: response[1] != 0,
+        })
+    }
+    /// Returns the last mode set by this driver
+    pub fn mode(&self) -> CartMode {
+        self.mode
+    }
+    pub fn is_powered(&self) -> bool {
+        self.mode.contains(CartMode::VCART)
+    }
+    fn set_mode(&mut self, mode: CartMode) -> Result<(), DriverError> {
+        if mode != self.mode {
+            debug!("Cartridge mode {:?} -> {:?}", self.mode, mode);
+            self.device()
+                .write(&[Command::SetMode as u8, mode.bits()])?;
+            self.mode = mode;
+        }
+        Ok(())
+    }
+    /// Powers the cartridge on and releases it from reset once power has settled.
+    ///
+    /// Does nothing if the cartridge is already powered.
+    pub fn power_on(&mut self) -> Result<(), DriverError> {
+        if self.is_powered() {
+            return Ok(());
+        }
+        self.set_mode(CartMode::VCART | CartMode::RESET)?;
+        thread::sleep(self.settle_delay);
+        self.set_mode(CartMode::VCART)
+    }
+    /// Puts the cartridge in reset and powers it off. The cartridge can be swapped afterwards
+    pub fn power_off(&mut self) -> Result<(), DriverError> {
+        if self.is_powered() {
+            self.set_mode(self.mode | CartMode::RESET)?;
+        }
+        self.set_mode(CartMode::RESET)
+    }
+    /// Powers the cartridge off, waits for `delay`, and powers it on again
+    pub fn power_cycle(&mut self, delay: Duration) -> Result<(), DriverError> {
+        self.power_off()?;
+        thread::sleep(delay);
+        self.power_on()
+    }
+    /// Holds the cartridge in reset without changing power
+    pub fn assert_reset(&mut self) -> Result<(), DriverError> {
+        self.set_mode(self.mode | CartMode::RESET)
+    }
+    /// Reads ROM area data starting from `addr`. Fails if the cartridge is unpowered
+    pub fn read_rom(&self, addr: u16, buffer: &mut [u8]) -> Result<(), DriverError> {
+        self.read_rom_with_timing(addr, buffer, ReadTiming::Fast)
+    }
+    pub fn read_rom_with_timing(
+        &self,
+        addr: u16,
+        buffer: &mut [u8],
+        timing: ReadTiming,
+    ) -> Result<(), DriverError> {
+        if u32::from(addr) + buffer.len() as u32 > ROM_AREA_END {
+            return Err(DriverError::AddressOutOfRange {
+                addr: u32::from(addr),
+                len: buffer.len(),
+            });
+        }
+        if !self.is_powered() {
+            return Err(DriverError::CartNotPowered);
+        }
+        let [addr_l, addr_h] = addr.to_le_bytes();
+        let [len_l, len_h] = (buffer.len() as u16).to_le_bytes();
+        let cmd = match timing {
+            ReadTiming::Fast => Command::ReadBurst as u8,
+            ReadTiming::ForceCe => Command::ReadBurst as u8 | CMD_FORCE_CE,
+        };
+        self.device().write(&[cmd, addr_l, addr_h, len_l, len_h])?;
+        self.read_exact(buffer)
+    }
+    /// Writes a byte to the ROM area, for example to select a mapper bank
+    pub fn write_rom(&self, addr: u16, data: u8) -> Result<(), DriverError> {
+        if u32::from(addr) >= ROM_AREA_END {
+            return Err(DriverError::AddressOutOfRange {
+                addr: u32::from(addr),
+                len: 1,
+            });
+        }
+        if !self.is_powered() {
+            return Err(DriverError::CartNotPowered);
+        }
+        let [addr_l, addr_h] = addr.to_le_bytes();
+        self.device()
+            .write(&[Command::Write as u8, addr_l, addr_h, data])?;
+        Ok(())
+    }
+    /// Powers the cartridge on and reads its header `passes` times to check the contacts
+    pub fn check(&mut self, passes: usize) -> Result<CartCheck, DriverError> {
+        self.power_on()?;
+        let mut reads = Vec::with_capacity(passes);
+        for _ in 0..passes.max(1) {
+            let mut header = [0; HEADER_LEN];
+            self.read_rom(HEADER_START, &mut header)?;
+            reads.push(header);
+        }
+        Ok(CartCheck::new(&reads))
+    }
+    /// Releases the cartridge from reset. Fails if the cartridge is unpowered, because driving
+    /// /RES high would power it through the pin
+    pub fn release_reset(&mut self) -> Result<(), DriverError> {
+        if !self.is_powered() {
+            return Err(DriverError::CartNotPowered);
+        }
+        self.set_mode(self.mode - CartMode::RESET)
+    }
+}
+
+impl Drop for CartDriver {
+    fn drop(&mut self) {
+        if self.device.is_some() {
+            if let Err(err) = self.power_off() {
+                debug!("Failed to power off the cartridge: {}", err);
+            }
+            if let Err(err) = self.take_device().release() {
+                debug!("Failed to release the firmware interfaces: {}", err);
+            }
+        }
+    }
+}
+
+/// Result of reading the cartridge header several times
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CartCheck {
+    pub passes: usize,
+    /// The header as read in the first pass
+    pub header: CartHeader,
+    /// Data lines that read differently in some pass, as a bitmask of D0-D7
+    pub unstable_lines: u8,
+    /// Stable data lines that read the Nintendo logo wrong
+    pub logo_errors: u8,
+    /// Every read returned the same floating bus value
+    pub floating: bool,
+}
+
+impl CartCheck {
+    fn new(reads: &[[u8; HEADER_LEN]]) -> CartCheck {
+        let first = &reads[0];
+        let unstable_lines = reads
+            .iter()
+            .flat_map(|read| read.iter().zip(first).map(|(a, b)| a ^ b))
+            .fold(0, |acc, diff| acc | diff);
+        let header = CartHeader::new(*first);
+        let logo_errors = header
+            .logo()
+            .iter()
+            .zip(NINTENDO_LOGO)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            & !unstable_lines;
+        let floating = [0x00, 0xff]
+            .iter()
+            .any(|&value| reads.iter().flatten().all(|&byte| byte == value));
+        CartCheck {
+            passes: reads.len(),
+            header,
+            unstable_lines,
+            logo_errors,
+            floating,
+        }
+    }
+    pub fn verdict(&self) -> CartVerdict {
+        if self.floating {
+            CartVerdict::NoCartridge
+        } else if self.unstable_lines != 0 {
+            CartVerdict::UnstableLines(self.unstable_lines)
+        } else if self.logo_errors != 0 {
+            CartVerdict::StuckLines(self.logo_errors)
+        } else if !self.header.has_valid_header_checksum() {
+            CartVerdict::BadHeaderChecksum
+        } else {
+            CartVerdict::Ok
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CartVerdict {
+    NoCartridge,
+    /// Data lines, as a bitmask of D0-D7, that flap between reads
+    UnstableLines(u8),
+    /// Data lines, as a bitmask of D0-D7, that consistently read the logo wrong
+    StuckLines(u8),
+    /// The logo is correct but the header checksum isn't, so address lines may be at fault
+    BadHeaderChecksum,
+    Ok,
+}
+
+impl CartVerdict {
+    pub fn is_ok(&self) -> bool {
+        *self == CartVerdict::Ok
+    }
+}
+
+impl fmt::Display for CartVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartVerdict::NoCartridge => write!(f, "no cartridge"),
+            CartVerdict::UnstableLines(lines) => {
+                write!(f, "dirty contacts ({} unstable)", DataLines(*lines))
+            }
+            CartVerdict::StuckLines(lines) => {
+                write!(f, "dirty contacts ({} stuck)", DataLines(*lines))
+            }
+            CartVerdict::BadHeaderChecksum => write!(f, "header checksum mismatch"),
+            CartVerdict::Ok => write!(f, "OK"),
+        }
+    }
+}
+
+struct DataLines(u8);
+
+impl fmt::Display for DataLines {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines = (0..8)
+            .filter(|bit| self.0 & (1 << bit) != 0)
+            .map(|bit| format!("D{}", bit))
+            .collect::<Vec<_>>();
+        write!(f, "{}", lines.join(", "))
+    }
+}
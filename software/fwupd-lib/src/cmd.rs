@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The command protocol spoken by the main firmware over the EP2 bulk pipe (see
+//! `firmware/cmd_protocol.h` and `firmware/main.c`).
+//!
+//! Every command starts with a command byte followed by a fixed-length header. Some commands then
+//! stream data in either direction. Commands other than `Ping`, `Unlock`, `Diagnostics` and
+//! `Identify` are only accepted after a successful `Unlock`. Invalid or locked commands are
+//! dropped silently.
+
+use bitflags::bitflags;
+
+pub const CMD_MASK: u8 = 0x1f;
+/// Uses the /CS pin instead of A15 to select the cartridge (`Read`, `ReadBurst`, `Write`,
+/// `WriteBurst`)
+pub const CMD_USE_CS: u8 = 1 << 7;
+/// Toggles chip select for every byte instead of keeping it asserted (`ReadBurst`)
+pub const CMD_FORCE_CE: u8 = 1 << 6;
+/// Uses the VIN pin instead of /WR as the write strobe (`Write`, `WriteBurst`, `FlashBurst`)
+pub const CMD_USE_VIN: u8 = 1 << 6;
+
+pub const UNLOCK_MAGIC: [u8; 16] = [
+    0x0d, 0x68, 0xb7, 0xa3, 0x12, 0x1b, 0x44, 0x13, 0xc2, 0x8a, 0xd0, 0xa4, 0xd3, 0x95, 0xaf, 0x86,
+];
+
+/// Maximum number of writes in a flash write sequence
+pub const MAX_FLASH_WRITE_SEQUENCE: usize = 16;
+/// Length of the `Diagnostics` response
+pub const DIAGNOSTICS_LEN: usize = 11;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    Ping = 0x01,
+    Unlock = 0x02,
+    SetMode = 0x03,
+    GetMode = 0x04,
+    Read = 0x05,
+    ReadBurst = 0x06,
+    Write = 0x07,
+    WriteBurst = 0x08,
+    PollFlashData = 0x09,
+    SetFlashWriteSequence = 0x0a,
+    FlashBurst = 0x0b,
+    Diagnostics = 0x1d,
+    Reset = 0x1e,
+    Identify = 0x1f,
+}
+
+impl Command {
+    /// Decodes a command byte, ignoring the flag bits
+    pub fn from_byte(byte: u8) -> Option<Command> {
+        match byte & CMD_MASK {
+            0x01 => Some(Command::Ping),
+            0x02 => Some(Command::Unlock),
+            0x03 => Some(Command::SetMode),
+            0x04 => Some(Command::GetMode),
+            0x05 => Some(Command::Read),
+            0x06 => Some(Command::ReadBurst),
+            0x07 => Some(Command::Write),
+            0x08 => Some(Command::WriteBurst),
+            0x09 => Some(Command::PollFlashData),
+            0x0a => Some(Command::SetFlashWriteSequence),
+            0x0b => Some(Command::FlashBurst),
+            0x1d => Some(Command::Diagnostics),
+            0x1e => Some(Command::Reset),
+            0x1f => Some(Command::Identify),
+            _ => None,
+        }
+    }
+    pub fn header_len(&self) -> usize {
+        match self {
+            Command::Ping | Command::Unlock => 16,
+            Command::SetMode | Command::SetFlashWriteSequence | Command::Reset => 1,
+            Command::GetMode | Command::Diagnostics | Command::Identify => 0,
+            Command::Read => 2,
+            Command::Write | Command::PollFlashData => 3,
+            Command::ReadBurst | Command::WriteBurst | Command::FlashBurst => 4,
+        }
+    }
+    pub fn requires_unlock(&self) -> bool {
+        !matches!(
+            self,
+            Command::Ping | Command::Unlock | Command::Diagnostics | Command::Identify
+        )
+    }
+}
+
+bitflags! {
+    /// Cartridge power and reset state (`SetMode`/`GetMode`)
+    pub struct CartMode: u8 {
+        const VCART = 1 << 0;
+        const RESET = 1 << 1;
+    }
+}
+
+/// One write of a flash write sequence (`SetFlashWriteSequence`)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FlashWrite {
+    pub addr: u16,
+    pub data: u8,
+    pub use_vin: bool,
+}
+
+impl FlashWrite {
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let [addr_l, addr_h] = self.addr.to_le_bytes();
+        let flags = if self.use_vin { CMD_USE_VIN } else { 0 };
+        [addr_l, addr_h, self.data, flags]
+    }
+    pub fn from_bytes(bytes: [u8; 4]) -> FlashWrite {
+        FlashWrite {
+            addr: u16::from_le_bytes([bytes[0], bytes[1]]),
+            data: bytes[2],
+            use_vin: bytes[3] & CMD_USE_VIN != 0,
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Logiqx XML DAT files, as published by No-Intro
+
+use std::fmt;
+use thiserror::Error;
+
+use crate::{
+    hash::{from_hex, RomHashes},
+    header::CartHeader,
+};
+
+#[derive(Error, Debug)]
+pub enum DatError {
+    #[error("Invalid XML in DAT file: {0}")]
+    Xml(#[from] roxmltree::Error),
+    #[error("Not a Logiqx DAT file (root element is <{0}>)")]
+    NotDatafile(String),
+    #[error("Invalid {attr} \"{value}\" for ROM {rom} in DAT file")]
+    InvalidAttribute {
+        rom: String,
+        attr: &'static str,
+        value: String,
+    },
+}
+
+/// One ROM image of a DAT game entry. Hashes missing from the DAT are None
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DatRom {
+    pub name: String,
+    pub size: Option<u64>,
+    pub crc32: Option<u32>,
+    pub md5: Option<[u8; 16]>,
+    pub sha1: Option<[u8; 20]>,
+    pub sha256: Option<[u8; 32]>,
+    /// No-Intro status, for example "verified" or "baddump"
+    pub status: Option<String>,
+}
+
+impl DatRom {
+    /// Returns true if the size and every hash present in the DAT match
+    pub fn matches(&self, hashes: &RomHashes) -> bool {
+        let has_hash = self.crc32.is_some()
+            || self.md5.is_some()
+            || self.sha1.is_some()
+            || self.sha256.is_some();
+        has_hash
+            && self.size.map_or(true, |size| size == hashes.size)
+            && self.crc32.map_or(true, |crc32| crc32 == hashes.crc32)
+            && self.md5.map_or(true, |md5| md5 == hashes.md5)
+            && self.sha1.map_or(true, |sha1| sha1 == hashes.sha1)
+            && self.sha256.map_or(true, |sha256| sha256 == hashes.sha256)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DatGame {
+    pub name: String,
+    pub roms: Vec<DatRom>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Dat {
+    /// Name from the DAT header, for example "Nintendo - Game Boy"
+    pub name: Option<String>,
+    pub games: Vec<DatGame>,
+}
+
+impl Dat {
+    pub fn parse(text: &str) -> Result<Dat, DatError> {
+        let doc = roxmltree::Document::parse(text)?;
+        let root = doc.root_element();
+        if !root.has_tag_name("datafile") {
+            return Err(DatError::NotDatafile(root.tag_name().name().to_string()));
+        }
+        let mut dat = Dat::default();
+        for node in root.children().filter(|node| node.is_element()) {
+            match node.tag_name().name() {
+                "header" => {
+                    dat.name = node
+                        .children()
+                        .find(|child| child.has_tag_name("name"))
+                        .and_then(|name| name.text())
+                        .map(str::to_string)
+                }
+                // MAME-style DATs call games machines
+                "game" | "machine" => {
+                    let roms = node
+                        .children()
+                        .filter(|child| child.has_tag_name("rom"))
+                        .map(parse_rom)
+                        .collect::<Result<_, _>>()?;
+                    dat.games.push(DatGame {
+                        name: node.attribute("name").unwrap_or_default().to_string(),
+                        roms,
+                    });
+                }
+                _ => (),
+            }
+        }
+        Ok(dat)
+    }
+    /// Identifies a ROM image by its hashes, or by its header title if no hashes match
+    pub fn identify(&self, data: &[u8]) -> DatMatch<'_> {
+        let hashes = RomHashes::calculate(data);
+        for game in &self.games {
+            if let Some(rom) = game.roms.iter().find(|rom| rom.matches(&hashes)) {
+                if rom.status.as_deref() == Some("baddump") {
+                    return DatMatch::KnownBadDump { game, rom };
+                }
+                return DatMatch::Verified { game, rom };
+            }
+        }
+        let title = match CartHeader::from_rom(data) {
+            Some(header) => normalize(&header.title()),
+            None => return DatMatch::Unknown,
+        };
+        if title.is_empty() {
+            return DatMatch::Unknown;
+        }
+        // header titles are truncated, so a game matches if its name starts with the title. An
+        // exact match is preferred, so "TETRIS" doesn't match "Tetris 2" if "Tetris" exists
+        let mut games = self
+            .games
+            .iter()
+            .map(|game| (normalize(&game.name), game))
+            .filter(|(name, _)| name.starts_with(&title))
+            .collect::<Vec<_>>();
+        if games.iter().any(|(name, _)| *name == title) {
+            games.retain(|(name, _)| *name == title);
+        }
+        // different revisions and regions of a game have the same normalized name
+        if games.iter().any(|(name, _)| *name != games[0].0) {
+            return DatMatch::Ambiguous {
+                games: games.into_iter().map(|(_, game)| game).collect(),
+            };
+        }
+        let mut candidates = games
+            .into_iter()
+            .flat_map(|(_, game)| game.roms.iter().map(move |rom| (game, rom)))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, rom)| rom.size != Some(hashes.size));
+        match candidates.first() {
+            Some(&(game, rom)) => DatMatch::BadDump {
+                game,
+                rom,
+                size: hashes.size,
+            },
+            None => DatMatch::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DatMatch<'a> {
+    Verified {
+        game: &'a DatGame,
+        rom: &'a DatRom,
+    },
+    /// The hashes match a ROM that the DAT marks as a bad dump
+    KnownBadDump {
+        game: &'a DatGame,
+        rom: &'a DatRom,
+    },
+    /// The header title matches a game, but the hashes don't
+    BadDump {
+        game: &'a DatGame,
+        rom: &'a DatRom,
+        size: u64,
+    },
+    /// The hashes don't match, and the header title matches several different games
+    Ambiguous {
+        games: Vec<&'a DatGame>,
+    },
+    Unknown,
+}
+
+impl<'a> DatMatch<'a> {
+    pub fn is_verified(&self) -> bool {
+        matches!(self, DatMatch::Verified { .. })
+    }
+}
+
+impl<'a> fmt::Display for DatMatch<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatMatch::Verified { game, .. } => write!(f, "verified ({})", game.name),
+            DatMatch::KnownBadDump { game, .. } => write!(f, "known bad dump of {}", game.name),
+            DatMatch::BadDump { game, rom, size } => {
+                write!(f, "bad dump of {}", game.name)?;
+                match rom.size {
+                    Some(expected) if expected != *size => {
+                        write!(f, " (expected {} bytes, got {})", expected, size)
+                    }
+                    Some(expected) => write!(f, " (expected {} bytes)", expected),
+                    None => Ok(()),
+                }
+            }
+            DatMatch::Ambiguous { games } => {
+                write!(f, "unverified, the title matches several games: ")?;
+                for (idx, game) in games.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", game.name)?;
+                }
+                Ok(())
+            }
+            DatMatch::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+fn parse_rom(node: roxmltree::Node) -> Result<DatRom, DatError> {
+    let name = node.attribute("name").unwrap_or_default().to_string();
+    let attr = |attr: &'static str| node.attribute(attr).filter(|value| !value.is_empty());
+    let invalid = |attr: &'static str, value: &str| DatError::InvalidAttribute {
+        rom: name.clone(),
+        attr,
+        value: value.to_string(),
+    };
+    let size = attr("size")
+        .map(|value| value.parse().map_err(|_| invalid("size", value)))
+        .transpose()?;
+    let crc32 = attr("crc")
+        .map(|value| u32::from_str_radix(value, 16).map_err(|_| invalid("crc", value)))
+        .transpose()?;
+    let md5 = attr("md5")
+        .map(|value| from_hex(value).ok_or_else(|| invalid("md5", value)))
+        .transpose()?;
+    let sha1 = attr("sha1")
+        .map(|value| from_hex(value).ok_or_else(|| invalid("sha1", value)))
+        .transpose()?;
+    let sha256 = attr("sha256")
+        .map(|value| from_hex(value).ok_or_else(|| invalid("sha256", value)))
+        .transpose()?;
+    Ok(DatRom {
+        size,
+        crc32,
+        md5,
+        sha1,
+        sha256,
+        status: attr("status").map(str::to_string),
+        name,
+    })
+}
+
+// Uppercase letters and digits only, without parenthesized tags like "(USA, Europe)"
+fn normalize(name: &str) -> String {
+    let mut depth = 0usize;
+    let mut normalized = String::new();
+    for c in name.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            c if depth == 0 && c.is_ascii_alphanumeric() => normalized.push(c.to_ascii_uppercase()),
+            _ => (),
+        }
+    }
+    normalized
+}
@@ -0,0 +1,276 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use thiserror::Error;
+
+use crate::{
+    cart::{CartDriver, ReadTiming},
+    header::{CartHeader, Mapper, ROM_BANK_SIZE},
+    DriverError,
+};
+
+/// Default number of reads of every bank
+pub const DUMP_PASSES: usize = 2;
+/// Default number of reads of a bank before giving up on a timing mode
+pub const DUMP_MAX_READS: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum DumpError {
+    #[error(transparent)]
+    Driver {
+        #[from]
+        source: DriverError,
+    },
+    #[error("ROM bank {bank} did not read the same twice in {reads} reads")]
+    NoConsensus { bank: usize, reads: usize },
+    #[error("Unknown ROM size code {0:#04x} in the cartridge header")]
+    UnknownRomSize(u8),
+    #[error("Invalid ROM size {0} (must be a multiple of 32 KiB)")]
+    InvalidRomSize(usize),
+    #[error("ROM bank 0 is too short to contain the cartridge header")]
+    MissingHeader,
+    #[error("Cartridges without a mapper have at most 32 KiB of ROM, but {0} KiB was requested")]
+    NoMapperRomSize(usize),
+    #[error(
+        "Don't know how to select ROM banks with {0}. Set the mapper explicitly to dump anyway"
+    )]
+    UnsupportedMapper(Mapper),
+}
+
+/// How a ROM bank was read
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BankStats {
+    pub bank: usize,
+    /// Reads of the bank, including ones in a failed timing mode
+    pub reads: usize,
+    /// Number of different contents seen with the final timing mode
+    pub distinct: usize,
+    pub timing: ReadTiming,
+}
+
+impl BankStats {
+    /// Returns true if some reads of the bank disagreed
+    pub fn is_inconsistent(&self) -> bool {
+        self.distinct > 1
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DumpEvent {
+    /// Bank 0 has been read, and the rest of the ROM is about to be
+    Started {
+        header: CartHeader,
+        banks: usize,
+    },
+    BankRead(BankStats),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RomDump {
+    pub header: CartHeader,
+    pub data: Vec<u8>,
+    pub banks: Vec<BankStats>,
+}
+
+impl RomDump {
+    pub fn inconsistent_banks(&self) -> impl Iterator<Item = &BankStats> {
+        self.banks.iter().filter(|stats| stats.is_inconsistent())
+    }
+}
+
+/// Dumps a cartridge ROM, reading every bank until two reads agree
+pub struct RomDumper {
+    passes: usize,
+    max_reads: usize,
+    timing: ReadTiming,
+    force_ce_fallback: bool,
+    rom_size: Option<usize>,
+    mapper: Option<Mapper>,
+}
+
+impl Default for RomDumper {
+    fn default() -> RomDumper {
+        RomDumper::new()
+    }
+}
+
+impl RomDumper {
+    pub fn new() -> RomDumper {
+        RomDumper {
+            passes: DUMP_PASSES,
+            max_reads: DUMP_MAX_READS,
+            timing: ReadTiming::Fast,
+            force_ce_fallback: false,
+            rom_size: None,
+            mapper: None,
+        }
+    }
+    /// Sets how many times every bank is read. Banks are read more if the reads disagree
+    pub fn passes(mut self, passes: usize) -> RomDumper {
+        self.passes = passes.max(2);
+        self.max_reads = self.max_reads.max(self.passes);
+        self
+    }
+    /// Sets how many times a bank is read before giving up on a timing mode
+    pub fn max_reads(mut self, max_reads: usize) -> RomDumper {
+        self.max_reads = max_reads.max(self.passes);
+        self
+    }
+    pub fn timing(mut self, timing: ReadTiming) -> RomDumper {
+        self.timing = timing;
+        self
+    }
+    /// Retries banks without consensus with `ReadTiming::ForceCe`
+    pub fn force_ce_fallback(mut self, fallback: bool) -> RomDumper {
+        self.force_ce_fallback = fallback;
+        self
+    }
+    /// Overrides the ROM size in the cartridge header
+    pub fn rom_size(mut self, rom_size: Option<usize>) -> RomDumper {
+        self.rom_size = rom_size;
+        self
+    }
+    /// Overrides the mapper in the cartridge header. `Mapper::Other` selects banks like MBC3
+    pub fn mapper(mut self, mapper: Option<Mapper>) -> RomDumper {
+        self.mapper = mapper;
+        self
+    }
+    pub fn dump<F: FnMut(DumpEvent)>(
+        &self,
+        drv: &mut CartDriver,
+        mut cb: F,
+    ) -> Result<RomDump, DumpError> {
+        drv.power_on()?;
+        // bank 0 is mapped at 0x0000 after power-on with all supported mappers
+        let (bank0, stats) = self.read_bank(drv, Mapper::None, 0)?;
+        let header = CartHeader::from_rom(&bank0).ok_or(DumpError::MissingHeader)?;
+        let rom_size = match self.rom_size {
+            Some(size) => size,
+            None => header
+                .rom_size()
+                .ok_or(DumpError::UnknownRomSize(header.rom_size_code()))?,
+        };
+        if rom_size == 0 || rom_size % (2 * ROM_BANK_SIZE) != 0 {
+            return Err(DumpError::InvalidRomSize(rom_size));
+        }
+        let banks = rom_size / ROM_BANK_SIZE;
+        let mapper = match (self.mapper, header.mapper()) {
+            (Some(mapper), _) => mapper,
+            (None, mapper @ Mapper::Other(_)) => return Err(DumpError::UnsupportedMapper(mapper)),
+            (None, mapper) => mapper,
+        };
+        // without a mapper, every bank above 1 would read bank 1 again
+        if mapper == Mapper::None && banks > 2 {
+            return Err(DumpError::NoMapperRomSize(rom_size / 1024));
+        }
+        cb(DumpEvent::Started {
+            header: header.clone(),
+            banks,
+        });
+        cb(DumpEvent::BankRead(stats));
+
+        let mut data = bank0;
+        let mut all_stats = vec![stats];
+        for bank in 1..banks {
+            let (bank_data, stats) = self.read_bank(drv, mapper, bank)?;
+            data.extend_from_slice(&bank_data);
+            all_stats.push(stats);
+            cb(DumpEvent::BankRead(stats));
+        }
+        Ok(RomDump {
+            header,
+            data,
+            banks: all_stats,
+        })
+    }
+    fn read_bank(
+        &self,
+        drv: &CartDriver,
+        mapper: Mapper,
+        bank: usize,
+    ) -> Result<(Vec<u8>, BankStats), DumpError> {
+        let mut timings = vec![self.timing];
+        if self.force_ce_fallback && self.timing != ReadTiming::ForceCe {
+            timings.push(ReadTiming::ForceCe);
+        }
+        let mut total_reads = 0;
+        for timing in timings {
+            let mut reads: Vec<Vec<u8>> = Vec::new();
+            while reads.len() < self.max_reads {
+                // the bank is selected again before every read in case a write was lost
+                let addr = select_bank(drv, mapper, bank)?;
+                let mut buffer = vec![0; ROM_BANK_SIZE];
+                drv.read_rom_with_timing(addr, &mut buffer, timing)?;
+                reads.push(buffer);
+                total_reads += 1;
+                if reads.len() < self.passes {
+                    continue;
+                }
+                if let Some(idx) = consensus(&reads) {
+                    let mut distinct: Vec<&Vec<u8>> = Vec::new();
+                    for read in &reads {
+                        if !distinct.contains(&read) {
+                            distinct.push(read);
+                        }
+                    }
+                    let stats = BankStats {
+                        bank,
+                        reads: total_reads,
+                        distinct: distinct.len(),
+                        timing,
+                    };
+                    return Ok((reads.swap_remove(idx), stats));
+                }
+            }
+        }
+        Err(DumpError::NoConsensus {
+            bank,
+            reads: total_reads,
+        })
+    }
+}
+
+// Returns the index of a read that was seen at least twice, and more often than any other
+fn consensus(reads: &[Vec<u8>]) -> Option<usize> {
+    let counts = reads
+        .iter()
+        .map(|read| reads.iter().filter(|other| *other == read).count())
+        .collect::<Vec<_>>();
+    let (idx, &best) = counts.iter().enumerate().max_by_key(|(_, &count)| count)?;
+    let tied = reads
+        .iter()
+        .zip(&counts)
+        .any(|(read, &count)| count == best && *read != reads[idx]);
+    if best >= 2 && !tied {
+        Some(idx)
+    } else {
+        None
+    }
+}
+
+// Maps `bank` into the ROM area and returns the address it can be read at
+fn select_bank(drv: &CartDriver, mapper: Mapper, bank: usize) -> Result<u16, DriverError> {
+    match (mapper, bank) {
+        (Mapper::Mbc1, _) if bank % 0x20 == 0 => {
+            // banks 0x00/0x20/0x40/0x60 are only visible at 0x0000, in mode 1
+            drv.write_rom(0x6000, 0x01)?;
+            drv.write_rom(0x4000, (bank >> 5) as u8)?;
+            return Ok(0x0000);
+        }
+        (_, 0) => return Ok(0x0000),
+        (Mapper::None, _) => (),
+        (Mapper::Mbc1, _) => {
+            drv.write_rom(0x6000, 0x00)?;
+            drv.write_rom(0x4000, (bank >> 5) as u8)?;
+            drv.write_rom(0x2000, (bank & 0x1f) as u8)?;
+        }
+        (Mapper::Mbc2, _) => drv.write_rom(0x2100, (bank & 0x0f) as u8)?,
+        (Mapper::Mbc5, _) => {
+            drv.write_rom(0x2000, bank as u8)?;
+            drv.write_rom(0x3000, (bank >> 8) as u8)?;
+        }
+        (Mapper::Mbc3 | Mapper::Other(_), _) => drv.write_rom(0x2000, bank as u8)?,
+    }
+    Ok(0x4000)
+}
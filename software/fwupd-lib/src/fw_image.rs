@@ -6,15 +6,24 @@ use flate2::read::GzDecoder;
 use log::warn;
 use pgp::{errors::Error as PgpError, Deserializable, SignedPublicKey, StandaloneSignature};
 use rsa::errors::Error as RsaError;
-use std::io::{self, Cursor, Read};
+use std::{
+    fmt,
+    io::{self, Cursor, Read},
+};
 use thiserror::Error;
 
-use crate::{FirmwareVersion, CONFIG_BLOCK_SIZE, FLASH_BLOCK_SIZE};
+use crate::{
+    boot::{calc_rom_crc, RomCrc, APPLICATION_START},
+    FirmwareVersion, CONFIG_BLOCK_SIZE, FLASH_BLOCK_SIZE,
+};
 
 static SIGNING_KEYS: [&str; 1] = [include_str!(
     "../../signing-keys/E2984F7B7562E0A759A75F36BCF068A71B6D5A67.asc"
 )];
 
+pub const HEX_FILE_NAME: &str = "GB-CARTPP-XC.hex";
+pub const SIG_FILE_NAME: &str = "GB-CARTPP-XC.hex.asc";
+
 #[derive(Error, Debug)]
 pub enum FirmwareError {
     #[error(transparent)]
@@ -37,12 +46,128 @@ pub enum FirmwareError {
         #[from]
         source: ihex::ReaderError,
     },
+    #[error("Firmware data at {0:#08x} is outside flash, ID and config memory")]
+    AddressOutOfRange(u32),
+    #[error("Binary firmware must be exactly {expected} bytes, but got {actual}")]
+    InvalidBinSize { expected: usize, actual: usize },
+    #[error("Invalid ELF file: {0}")]
+    InvalidElf(&'static str),
+    #[error("Invalid cabinet file: {0}")]
+    InvalidCab(&'static str),
+    #[error("Firmware image has no version in its ID bytes")]
+    MissingVersion,
+    #[error("Firmware image sets only one of the two ROM CRC bytes")]
+    PartialRomCrc,
+}
+
+/// Firmware file formats accepted by `FirmwareFile`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FirmwareFormat {
+    /// Signed release archive (.tar.gz with a .hex and an optional .hex.asc)
+    Archive,
+    /// LVFS cabinet containing the same files as a release archive
+    Cab,
+    /// Intel HEX
+    Hex,
+    /// Raw flash contents
+    Bin,
+    /// MPLAB X / XC8 ELF output
+    Elf,
+}
+
+impl FirmwareFormat {
+    /// Detects the format from file contents
+    pub fn detect(data: &[u8]) -> Option<FirmwareFormat> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Some(FirmwareFormat::Archive)
+        } else if data.starts_with(b"MSCF") {
+            Some(FirmwareFormat::Cab)
+        } else if data.starts_with(b"\x7fELF") {
+            Some(FirmwareFormat::Elf)
+        } else if data.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b':') {
+            Some(FirmwareFormat::Hex)
+        } else if data.len() == FLASH_SIZE {
+            Some(FirmwareFormat::Bin)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for FirmwareFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FirmwareFormat::Archive => write!(f, "firmware archive"),
+            FirmwareFormat::Cab => write!(f, "cabinet"),
+            FirmwareFormat::Hex => write!(f, "Intel HEX"),
+            FirmwareFormat::Bin => write!(f, "binary"),
+            FirmwareFormat::Elf => write!(f, "ELF"),
+        }
+    }
+}
+
+/// A firmware file in any supported format. Only archives and cabinets can carry a signature
+#[derive(Debug)]
+pub enum FirmwareFile {
+    Archive(FirmwareArchive),
+    Raw {
+        format: FirmwareFormat,
+        data: Vec<u8>,
+    },
+}
+
+impl FirmwareFile {
+    /// Detects the format of the data. Returns None if the format is unknown, or if an archive
+    /// has no firmware image
+    pub fn from_bytes(data: Vec<u8>) -> Result<Option<FirmwareFile>, FirmwareError> {
+        Ok(match FirmwareFormat::detect(&data) {
+            Some(FirmwareFormat::Archive) => {
+                FirmwareArchive::from_reader(&data[..])?.map(FirmwareFile::Archive)
+            }
+            Some(FirmwareFormat::Cab) => {
+                FirmwareArchive::from_cab(&data[..])?.map(FirmwareFile::Archive)
+            }
+            Some(format) => Some(FirmwareFile::Raw { format, data }),
+            None => None,
+        })
+    }
+    pub fn format(&self) -> FirmwareFormat {
+        match self {
+            FirmwareFile::Archive(_) => FirmwareFormat::Archive,
+            FirmwareFile::Raw { format, .. } => *format,
+        }
+    }
+    /// Decodes the image. Development builds have no ROM CRC in the ID bytes, so one is
+    /// calculated for raw files that don't include it. A CRC with only one of its bytes set is
+    /// rejected
+    pub fn decode(self) -> Result<FirmwareImage, FirmwareError> {
+        let mut image = match self {
+            FirmwareFile::Archive(archive) => return archive.decode(),
+            FirmwareFile::Raw {
+                format: FirmwareFormat::Bin,
+                data,
+            } => FirmwareImage::from_bin(&data)?,
+            FirmwareFile::Raw {
+                format: FirmwareFormat::Elf,
+                data,
+            } => FirmwareImage::from_elf(&data)?,
+            FirmwareFile::Raw { data, .. } => FirmwareImage::from_hex(&data)?,
+        };
+        if image.id_mask[0] != image.id_mask[1] {
+            return Err(FirmwareError::PartialRomCrc);
+        }
+        if !image.id_mask[0] {
+            let [crc_l, crc_h] = image.checksum().to_le_bytes();
+            image.load(0x20_0000, &[crc_l, crc_h])?;
+        }
+        Ok(image)
+    }
 }
 
 #[derive(Debug)]
 pub struct FirmwareArchive {
-    hex_file: Vec<u8>,
-    sig_file: Option<Vec<u8>>,
+    pub(crate) hex_file: Vec<u8>,
+    pub(crate) sig_file: Option<Vec<u8>>,
 }
 
 impl FirmwareArchive {
@@ -53,12 +178,12 @@ impl FirmwareArchive {
             let mut entry = entry?;
             let path = entry.path()?;
             match path.to_str() {
-                Some("GB-CARTPP-XC.hex") => {
+                Some(HEX_FILE_NAME) => {
                     let mut buf = Vec::new();
                     entry.read_to_end(&mut buf)?;
                     hex_file = Some(buf);
                 }
-                Some("GB-CARTPP-XC.hex.asc") => {
+                Some(SIG_FILE_NAME) => {
                     let mut buf = Vec::new();
                     entry.read_to_end(&mut buf)?;
                     sig_file = Some(buf);
@@ -92,58 +217,7 @@ impl FirmwareArchive {
         }
     }
     pub fn decode(self) -> Result<FirmwareImage, FirmwareError> {
-        let mut image = FirmwareImage {
-            flash: Box::new([0xff; 0x8000]),
-            id: [0xff; 8],
-            id_mask: [false; 8],
-            config: [0xff; 14],
-            config_mask: [false; 14],
-        };
-        let mut hex = String::new();
-        (&self.hex_file[..]).read_to_string(&mut hex)?;
-        let mut addr_high = 0;
-        for record in ihex::Reader::new(&hex) {
-            let record = record?;
-            match record {
-                ihex::Record::Data { offset, value } => {
-                    let addr = addr_high | (offset as u32);
-                    match addr {
-                        0x00_0000..=0x00_7fff => {
-                            assert!(addr + value.len() as u32 <= 0x00_8000);
-                            let idx = (addr & 0x7fff) as usize;
-                            let range = idx..(idx + value.len());
-                            image.flash[range].copy_from_slice(&value);
-                        }
-                        0x20_0000..=0x20_0007 => {
-                            assert!(addr + value.len() as u32 <= 0x20_0008);
-                            let idx = (addr & 0b111) as usize;
-                            let range = idx..(idx + value.len());
-                            image.id[range.clone()].copy_from_slice(&value);
-                            for m in &mut image.id_mask[range] {
-                                *m = true;
-                            }
-                        }
-                        0x30_0000..=0x30_000e => {
-                            assert!(addr + value.len() as u32 <= 0x30_000e);
-                            let idx = (addr & 0xf) as usize;
-                            let range = idx..(idx + value.len());
-                            image.config[range.clone()].copy_from_slice(&value);
-                            for m in &mut image.config_mask[range] {
-                                *m = true;
-                            }
-                        }
-                        _ => panic!("Address out of bounds: {:08x}", addr),
-                    }
-                }
-                ihex::Record::ExtendedLinearAddress(upper) => addr_high = (upper as u32) << 16,
-                _ => (),
-            }
-        }
-        image.config[4] = 0xff;
-        image.config[7] = 0xff;
-        image.config_mask[4] = false;
-        image.config_mask[7] = false;
-        Ok(image)
+        FirmwareImage::from_hex(&self.hex_file)
     }
 }
 
@@ -156,9 +230,128 @@ pub struct FirmwareImage {
     pub config_mask: [bool; CONFIG_BLOCK_SIZE],
 }
 
-const MAIN_FIRMWARE_START: usize = 0x800;
+const MAIN_FIRMWARE_START: usize = APPLICATION_START as usize;
+const FLASH_SIZE: usize = 0x8000;
+const ID_START: u32 = 0x20_0000;
+const CONFIG_START: u32 = 0x30_0000;
+
+const PT_LOAD: u32 = 1;
 
 impl FirmwareImage {
+    fn blank() -> FirmwareImage {
+        FirmwareImage {
+            flash: Box::new([0xff; FLASH_SIZE]),
+            id: [0xff; 8],
+            id_mask: [false; 8],
+            config: [0xff; CONFIG_BLOCK_SIZE],
+            config_mask: [false; CONFIG_BLOCK_SIZE],
+        }
+    }
+    /// Copies data to the image and marks ID and config bytes for programming
+    fn load(&mut self, addr: u32, data: &[u8]) -> Result<(), FirmwareError> {
+        let end = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| addr.checked_add(len))
+            .ok_or(FirmwareError::AddressOutOfRange(addr))?;
+        let (bytes, mask, idx): (&mut [u8], Option<&mut [bool]>, u32) = if end <= FLASH_SIZE as u32
+        {
+            (&mut self.flash[..], None, addr)
+        } else if addr >= ID_START && end <= ID_START + self.id.len() as u32 {
+            (&mut self.id, Some(&mut self.id_mask), addr - ID_START)
+        } else if addr >= CONFIG_START && end <= CONFIG_START + CONFIG_BLOCK_SIZE as u32 {
+            (
+                &mut self.config,
+                Some(&mut self.config_mask),
+                addr - CONFIG_START,
+            )
+        } else {
+            return Err(FirmwareError::AddressOutOfRange(addr));
+        };
+        let range = idx as usize..idx as usize + data.len();
+        bytes[range.clone()].copy_from_slice(data);
+        if let Some(mask) = mask {
+            mask[range].fill(true);
+        }
+        Ok(())
+    }
+    // Config bytes 4 and 7 are unimplemented and must not be programmed
+    fn finish(mut self) -> FirmwareImage {
+        self.config[4] = 0xff;
+        self.config[7] = 0xff;
+        self.config_mask[4] = false;
+        self.config_mask[7] = false;
+        self
+    }
+    pub fn from_hex(data: &[u8]) -> Result<FirmwareImage, FirmwareError> {
+        let mut image = FirmwareImage::blank();
+        let mut hex = String::new();
+        (&data[..]).read_to_string(&mut hex)?;
+        let mut addr_high = 0;
+        for record in ihex::Reader::new(&hex) {
+            match record? {
+                ihex::Record::Data { offset, value } => {
+                    image.load(addr_high | (offset as u32), &value)?
+                }
+                ihex::Record::ExtendedLinearAddress(upper) => addr_high = (upper as u32) << 16,
+                _ => (),
+            }
+        }
+        Ok(image.finish())
+    }
+    /// Loads raw flash contents. ID and config bytes are not programmed
+    pub fn from_bin(data: &[u8]) -> Result<FirmwareImage, FirmwareError> {
+        if data.len() != FLASH_SIZE {
+            return Err(FirmwareError::InvalidBinSize {
+                expected: FLASH_SIZE,
+                actual: data.len(),
+            });
+        }
+        let mut image = FirmwareImage::blank();
+        image.flash.copy_from_slice(data);
+        Ok(image.finish())
+    }
+    /// Loads the PT_LOAD segments of a 32-bit little-endian ELF file at their physical addresses
+    pub fn from_elf(data: &[u8]) -> Result<FirmwareImage, FirmwareError> {
+        let u16_at = |offset: usize| -> Result<u16, FirmwareError> {
+            data.get(offset..offset + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                .ok_or(FirmwareError::InvalidElf("truncated file"))
+        };
+        let u32_at = |offset: usize| -> Result<u32, FirmwareError> {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .ok_or(FirmwareError::InvalidElf("truncated file"))
+        };
+        if !data.starts_with(b"\x7fELF") {
+            return Err(FirmwareError::InvalidElf("missing ELF magic"));
+        }
+        // EI_CLASS and EI_DATA
+        if data.get(4..6) != Some(&[1, 1]) {
+            return Err(FirmwareError::InvalidElf("not a 32-bit little-endian file"));
+        }
+        let ph_offset = u32_at(0x1c)? as usize;
+        let ph_size = usize::from(u16_at(0x2a)?);
+        let ph_count = usize::from(u16_at(0x2c)?);
+        let mut image = FirmwareImage::blank();
+        for idx in 0..ph_count {
+            let header = ph_offset + idx * ph_size;
+            let (p_type, p_offset, p_paddr, p_filesz) = (
+                u32_at(header)?,
+                u32_at(header + 0x04)? as usize,
+                u32_at(header + 0x0c)?,
+                u32_at(header + 0x10)? as usize,
+            );
+            if p_type != PT_LOAD || p_filesz == 0 {
+                continue;
+            }
+            let segment = p_offset
+                .checked_add(p_filesz)
+                .and_then(|end| data.get(p_offset..end))
+                .ok_or(FirmwareError::InvalidElf("segment outside file"))?;
+            image.load(p_paddr, segment)?;
+        }
+        Ok(image.finish())
+    }
     pub fn iter_flash_blocks(&self) -> impl Iterator<Item = (u32, &[u8])> {
         self.flash
             .chunks_exact(FLASH_BLOCK_SIZE)
@@ -201,7 +394,13 @@ impl FirmwareImage {
             })
     }
     pub fn checksum(&self) -> u16 {
-        crc16::State::<crc16::XMODEM>::calculate(&self.flash[MAIN_FIRMWARE_START..])
+        calc_rom_crc(&self.flash[MAIN_FIRMWARE_START..])
+    }
+    pub fn rom_crc(&self) -> RomCrc {
+        RomCrc {
+            calculated: self.checksum(),
+            stored: u16::from_le_bytes([self.id[0], self.id[1]]),
+        }
     }
     pub fn version(&self) -> FirmwareVersion {
         FirmwareVersion {
@@ -0,0 +1,46 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// The hashes DAT files use to identify ROM images
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RomHashes {
+    pub size: u64,
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+    pub sha256: [u8; 32],
+}
+
+impl RomHashes {
+    pub fn calculate(data: &[u8]) -> RomHashes {
+        RomHashes {
+            size: data.len() as u64,
+            crc32: crc32fast::hash(data),
+            md5: Md5::digest(data).into(),
+            sha1: Sha1::digest(data).into(),
+            sha256: Sha256::digest(data).into(),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses a hex string of exactly `N` bytes, in either case
+pub fn from_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(text.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(bytes)
+}
@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The cartridge header at 0x0100-0x014f of every Game Boy ROM
+
+use std::{fmt, ops::Range};
+
+pub const HEADER_START: u16 = 0x0100;
+pub const HEADER_LEN: usize = 0x50;
+/// Address range of the Nintendo logo checked by the boot ROM
+pub const LOGO_RANGE: Range<u16> = 0x0104..0x0134;
+
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+const TITLE_OFFSET: usize = 0x34;
+const CGB_FLAG_OFFSET: usize = 0x43;
+const CART_TYPE_OFFSET: usize = 0x47;
+const ROM_SIZE_OFFSET: usize = 0x48;
+const RAM_SIZE_OFFSET: usize = 0x49;
+const HEADER_CHECKSUM_OFFSET: usize = 0x4d;
+const GLOBAL_CHECKSUM_OFFSET: usize = 0x4e;
+
+/// ROM bank size, and the size of the 0x0000-0x3fff and 0x4000-0x7fff areas
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+/// Memory bank controller, as far as ROM banking is concerned
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mapper {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    /// A mapper that is assumed to select ROM banks with writes to 0x2000
+    Other(u8),
+}
+
+impl Mapper {
+    pub fn from_cart_type(cart_type: u8) -> Mapper {
+        match cart_type {
+            0x00 | 0x08 | 0x09 => Mapper::None,
+            0x01..=0x03 => Mapper::Mbc1,
+            0x05 | 0x06 => Mapper::Mbc2,
+            0x0f..=0x13 => Mapper::Mbc3,
+            0x19..=0x1e => Mapper::Mbc5,
+            cart_type => Mapper::Other(cart_type),
+        }
+    }
+}
+
+impl fmt::Display for Mapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mapper::None => write!(f, "no mapper"),
+            Mapper::Mbc1 => write!(f, "MBC1"),
+            Mapper::Mbc2 => write!(f, "MBC2"),
+            Mapper::Mbc3 => write!(f, "MBC3"),
+            Mapper::Mbc5 => write!(f, "MBC5"),
+            Mapper::Other(cart_type) => write!(f, "unknown mapper (type {:#04x})", cart_type),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CartHeader {
+    pub bytes: [u8; HEADER_LEN],
+}
+
+impl CartHeader {
+    pub fn new(bytes: [u8; HEADER_LEN]) -> CartHeader {
+        CartHeader { bytes }
+    }
+    /// Returns the header of a ROM image, or None if the image is too small to have one
+    pub fn from_rom(rom: &[u8]) -> Option<CartHeader> {
+        let start = usize::from(HEADER_START);
+        let bytes = rom.get(start..start + HEADER_LEN)?;
+        Some(CartHeader::new(bytes.try_into().ok()?))
+    }
+    pub fn logo(&self) -> &[u8] {
+        let start = usize::from(LOGO_RANGE.start - HEADER_START);
+        &self.bytes[start..start + NINTENDO_LOGO.len()]
+    }
+    pub fn has_valid_logo(&self) -> bool {
+        self.logo() == NINTENDO_LOGO
+    }
+    /// Returns the title as printable ASCII, without the trailing padding.
+    ///
+    /// The last title byte is the CGB flag in CGB-aware cartridges, so it's left out if it looks
+    /// like one.
+    pub fn title(&self) -> String {
+        let end = if self.bytes[CGB_FLAG_OFFSET] & 0x80 != 0 {
+            CGB_FLAG_OFFSET
+        } else {
+            CGB_FLAG_OFFSET + 1
+        };
+        self.bytes[TITLE_OFFSET..end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '?'
+                }
+            })
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+    pub fn cart_type(&self) -> u8 {
+        self.bytes[CART_TYPE_OFFSET]
+    }
+    pub fn mapper(&self) -> Mapper {
+        Mapper::from_cart_type(self.cart_type())
+    }
+    pub fn rom_size_code(&self) -> u8 {
+        self.bytes[ROM_SIZE_OFFSET]
+    }
+    /// Returns the ROM size in bytes, or None if the size code is unknown
+    pub fn rom_size(&self) -> Option<usize> {
+        match self.rom_size_code() {
+            code @ 0x00..=0x08 => Some(0x8000 << code),
+            _ => None,
+        }
+    }
+    pub fn ram_size_code(&self) -> u8 {
+        self.bytes[RAM_SIZE_OFFSET]
+    }
+    pub fn header_checksum(&self) -> u8 {
+        self.bytes[HEADER_CHECKSUM_OFFSET]
+    }
+    /// Calculates the header checksum the boot ROM expects, over 0x0134-0x014c
+    pub fn calc_header_checksum(&self) -> u8 {
+        self.bytes[TITLE_OFFSET..HEADER_CHECKSUM_OFFSET]
+            .iter()
+            .fold(0u8, |acc, &byte| acc.wrapping_sub(byte).wrapping_sub(1))
+    }
+    pub fn has_valid_header_checksum(&self) -> bool {
+        self.header_checksum() == self.calc_header_checksum()
+    }
+    pub fn global_checksum(&self) -> u16 {
+        u16::from_be_bytes([
+            self.bytes[GLOBAL_CHECKSUM_OFFSET],
+            self.bytes[GLOBAL_CHECKSUM_OFFSET + 1],
+        ])
+    }
+}
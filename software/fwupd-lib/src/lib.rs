@@ -3,14 +3,39 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use bitflags::bitflags;
-use std::{error::Error, fmt};
+use std::{cmp::Ordering, error::Error, fmt, str::FromStr};
 
+pub mod boot;
 pub mod bootloader;
+pub mod cab;
+pub mod cart;
+pub mod cmd;
+pub mod dat;
+pub mod dump;
 pub mod fw_image;
+pub mod hash;
+pub mod header;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod post_mortem;
+pub mod sfr;
+pub mod sim;
+pub mod updater;
 mod usb;
 
+pub use boot::*;
 pub use bootloader::*;
+pub use cab::*;
+pub use cart::*;
+pub use cmd::*;
+pub use dat::*;
+pub use dump::*;
 pub use fw_image::*;
+pub use hash::*;
+pub use header::*;
+pub use post_mortem::*;
+pub use sfr::*;
+pub use updater::*;
 pub use usb::*;
 
 bitflags! {
@@ -35,18 +60,86 @@ pub struct Diagnostics {
     pub stkptr: StkPtr,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct FirmwareVersion {
     pub major: u8,
     pub minor: u8,
 }
 
+impl FirmwareVersion {
+    /// Version bytes of an erased device or image
+    pub const UNKNOWN: FirmwareVersion = FirmwareVersion {
+        major: 0xff,
+        minor: 0xff,
+    };
+
+    pub fn is_unknown(&self) -> bool {
+        *self == FirmwareVersion::UNKNOWN
+    }
+}
+
 impl fmt::Display for FirmwareVersion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match (self.major, self.minor) {
-            (0xff, 0xff) => write!(f, "???"),
-            (major, minor) => write!(f, "{}.{}", major, minor),
+        if self.is_unknown() {
+            write!(f, "???")
+        } else {
+            write!(f, "{}.{}", self.major, self.minor)
+        }
+    }
+}
+
+// An unknown version is older than any known version
+impl Ord for FirmwareVersion {
+    fn cmp(&self, other: &FirmwareVersion) -> Ordering {
+        match (self.is_unknown(), other.is_unknown()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => (self.major, self.minor).cmp(&(other.major, other.minor)),
+        }
+    }
+}
+
+impl PartialOrd for FirmwareVersion {
+    fn partial_cmp(&self, other: &FirmwareVersion) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseVersionError(String);
+
+impl fmt::Display for ParseVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid firmware version \"{}\"", self.0)
+    }
+}
+
+impl Error for ParseVersionError {}
+
+/// Parses "major.minor", optionally prefixed with "v", or "???" for an unknown version
+impl FromStr for FirmwareVersion {
+    type Err = ParseVersionError;
+
+    fn from_str(s: &str) -> Result<FirmwareVersion, ParseVersionError> {
+        if s == "???" {
+            return Ok(FirmwareVersion::UNKNOWN);
         }
+        let digits = s.strip_prefix('v').unwrap_or(s);
+        // u8::from_str accepts a leading '+', which isn't a valid version
+        let parse = |part: &str| {
+            if part.starts_with(|c: char| c.is_ascii_digit()) {
+                part.parse().ok()
+            } else {
+                None
+            }
+        };
+        digits
+            .split_once('.')
+            .and_then(|(major, minor)| Some((parse(major)?, parse(minor)?)))
+            .map(|(major, minor)| FirmwareVersion { major, minor })
+            .ok_or_else(|| ParseVersionError(s.to_string()))
     }
 }
 
@@ -57,35 +150,167 @@ pub enum DriverError {
     UsbIo,
     NoDevice,
     UnsupportedUsbOperation,
+    ReconnectTimeout,
+    Access,
+    Busy,
+    Overflow,
+    NotFound,
+    /// The device returned less data than requested
+    ShortRead {
+        expected: usize,
+        actual: usize,
+    },
+    /// The device accepted less data than was sent
+    ShortWrite {
+        expected: usize,
+        actual: usize,
+    },
+    /// The request would access memory outside the range it is allowed to touch
+    AddressOutOfRange {
+        addr: u32,
+        len: usize,
+    },
+    /// The device is not in the mode the operation requires
+    WrongMode {
+        kind: UsbDeviceKind,
+    },
+    /// The operation requires the cartridge to be powered
+    CartNotPowered,
     Other(i32, &'static str),
+    /// An error annotated with the request and device it happened with
+    Context {
+        context: ErrorContext,
+        source: Box<DriverError>,
+    },
+}
+
+impl DriverError {
+    /// Returns the underlying error without any context
+    pub fn root(&self) -> &DriverError {
+        match self {
+            DriverError::Context { source, .. } => source.root(),
+            err => err,
+        }
+    }
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            DriverError::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+    // errors that are expected if the device disconnects or re-enumerates during a request
+    pub(crate) fn is_disconnect(&self) -> bool {
+        matches!(
+            self.root(),
+            DriverError::NoDevice | DriverError::UsbIo | DriverError::UsbPipe
+        )
+    }
+    pub(crate) fn with_context(self, context: ErrorContext) -> DriverError {
+        match self {
+            err @ DriverError::Context { .. } => err,
+            err => DriverError::Context {
+                context,
+                source: Box::new(err),
+            },
+        }
+    }
 }
 
 impl fmt::Display for DriverError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
+        match self {
             DriverError::UsbPipe => write!(f, "USB pipe error"),
             DriverError::UsbTimeout => write!(f, "USB operation timed out"),
             DriverError::UsbIo => write!(f, "USB I/O error"),
             DriverError::NoDevice => write!(f, "No such device (device disconnected?)"),
             DriverError::UnsupportedUsbOperation => write!(f, "Unsupported USB operation"),
+            DriverError::ReconnectTimeout => write!(f, "Failed to detect device after reset"),
+            DriverError::Access => write!(f, "Access denied (insufficient permissions)"),
+            DriverError::Busy => write!(f, "Device busy (claimed by another program?)"),
+            DriverError::Overflow => write!(f, "USB transfer overflow"),
+            DriverError::NotFound => write!(f, "Not found (device detached or driver missing?)"),
+            DriverError::ShortRead { expected, actual } => write!(
+                f,
+                "Short read (expected {} bytes, got {})",
+                expected, actual
+            ),
+            DriverError::ShortWrite { expected, actual } => write!(
+                f,
+                "Short write (sent {} bytes, {} written)",
+                expected, actual
+            ),
+            DriverError::AddressOutOfRange { addr, len } => {
+                write!(f, "Invalid access of {} bytes at {:#06x}", len, addr)
+            }
+            DriverError::WrongMode { kind } => match kind {
+                UsbDeviceKind::Bootloader { .. } => write!(f, "Device is in bootloader mode"),
+                UsbDeviceKind::Firmware { .. } => write!(f, "Device is not in bootloader mode"),
+                UsbDeviceKind::Unusable => write!(f, "Device is not usable (no driver installed?)"),
+            },
+            DriverError::CartNotPowered => write!(f, "Cartridge is not powered"),
             DriverError::Other(_, msg) => write!(f, "{}", msg),
+            DriverError::Context { context, source } => write!(f, "{} ({})", source, context),
+        }
+    }
+}
+
+impl Error for DriverError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DriverError::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
         }
     }
 }
 
-impl Error for DriverError {}
+/// Describes what the driver was doing when an error happened
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ErrorContext {
+    pub request: Option<VendorCtrlRequest>,
+    pub addr: Option<u32>,
+    pub device: Option<UsbDeviceId>,
+    pub port_path: Option<UsbPortPath>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(request) = self.request {
+            parts.push(format!("{:?}", request));
+        }
+        if let Some(addr) = self.addr {
+            parts.push(format!("at {:#06x}", addr));
+        }
+        if let Some(device) = self.device {
+            parts.push(format!("on device {}", device));
+        }
+        if let Some(port_path) = &self.port_path {
+            parts.push(format!("port {}", port_path));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
 
-pub(crate) const FLASH_BLOCK_SIZE: usize = 64;
+/// Size of the flash blocks that are erased and written at once
+pub const FLASH_BLOCK_SIZE: usize = 64;
 pub(crate) const CONFIG_BLOCK_SIZE: usize = 14;
 
 #[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum VerifyResult {
     Valid,
     Invalid { errors: u32, first_error_addr: u32 },
 }
 
 impl VerifyResult {
-    fn mark_error(&mut self, addr: u32) {
+    pub fn errors(&self) -> u32 {
+        match self {
+            VerifyResult::Valid => 0,
+            VerifyResult::Invalid { errors, .. } => *errors,
+        }
+    }
+    pub(crate) fn mark_error(&mut self, addr: u32) {
         match self {
             VerifyResult::Valid => {
                 *self = VerifyResult::Invalid {
@@ -0,0 +1,645 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! In-memory GB-CARTPP-XC devices for testing without hardware.
+//!
+//! A `MockDevice` answers the bootloader vendor requests against a 32 KiB flash model. After a
+//! reset request it re-enumerates with a new address, and starts the application only if the
+//! real bootloader would (see `boot_decision`). In firmware mode the EP2 pipe is served by a
+//! `sim::FirmwareSim`, so a cartridge can be inserted with `MockDevice::insert_cartridge`.
+
+use libusb1_sys::constants::*;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::{
+    boot::{boot_decision, calc_rom_crc, RomCrc, APPLICATION_END, APPLICATION_START},
+    fw_image::FirmwareImage,
+    post_mortem::{PANIC_MESSAGE_ADDR, PANIC_MESSAGE_LEN, RCON_SAVE_ADDR, STKPTR_SAVE_ADDR},
+    sim::{Cartridge, FirmwareSim},
+    usb::{
+        DeviceDescriptor, Transport, Usb, UsbDeviceId, VendorCtrlRequest, VirtualBus,
+        VirtualDevice, BOOTLOADER_PRODUCT_ID, FIRMWARE_PRODUCT_ID, VENDOR_ID,
+    },
+    CartMode, DriverError, FirmwareVersion, Rcon, StkPtr, DIAGNOSTICS_LEN, EP2_IN, EP2_OUT,
+};
+
+pub const MOCK_BUS_NUMBER: u8 = 1;
+pub const MOCK_BOOTLOADER_VERSION: FirmwareVersion = FirmwareVersion { major: 1, minor: 0 };
+
+const UNLOCK_VALUE: u16 = 0xc2f2;
+const UNLOCK_INDEX: u16 = 0xf09a;
+const RAM_START: u32 = 0x8000_0000;
+const RAM_SIZE: usize = 0x1000;
+const RCON_ADDR: usize = 0xfd0;
+const STKPTR_ADDR: usize = 0xffc;
+const RCON_SAVE: usize = (RCON_SAVE_ADDR - RAM_START) as usize;
+const STKPTR_SAVE: usize = (STKPTR_SAVE_ADDR - RAM_START) as usize;
+const CDC_SET_LINE_CODING: u8 = 0x20;
+const CDC_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const CDC_DTR: u16 = 1 << 0;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// The request times out without being processed
+    Timeout,
+    /// The device stalls the request without processing it
+    Pipe,
+    /// The request is processed, but the lowest bit of the first data byte is flipped
+    CorruptWrite,
+    /// The device disconnects instead of processing the request
+    Disconnect,
+    /// The request is processed, but only half of the requested data is returned
+    ShortRead,
+}
+
+#[derive(Debug)]
+struct FaultRule {
+    request: VendorCtrlRequest,
+    skip: usize,
+    fault: Fault,
+}
+
+#[derive(Debug)]
+struct MockState {
+    flash: Box<[u8; 0x8000]>,
+    id: [u8; 8],
+    config: [u8; 14],
+    ram: Box<[u8; RAM_SIZE]>,
+    bootloader: bool,
+    unlocked: bool,
+    claimed: Vec<u8>,
+    firmware: FirmwareSim,
+    address: u8,
+    connected: bool,
+    // false emulates Windows without a driver for the device, where it can't be opened
+    driver_installed: bool,
+    // returned when the host tries to open the device, e.g. Access without a udev rule
+    open_error: Option<DriverError>,
+    // set by a reset request, so the device shows up again on the next enumeration
+    reconnecting: bool,
+    generation: u32,
+    faults: Vec<FaultRule>,
+    flash_writes: usize,
+    resets: usize,
+}
+
+impl MockState {
+    fn rom_crc(&self) -> RomCrc {
+        RomCrc {
+            calculated: calc_rom_crc(
+                &self.flash[APPLICATION_START as usize..APPLICATION_END as usize],
+            ),
+            stored: u16::from_le_bytes([self.id[0], self.id[1]]),
+        }
+    }
+    fn fw_version(&self) -> FirmwareVersion {
+        FirmwareVersion {
+            major: self.id[3],
+            minor: self.id[2],
+        }
+    }
+    // RCON is stored active-low like the hardware register
+    fn boot(&mut self, rcon: Rcon, stkptr: StkPtr, reset_magic: u8) {
+        let decision = boot_decision(rcon, stkptr, reset_magic, self.rom_crc());
+        self.bootloader = !decision.is_application();
+        self.unlocked = false;
+        self.claimed.clear();
+        self.ram[RCON_ADDR] = !rcon.bits() & 0b0001_1111;
+        self.ram[STKPTR_ADDR] = stkptr.bits();
+        self.ram[RCON_SAVE] = self.ram[RCON_ADDR];
+        self.ram[STKPTR_SAVE] = self.ram[STKPTR_ADDR];
+        // the application starts with a fresh pipe, but the cartridge stays in the slot
+        let cart = self.firmware.remove_cartridge();
+        self.firmware = FirmwareSim::new(self.fw_version(), MOCK_BOOTLOADER_VERSION);
+        let mut diagnostics = [0; DIAGNOSTICS_LEN];
+        diagnostics[2] = self.ram[RCON_ADDR];
+        diagnostics[3] = self.ram[STKPTR_ADDR];
+        self.firmware.set_diagnostics(diagnostics);
+        if let Some(cart) = cart {
+            self.firmware.insert_cartridge(cart);
+        }
+        // the bootloader clears the flags, so only the saved copies are left
+        if self.bootloader {
+            self.ram[RCON_ADDR] = Rcon::IPEN.bits() | 0b0001_1111;
+            self.ram[STKPTR_ADDR] = 0;
+        }
+    }
+    fn reset(&mut self, reset_magic: u8) {
+        self.resets += 1;
+        self.connected = false;
+        self.reconnecting = true;
+        self.boot(Rcon::RI, StkPtr::empty(), reset_magic);
+    }
+    fn take_fault(&mut self, request: u8) -> Option<Fault> {
+        let idx = self
+            .faults
+            .iter()
+            .position(|rule| rule.request as u8 == request)?;
+        let rule = &mut self.faults[idx];
+        if rule.skip > 0 {
+            rule.skip -= 1;
+            None
+        } else {
+            Some(self.faults.remove(idx).fault)
+        }
+    }
+    fn read_byte(&self, addr: u32) -> u8 {
+        match addr {
+            0x00_0000..=0x00_7fff => self.flash[addr as usize],
+            0x20_0000..=0x20_0007 => self.id[(addr & 0x7) as usize],
+            0x30_0000..=0x30_000d => self.config[(addr & 0xf) as usize],
+            RAM_START..=0x8000_0fff => self.ram[(addr - RAM_START) as usize],
+            _ => 0x00,
+        }
+    }
+    fn vendor_in(
+        &mut self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+    ) -> Result<usize, DriverError> {
+        let addr = (index as u32) << 16 | value as u32;
+        let magic = if self.bootloader { 0x42 } else { 0x99 };
+        match request {
+            req if req == VendorCtrlRequest::Identify as u8 => {
+                let fw_version = self.fw_version();
+                let response = [
+                    magic,
+                    MOCK_BOOTLOADER_VERSION.minor,
+                    MOCK_BOOTLOADER_VERSION.major,
+                    fw_version.minor,
+                    fw_version.major,
+                ];
+                let len = data.len().min(response.len());
+                data[..len].copy_from_slice(&response[..len]);
+                Ok(len)
+            }
+            // like the real bootloader, reads are only served while unlocked
+            req if req == VendorCtrlRequest::Read as u8 && self.bootloader && self.unlocked => {
+                for (offset, byte) in data.iter_mut().enumerate() {
+                    *byte = self.read_byte(addr.wrapping_add(offset as u32));
+                }
+                Ok(data.len())
+            }
+            _ => Err(DriverError::UsbPipe),
+        }
+    }
+    fn vendor_out(
+        &mut self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), DriverError> {
+        let addr = (index as u32) << 16 | value as u32;
+        let flash_range = APPLICATION_START..APPLICATION_END;
+        match request {
+            req if req == VendorCtrlRequest::Reset as u8 => {
+                self.reset(value as u8);
+                Ok(())
+            }
+            _ if !self.bootloader => Err(DriverError::UsbPipe),
+            req if req == VendorCtrlRequest::Unlock as u8 => {
+                if value == UNLOCK_VALUE && index == UNLOCK_INDEX {
+                    self.unlocked = true;
+                    Ok(())
+                } else {
+                    Err(DriverError::UsbPipe)
+                }
+            }
+            _ if !self.unlocked => Err(DriverError::UsbPipe),
+            req if req == VendorCtrlRequest::Lock as u8 => {
+                self.unlocked = false;
+                Ok(())
+            }
+            req if req == VendorCtrlRequest::EraseFlash as u8 => {
+                if !flash_range.contains(&addr) {
+                    return Err(DriverError::UsbPipe);
+                }
+                let start = (addr & !0x3f) as usize;
+                self.flash[start..start + 64].fill(0xff);
+                self.flash_writes += 1;
+                Ok(())
+            }
+            req if req == VendorCtrlRequest::WriteFlash as u8 => {
+                // like _write_flash_block, the 64-byte block containing addr is read, the data is
+                // copied over it and the block is erased and written back. Data past the end of
+                // the block is not written
+                let block = addr & !0x3f;
+                // the boot block is write-protected
+                if !flash_range.contains(&block) {
+                    return Ok(());
+                }
+                let start = addr as usize;
+                let len = data.len().min(0x40 - (addr & 0x3f) as usize);
+                self.flash[start..start + len].copy_from_slice(&data[..len]);
+                self.flash_writes += 1;
+                Ok(())
+            }
+            req if req == VendorCtrlRequest::WriteCfg as u8 => {
+                write_range(&mut self.config[..], 0x30_0000, addr, data)
+            }
+            req if req == VendorCtrlRequest::WriteId as u8 => {
+                write_range(&mut self.id[..], 0x20_0000, addr, data)
+            }
+            _ => Err(DriverError::UsbPipe),
+        }
+    }
+}
+
+fn write_range(target: &mut [u8], base: u32, addr: u32, data: &[u8]) -> Result<(), DriverError> {
+    let start = addr.wrapping_sub(base) as usize;
+    match target.get_mut(start..start + data.len()) {
+        Some(target) => {
+            target.copy_from_slice(data);
+            Ok(())
+        }
+        None => Err(DriverError::UsbPipe),
+    }
+}
+
+/// An emulated GB-CARTPP-XC device
+#[derive(Debug)]
+pub struct MockDevice {
+    state: Mutex<MockState>,
+}
+
+impl MockDevice {
+    /// Creates a device with erased flash, which stays in the bootloader after power-on
+    pub fn blank() -> MockDevice {
+        let mut state = MockState {
+            flash: Box::new([0xff; 0x8000]),
+            id: [0xff; 8],
+            config: [0xff; 14],
+            ram: Box::new([0; RAM_SIZE]),
+            bootloader: true,
+            unlocked: false,
+            claimed: Vec::new(),
+            firmware: FirmwareSim::new(MOCK_BOOTLOADER_VERSION, MOCK_BOOTLOADER_VERSION),
+            address: 0,
+            connected: true,
+            driver_installed: true,
+            open_error: None,
+            reconnecting: false,
+            generation: 0,
+            faults: Vec::new(),
+            flash_writes: 0,
+            resets: 0,
+        };
+        state.boot(Rcon::POR | Rcon::BOR, StkPtr::empty(), 0);
+        MockDevice {
+            state: Mutex::new(state),
+        }
+    }
+    /// Creates a device programmed with `fw`, which starts the application after power-on if the
+    /// image has a valid ROM CRC
+    pub fn with_image(fw: &FirmwareImage) -> MockDevice {
+        let device = MockDevice::blank();
+        {
+            let mut state = device.state();
+            let range = APPLICATION_START as usize..APPLICATION_END as usize;
+            state.flash[range.clone()].copy_from_slice(&fw.flash[range]);
+            for (addr, byte) in fw.iter_id_bytes() {
+                state.id[(addr & 0x7) as usize] = byte;
+            }
+            for (addr, byte) in fw.iter_config_bytes() {
+                state.config[(addr & 0xf) as usize] = byte;
+            }
+            state.boot(Rcon::POR | Rcon::BOR, StkPtr::empty(), 0);
+        }
+        device
+    }
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub fn flash(&self) -> Vec<u8> {
+        self.state().flash.to_vec()
+    }
+    pub fn id(&self) -> [u8; 8] {
+        self.state().id
+    }
+    pub fn config(&self) -> [u8; 14] {
+        self.state().config
+    }
+    pub fn is_bootloader(&self) -> bool {
+        self.state().bootloader
+    }
+    /// Returns true if the bootloader accepts erase, write and read requests
+    pub fn is_unlocked(&self) -> bool {
+        self.state().unlocked
+    }
+    /// Interfaces claimed by the host, in claim order
+    pub fn claimed_interfaces(&self) -> Vec<u8> {
+        self.state().claimed.clone()
+    }
+    pub fn is_connected(&self) -> bool {
+        self.state().connected
+    }
+    /// Sets whether the device can be opened, or is detected without a driver
+    pub fn set_driver_installed(&self, installed: bool) {
+        self.state().driver_installed = installed;
+    }
+    /// Makes opening the device fail with `error`, or succeed again if `None`
+    pub fn set_open_error(&self, error: Option<DriverError>) {
+        self.state().open_error = error;
+    }
+    /// Returns true if the host has activated the firmware command pipe by setting DTR
+    pub fn is_pipe_active(&self) -> bool {
+        self.state().firmware.is_active()
+    }
+    pub fn address(&self) -> u8 {
+        self.state().address
+    }
+    /// Number of flash rows erased or written so far
+    pub fn flash_writes(&self) -> usize {
+        self.state().flash_writes
+    }
+    pub fn resets(&self) -> usize {
+        self.state().resets
+    }
+    pub fn cart_mode(&self) -> CartMode {
+        self.state().firmware.mode()
+    }
+    /// Returns the cartridge modes set since the last call, oldest first
+    pub fn take_cart_mode_changes(&self) -> Vec<CartMode> {
+        self.state().firmware.take_mode_changes()
+    }
+    /// Inserts a cartridge into the emulated cartridge slot, returning the previous one
+    pub fn insert_cartridge<C: Cartridge + 'static>(&self, cart: C) -> Option<Box<dyn Cartridge>> {
+        self.state().firmware.insert_cartridge(Box::new(cart))
+    }
+    pub fn remove_cartridge(&self) -> Option<Box<dyn Cartridge>> {
+        self.state().firmware.remove_cartridge()
+    }
+    /// Makes the `skip + 1`th following `request` fail with `fault`
+    pub fn inject_fault(&self, request: VendorCtrlRequest, skip: usize, fault: Fault) {
+        self.state().faults.push(FaultRule {
+            request,
+            skip,
+            fault,
+        });
+    }
+    /// Emulates a firmware panic that is followed by a reset with the given flags. The message
+    /// is left in RAM like the DEBUG build of the firmware does
+    pub fn crash(&self, panic_message: &str, rcon: Rcon, stkptr: StkPtr) {
+        let mut state = self.state();
+        let start = (PANIC_MESSAGE_ADDR - RAM_START) as usize;
+        let message = &mut state.ram[start..start + PANIC_MESSAGE_LEN];
+        message.fill(0);
+        let len = panic_message.len().min(PANIC_MESSAGE_LEN);
+        message[..len].copy_from_slice(&panic_message.as_bytes()[..len]);
+        state.connected = false;
+        state.reconnecting = true;
+        state.boot(rcon, stkptr, 0);
+    }
+    pub fn disconnect(&self) {
+        let mut state = self.state();
+        state.connected = false;
+        state.generation += 1;
+    }
+}
+
+/// A bus of emulated devices, usable through `MockBus::usb`
+#[derive(Debug)]
+pub struct MockBus {
+    devices: Mutex<Vec<Arc<MockDevice>>>,
+    next_address: Arc<AtomicU8>,
+}
+
+impl MockBus {
+    pub fn new() -> Arc<MockBus> {
+        Arc::new(MockBus {
+            devices: Mutex::new(Vec::new()),
+            next_address: Arc::new(AtomicU8::new(1)),
+        })
+    }
+    /// Plugs in a device, which gets the next free address
+    pub fn attach(&self, device: MockDevice) -> Arc<MockDevice> {
+        device.state().address = allocate_address(&self.next_address);
+        let device = Arc::new(device);
+        self.devices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(device.clone());
+        device
+    }
+    pub fn usb(self: &Arc<MockBus>) -> Arc<Usb> {
+        Usb::with_virtual_bus(self.clone())
+    }
+    fn find(&self, id: UsbDeviceId) -> Option<Arc<MockDevice>> {
+        self.devices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|device| id.bus == MOCK_BUS_NUMBER && device.address() == id.address)
+            .cloned()
+    }
+}
+
+fn allocate_address(next_address: &AtomicU8) -> u8 {
+    next_address
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |address| {
+            Some(address % 127 + 1)
+        })
+        .unwrap_or(1)
+}
+
+impl VirtualBus for MockBus {
+    fn devices(&self) -> Vec<VirtualDevice> {
+        let devices = self.devices.lock().unwrap_or_else(PoisonError::into_inner);
+        devices
+            .iter()
+            .filter_map(|device| {
+                let mut state = device.state();
+                if !state.connected {
+                    if !state.reconnecting {
+                        return None;
+                    }
+                    state.connected = true;
+                    state.reconnecting = false;
+                    state.generation += 1;
+                    state.address = allocate_address(&self.next_address);
+                }
+                let (product_id, version) = if state.bootloader {
+                    (BOOTLOADER_PRODUCT_ID, MOCK_BOOTLOADER_VERSION)
+                } else {
+                    (FIRMWARE_PRODUCT_ID, state.fw_version())
+                };
+                Some(VirtualDevice {
+                    id: UsbDeviceId {
+                        bus: MOCK_BUS_NUMBER,
+                        address: state.address,
+                    },
+                    descriptor: DeviceDescriptor {
+                        vendor_id: VENDOR_ID,
+                        product_id,
+                        bcd_device: u16::from_be_bytes([version.major, version.minor]),
+                        manufacturer: 1,
+                        product: 2,
+                    },
+                })
+            })
+            .collect()
+    }
+    fn open(&self, id: UsbDeviceId) -> Result<Option<Box<dyn Transport>>, DriverError> {
+        let device = self.find(id).ok_or(DriverError::NoDevice)?;
+        let state = device.state();
+        if let Some(err) = &state.open_error {
+            return Err(err.clone());
+        }
+        if !state.driver_installed {
+            return Ok(None);
+        }
+        let generation = state.generation;
+        drop(state);
+        Ok(Some(Box::new(MockTransport { device, generation })))
+    }
+}
+
+#[derive(Debug)]
+struct MockTransport {
+    device: Arc<MockDevice>,
+    generation: u32,
+}
+
+impl MockTransport {
+    fn state(&self) -> Result<MutexGuard<'_, MockState>, DriverError> {
+        let state = self.device.state();
+        if state.connected && state.generation == self.generation {
+            Ok(state)
+        } else {
+            Err(DriverError::NoDevice)
+        }
+    }
+}
+
+fn apply_fault(state: &mut MockState, fault: Option<Fault>) -> Result<(), DriverError> {
+    match fault {
+        Some(Fault::Timeout) => Err(DriverError::UsbTimeout),
+        Some(Fault::Pipe) => Err(DriverError::UsbPipe),
+        Some(Fault::Disconnect) => {
+            state.connected = false;
+            state.generation += 1;
+            Err(DriverError::NoDevice)
+        }
+        Some(Fault::CorruptWrite) | Some(Fault::ShortRead) | None => Ok(()),
+    }
+}
+
+impl Transport for MockTransport {
+    fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        _timeout: u32,
+    ) -> Result<usize, DriverError> {
+        let mut state = self.state()?;
+        match request_type {
+            LIBUSB_ENDPOINT_IN if request == LIBUSB_REQUEST_GET_DESCRIPTOR => {
+                let text = match value {
+                    0x0301 => "gekkio.fi",
+                    0x0302 => "GB-CARTPP-XC",
+                    _ => return Err(DriverError::UsbPipe),
+                };
+                let mut descriptor = vec![0, LIBUSB_DT_STRING];
+                descriptor.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+                descriptor[0] = descriptor.len() as u8;
+                let len = data.len().min(descriptor.len());
+                data[..len].copy_from_slice(&descriptor[..len]);
+                Ok(len)
+            }
+            0xc0 => {
+                let fault = state.take_fault(request);
+                apply_fault(&mut state, fault)?;
+                let len = state.vendor_in(request, value, index, data)?;
+                match fault {
+                    Some(Fault::ShortRead) => Ok(len / 2),
+                    _ => Ok(len),
+                }
+            }
+            _ => Err(DriverError::UsbPipe),
+        }
+    }
+    fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        _timeout: u32,
+    ) -> Result<usize, DriverError> {
+        let mut state = self.state()?;
+        match (request_type, request) {
+            (0x40, _) => (),
+            (0x21, CDC_SET_LINE_CODING) if !state.bootloader => return Ok(data.len()),
+            (0x21, CDC_SET_CONTROL_LINE_STATE) if !state.bootloader => {
+                state.firmware.set_active(value & CDC_DTR != 0);
+                return Ok(0);
+            }
+            _ => return Err(DriverError::UsbPipe),
+        }
+        let fault = state.take_fault(request);
+        apply_fault(&mut state, fault)?;
+        let mut data = data.to_vec();
+        if let (Some(Fault::CorruptWrite), Some(byte)) = (fault, data.first_mut()) {
+            *byte ^= 0x01;
+        }
+        state.vendor_out(request, value, index, &data)?;
+        Ok(data.len())
+    }
+    fn bulk_in(&self, endpoint: u8, data: &mut [u8], _timeout: u32) -> Result<usize, DriverError> {
+        let mut state = self.state()?;
+        // the bootloader has no bulk endpoints
+        if state.bootloader || endpoint != EP2_IN {
+            return Err(DriverError::UsbPipe);
+        }
+        match state.firmware.read(data) {
+            0 => Err(DriverError::UsbTimeout),
+            len => Ok(len),
+        }
+    }
+    fn bulk_out(&self, endpoint: u8, data: &[u8], _timeout: u32) -> Result<usize, DriverError> {
+        let mut state = self.state()?;
+        if state.bootloader || endpoint != EP2_OUT {
+            return Err(DriverError::UsbPipe);
+        }
+        // the firmware doesn't read the pipe until DTR has been set
+        if !state.firmware.is_active() {
+            return Err(DriverError::UsbTimeout);
+        }
+        state.firmware.write(data);
+        if let Some(reset_magic) = state.firmware.take_reset() {
+            state.reset(reset_magic);
+        }
+        Ok(data.len())
+    }
+    fn claim_interface(&self, interface: u8) -> Result<(), DriverError> {
+        let mut state = self.state()?;
+        if state.claimed.contains(&interface) {
+            return Err(DriverError::Busy);
+        }
+        state.claimed.push(interface);
+        Ok(())
+    }
+    fn release_interface(&self, interface: u8) -> Result<(), DriverError> {
+        let mut state = self.state()?;
+        match state
+            .claimed
+            .iter()
+            .position(|&claimed| claimed == interface)
+        {
+            Some(idx) => {
+                state.claimed.remove(idx);
+                Ok(())
+            }
+            None => Err(DriverError::NotFound),
+        }
+    }
+}
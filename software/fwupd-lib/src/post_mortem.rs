@@ -0,0 +1,102 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Post-mortem analysis of an application crash, based on what is left in RAM and the reset
+//! flags after the device has reset into the bootloader.
+
+use std::fmt;
+
+use crate::{Diagnostics, Rcon, StkPtr};
+
+/// Address of `panic_message` in the firmware, as seen through the bootloader read request
+pub const PANIC_MESSAGE_ADDR: u32 = 0x8000_0700;
+/// Size of `panic_message` in the firmware
+pub const PANIC_MESSAGE_LEN: usize = 128;
+/// Address of `RCON_SAVE` in the bootloader. RCON itself is reset by the bootloader
+pub const RCON_SAVE_ADDR: u32 = 0x8000_07fe;
+/// Address of `STKPTR_SAVE` in the bootloader. STKPTR itself is cleared by the bootloader
+pub const STKPTR_SAVE_ADDR: u32 = 0x8000_07fd;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetCause {
+    StackOverflow,
+    StackUnderflow,
+    Watchdog,
+    PowerOn,
+    BrownOut,
+    ResetInstruction,
+    /// MCLR or some other reset that doesn't set any flags
+    Other,
+}
+
+impl ResetCause {
+    /// Returns false for resets that happen during normal operation
+    pub fn is_unexpected(&self) -> bool {
+        !matches!(self, ResetCause::PowerOn | ResetCause::ResetInstruction)
+    }
+}
+
+impl From<Diagnostics> for ResetCause {
+    fn from(diagnostics: Diagnostics) -> ResetCause {
+        let Diagnostics { rcon, stkptr } = diagnostics;
+        if stkptr.contains(StkPtr::STKFUL) {
+            ResetCause::StackOverflow
+        } else if stkptr.contains(StkPtr::STKUNF) {
+            ResetCause::StackUnderflow
+        } else if rcon.contains(Rcon::TO) {
+            ResetCause::Watchdog
+        } else if rcon.contains(Rcon::POR) {
+            // BOR is also cleared on power-on
+            ResetCause::PowerOn
+        } else if rcon.contains(Rcon::BOR) {
+            ResetCause::BrownOut
+        } else if rcon.contains(Rcon::RI) {
+            ResetCause::ResetInstruction
+        } else {
+            ResetCause::Other
+        }
+    }
+}
+
+impl fmt::Display for ResetCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResetCause::StackOverflow => write!(f, "stack overflow"),
+            ResetCause::StackUnderflow => write!(f, "stack underflow"),
+            ResetCause::Watchdog => write!(f, "watchdog timeout"),
+            ResetCause::PowerOn => write!(f, "power-on reset"),
+            ResetCause::BrownOut => write!(f, "brown-out reset"),
+            ResetCause::ResetInstruction => write!(f, "reset instruction"),
+            ResetCause::Other => write!(f, "MCLR or unknown reset"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PostMortem {
+    pub diagnostics: Diagnostics,
+    pub cause: ResetCause,
+    /// Message passed to `panic` by a DEBUG build of the firmware
+    pub panic_message: Option<String>,
+}
+
+/// Decodes the NUL-terminated contents of `panic_message`.
+///
+/// RAM is not cleared on reset, so anything that isn't printable ASCII is assumed to be garbage
+/// left over from power-on instead of a message.
+pub fn decode_panic_message(data: &[u8]) -> Option<String> {
+    let len = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    let message = &data[..len];
+    if message.is_empty()
+        || !message
+            .iter()
+            .all(|&byte| byte.is_ascii_graphic() || byte == b' ')
+    {
+        return None;
+    }
+    String::from_utf8(message.to_vec()).ok()
+}
@@ -0,0 +1,523 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Register map of the PIC18F45K50 special function registers, used to decode the dump returned
+//! by `BootloaderDriver::dump_sfrs`.
+//!
+//! Addresses without an entry in the map are either unimplemented or not decoded, and are shown
+//! only by address.
+
+use std::fmt;
+
+/// First SFR address covered by the dump
+pub const SFR_DUMP_START: u16 = 0x0f57;
+/// Number of bytes in a full dump (0x0F57-0x0FFF)
+pub const SFR_DUMP_LEN: usize = 169;
+
+/// A bitfield of a register
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    pub lsb: u8,
+    pub width: u8,
+}
+
+impl Field {
+    pub fn extract(&self, value: u8) -> u8 {
+        (value >> self.lsb) & (0xff >> (8 - self.width))
+    }
+}
+
+const fn bit(name: &'static str, lsb: u8) -> Field {
+    Field {
+        name,
+        lsb,
+        width: 1,
+    }
+}
+
+const fn bits(name: &'static str, lsb: u8, width: u8) -> Field {
+    Field { name, lsb, width }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Register {
+    pub addr: u16,
+    pub name: &'static str,
+    /// Bitfields from the most significant bit down. Empty if the register holds a plain value
+    pub fields: &'static [Field],
+}
+
+const fn reg(addr: u16, name: &'static str) -> Register {
+    Register {
+        addr,
+        name,
+        fields: &[],
+    }
+}
+
+const fn reg_fields(addr: u16, name: &'static str, fields: &'static [Field]) -> Register {
+    Register { addr, name, fields }
+}
+
+const UEP_FIELDS: &[Field] = &[
+    bit("EPHSHK", 4),
+    bit("EPCONDIS", 3),
+    bit("EPOUTEN", 2),
+    bit("EPINEN", 1),
+    bit("EPSTALL", 0),
+];
+
+/// All decoded registers, sorted by address
+pub static REGISTERS: &[Register] = &[
+    reg_fields(0x0f5a, "VREGCON", &[bit("VREGPM1", 1), bit("VREGPM0", 0)]),
+    reg(0x0f5b, "ANSELA"),
+    reg(0x0f5c, "ANSELB"),
+    reg(0x0f5d, "ANSELC"),
+    reg(0x0f5e, "ANSELD"),
+    reg(0x0f5f, "ANSELE"),
+    reg_fields(
+        0x0f60,
+        "UCON",
+        &[
+            bit("PPBRST", 6),
+            bit("SE0", 5),
+            bit("PKTDIS", 4),
+            bit("USBEN", 3),
+            bit("RESUME", 2),
+            bit("SUSPND", 1),
+        ],
+    ),
+    reg_fields(
+        0x0f61,
+        "USTAT",
+        &[bits("ENDP", 3, 4), bit("DIR", 2), bit("PPBI", 1)],
+    ),
+    reg_fields(
+        0x0f62,
+        "UCFG",
+        &[
+            bit("UTEYE", 7),
+            bit("UPUEN", 4),
+            bit("FSEN", 2),
+            bit("PPB1", 1),
+            bit("PPB0", 0),
+        ],
+    ),
+    reg_fields(0x0f63, "UADDR", &[bits("ADDR", 0, 7)]),
+    reg_fields(
+        0x0f64,
+        "UIE",
+        &[
+            bit("SOFIE", 6),
+            bit("STALLIE", 5),
+            bit("IDLEIE", 4),
+            bit("TRNIE", 3),
+            bit("ACTVIE", 2),
+            bit("UERRIE", 1),
+            bit("URSTIE", 0),
+        ],
+    ),
+    reg_fields(
+        0x0f65,
+        "UIR",
+        &[
+            bit("SOFIF", 6),
+            bit("STALLIF", 5),
+            bit("IDLEIF", 4),
+            bit("TRNIF", 3),
+            bit("ACTVIF", 2),
+            bit("UERRIF", 1),
+            bit("URSTIF", 0),
+        ],
+    ),
+    reg_fields(
+        0x0f66,
+        "UEIE",
+        &[
+            bit("BTSEE", 7),
+            bit("BTOEE", 4),
+            bit("DFN8EE", 3),
+            bit("CRC16EE", 2),
+            bit("CRC5EE", 1),
+            bit("PIDEE", 0),
+        ],
+    ),
+    reg_fields(
+        0x0f67,
+        "UEIR",
+        &[
+            bit("BTSEF", 7),
+            bit("BTOEF", 4),
+            bit("DFN8EF", 3),
+            bit("CRC16EF", 2),
+            bit("CRC5EF", 1),
+            bit("PIDEF", 0),
+        ],
+    ),
+    reg(0x0f68, "UFRML"),
+    reg_fields(0x0f69, "UFRMH", &[bits("FRM", 0, 3)]),
+    reg_fields(0x0f6a, "UEP0", UEP_FIELDS),
+    reg_fields(0x0f6b, "UEP1", UEP_FIELDS),
+    reg_fields(0x0f6c, "UEP2", UEP_FIELDS),
+    reg_fields(0x0f6d, "UEP3", UEP_FIELDS),
+    reg_fields(0x0f6e, "UEP4", UEP_FIELDS),
+    reg_fields(0x0f6f, "UEP5", UEP_FIELDS),
+    reg_fields(0x0f70, "UEP6", UEP_FIELDS),
+    reg_fields(0x0f71, "UEP7", UEP_FIELDS),
+    reg_fields(0x0f72, "UEP8", UEP_FIELDS),
+    reg_fields(0x0f73, "UEP9", UEP_FIELDS),
+    reg_fields(0x0f74, "UEP10", UEP_FIELDS),
+    reg_fields(0x0f75, "UEP11", UEP_FIELDS),
+    reg_fields(0x0f76, "UEP12", UEP_FIELDS),
+    reg_fields(0x0f77, "UEP13", UEP_FIELDS),
+    reg_fields(0x0f78, "UEP14", UEP_FIELDS),
+    reg_fields(0x0f79, "UEP15", UEP_FIELDS),
+    reg(0x0f7e, "PMD0"),
+    reg(0x0f7f, "PMD1"),
+    reg(0x0f80, "PORTA"),
+    reg(0x0f81, "PORTB"),
+    reg(0x0f82, "PORTC"),
+    reg(0x0f83, "PORTD"),
+    reg(0x0f84, "PORTE"),
+    reg(0x0f85, "WPUB"),
+    reg(0x0f89, "LATA"),
+    reg(0x0f8a, "LATB"),
+    reg(0x0f8b, "LATC"),
+    reg(0x0f8c, "LATD"),
+    reg(0x0f8d, "LATE"),
+    reg(0x0f92, "TRISA"),
+    reg(0x0f93, "TRISB"),
+    reg(0x0f94, "TRISC"),
+    reg(0x0f95, "TRISD"),
+    reg_fields(
+        0x0f96,
+        "TRISE",
+        &[
+            bit("WPUE3", 7),
+            bit("TRISE2", 2),
+            bit("TRISE1", 1),
+            bit("TRISE0", 0),
+        ],
+    ),
+    reg_fields(0x0f9b, "OSCTUNE", &[bit("SPLLMULT", 7), bits("TUN", 0, 6)]),
+    reg_fields(
+        0x0f9c,
+        "HLVDCON",
+        &[
+            bit("VDIRMAG", 7),
+            bit("BGVST", 6),
+            bit("IRVST", 5),
+            bit("HLVDEN", 4),
+            bits("HLVDL", 0, 4),
+        ],
+    ),
+    reg(0x0f9d, "PIE1"),
+    reg(0x0f9e, "PIR1"),
+    reg(0x0f9f, "IPR1"),
+    reg(0x0fa0, "PIE2"),
+    reg(0x0fa1, "PIR2"),
+    reg(0x0fa2, "IPR2"),
+    reg(0x0fa3, "PIE3"),
+    reg(0x0fa4, "PIR3"),
+    reg(0x0fa5, "IPR3"),
+    reg_fields(
+        0x0fa6,
+        "EECON1",
+        &[
+            bit("EEPGD", 7),
+            bit("CFGS", 6),
+            bit("FREE", 4),
+            bit("WRERR", 3),
+            bit("WREN", 2),
+            bit("WR", 1),
+            bit("RD", 0),
+        ],
+    ),
+    reg(0x0fa7, "EECON2"),
+    reg(0x0fa8, "EEDATA"),
+    reg(0x0fa9, "EEADR"),
+    reg(0x0fab, "RCSTA1"),
+    reg(0x0fac, "TXSTA1"),
+    reg(0x0fad, "TXREG1"),
+    reg(0x0fae, "RCREG1"),
+    reg(0x0faf, "SPBRG1"),
+    reg(0x0fb0, "SPBRGH1"),
+    reg(0x0fb1, "T3CON"),
+    reg(0x0fb2, "TMR3L"),
+    reg(0x0fb3, "TMR3H"),
+    reg(0x0fb4, "T3GCON"),
+    reg_fields(
+        0x0fb5,
+        "ACTCON",
+        &[
+            bit("ACTEN", 7),
+            bit("ACTUD", 6),
+            bit("ACTSRC", 4),
+            bit("ACTLOCK", 3),
+            bit("ACTORS", 1),
+        ],
+    ),
+    reg(0x0fb6, "ECCP1AS"),
+    reg(0x0fb7, "PWM1CON"),
+    reg(0x0fb8, "BAUDCON1"),
+    reg(0x0fb9, "PSTR1CON"),
+    reg(0x0fba, "T2CON"),
+    reg(0x0fbb, "PR2"),
+    reg(0x0fbc, "TMR2"),
+    reg(0x0fbd, "CCP1CON"),
+    reg(0x0fbe, "CCPR1L"),
+    reg(0x0fbf, "CCPR1H"),
+    reg(0x0fc0, "ADCON2"),
+    reg(0x0fc1, "ADCON1"),
+    reg(0x0fc2, "ADCON0"),
+    reg(0x0fc3, "ADRESL"),
+    reg(0x0fc4, "ADRESH"),
+    reg(0x0fc5, "SSP1CON2"),
+    reg(0x0fc6, "SSP1CON1"),
+    reg(0x0fc7, "SSP1STAT"),
+    reg(0x0fc8, "SSP1ADD"),
+    reg(0x0fc9, "SSP1BUF"),
+    reg(0x0fca, "SSP1MSK"),
+    reg(0x0fcb, "SSP1CON3"),
+    reg(0x0fcc, "T1GCON"),
+    reg(0x0fcd, "T1CON"),
+    reg(0x0fce, "TMR1L"),
+    reg(0x0fcf, "TMR1H"),
+    reg_fields(
+        0x0fd0,
+        "RCON",
+        &[
+            bit("IPEN", 7),
+            bit("SBOREN", 6),
+            bit("RI", 4),
+            bit("TO", 3),
+            bit("PD", 2),
+            bit("POR", 1),
+            bit("BOR", 0),
+        ],
+    ),
+    reg_fields(0x0fd1, "WDTCON", &[bit("SWDTEN", 0)]),
+    reg_fields(
+        0x0fd2,
+        "OSCCON2",
+        &[
+            bit("PLLRDY", 7),
+            bit("SOSCRUN", 6),
+            bit("INTSRC", 5),
+            bit("PLLEN", 4),
+            bit("SOSCGO", 3),
+            bit("PRISD", 2),
+            bit("HFIOFR", 1),
+            bit("LFIOFS", 0),
+        ],
+    ),
+    reg_fields(
+        0x0fd3,
+        "OSCCON",
+        &[
+            bit("IDLEN", 7),
+            bits("IRCF", 4, 3),
+            bit("OSTS", 3),
+            bit("HFIOFS", 2),
+            bits("SCS", 0, 2),
+        ],
+    ),
+    reg(0x0fd5, "T0CON"),
+    reg(0x0fd6, "TMR0L"),
+    reg(0x0fd7, "TMR0H"),
+    reg_fields(
+        0x0fd8,
+        "STATUS",
+        &[
+            bit("N", 4),
+            bit("OV", 3),
+            bit("Z", 2),
+            bit("DC", 1),
+            bit("C", 0),
+        ],
+    ),
+    reg(0x0fd9, "FSR2L"),
+    reg(0x0fda, "FSR2H"),
+    reg(0x0fdb, "PLUSW2"),
+    reg(0x0fdc, "PREINC2"),
+    reg(0x0fdd, "POSTDEC2"),
+    reg(0x0fde, "POSTINC2"),
+    reg(0x0fdf, "INDF2"),
+    reg(0x0fe0, "BSR"),
+    reg(0x0fe1, "FSR1L"),
+    reg(0x0fe2, "FSR1H"),
+    reg(0x0fe3, "PLUSW1"),
+    reg(0x0fe4, "PREINC1"),
+    reg(0x0fe5, "POSTDEC1"),
+    reg(0x0fe6, "POSTINC1"),
+    reg(0x0fe7, "INDF1"),
+    reg(0x0fe8, "WREG"),
+    reg(0x0fe9, "FSR0L"),
+    reg(0x0fea, "FSR0H"),
+    reg(0x0feb, "PLUSW0"),
+    reg(0x0fec, "PREINC0"),
+    reg(0x0fed, "POSTDEC0"),
+    reg(0x0fee, "POSTINC0"),
+    reg(0x0fef, "INDF0"),
+    reg(0x0ff0, "INTCON3"),
+    reg(0x0ff1, "INTCON2"),
+    reg_fields(
+        0x0ff2,
+        "INTCON",
+        &[
+            bit("GIE", 7),
+            bit("PEIE", 6),
+            bit("TMR0IE", 5),
+            bit("INT0IE", 4),
+            bit("IOCIE", 3),
+            bit("TMR0IF", 2),
+            bit("INT0IF", 1),
+            bit("IOCIF", 0),
+        ],
+    ),
+    reg(0x0ff3, "PRODL"),
+    reg(0x0ff4, "PRODH"),
+    reg(0x0ff5, "TABLAT"),
+    reg(0x0ff6, "TBLPTRL"),
+    reg(0x0ff7, "TBLPTRH"),
+    reg(0x0ff8, "TBLPTRU"),
+    reg(0x0ff9, "PCL"),
+    reg(0x0ffa, "PCLATH"),
+    reg(0x0ffb, "PCLATU"),
+    reg_fields(
+        0x0ffc,
+        "STKPTR",
+        &[bit("STKFUL", 7), bit("STKUNF", 6), bits("STKPTR", 0, 5)],
+    ),
+    reg(0x0ffd, "TOSL"),
+    reg(0x0ffe, "TOSH"),
+    reg(0x0fff, "TOSU"),
+];
+
+pub fn find_register(addr: u16) -> Option<&'static Register> {
+    REGISTERS
+        .binary_search_by_key(&addr, |register| register.addr)
+        .ok()
+        .map(|idx| &REGISTERS[idx])
+}
+
+/// One register of a dump together with its value
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SfrValue {
+    pub addr: u16,
+    pub register: Option<&'static Register>,
+    pub value: u8,
+}
+
+impl SfrValue {
+    pub fn name(&self) -> Option<&'static str> {
+        self.register.map(|register| register.name)
+    }
+    pub fn fields(&self) -> impl Iterator<Item = (&'static Field, u8)> + '_ {
+        self.register
+            .into_iter()
+            .flat_map(|register| register.fields.iter())
+            .map(move |field| (field, field.extract(self.value)))
+    }
+}
+
+impl fmt::Display for SfrValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#06x} {:<9} {:#04x} {:08b}",
+            self.addr,
+            self.name().unwrap_or("-"),
+            self.value,
+            self.value
+        )?;
+        for (field, value) in self.fields() {
+            write!(f, " {}={}", field.name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A register whose value differs between two dumps
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SfrChange {
+    pub addr: u16,
+    pub register: Option<&'static Register>,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for SfrChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.register.map(|register| register.name).unwrap_or("-");
+        write!(
+            f,
+            "{:#06x} {:<9} {:#04x} -> {:#04x}",
+            self.addr, name, self.old, self.new
+        )?;
+        let fields = self.register.into_iter().flat_map(|r| r.fields.iter());
+        for field in fields {
+            let (old, new) = (field.extract(self.old), field.extract(self.new));
+            if old != new {
+                write!(f, " {}={}->{}", field.name, old, new)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// SFR contents starting at `SFR_DUMP_START`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SfrDump {
+    data: Vec<u8>,
+}
+
+impl SfrDump {
+    /// Creates a dump from raw bytes. A short dump only covers the registers it has data for
+    pub fn new(data: Vec<u8>) -> SfrDump {
+        let mut data = data;
+        data.truncate(SFR_DUMP_LEN);
+        SfrDump { data }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn value(&self, addr: u16) -> Option<u8> {
+        let offset = addr.checked_sub(SFR_DUMP_START)?;
+        self.data.get(usize::from(offset)).copied()
+    }
+    /// Returns the value of a register by name
+    pub fn get(&self, name: &str) -> Option<SfrValue> {
+        let register = REGISTERS.iter().find(|register| register.name == name)?;
+        Some(SfrValue {
+            addr: register.addr,
+            register: Some(register),
+            value: self.value(register.addr)?,
+        })
+    }
+    pub fn iter(&self) -> impl Iterator<Item = SfrValue> + '_ {
+        self.data
+            .iter()
+            .zip(SFR_DUMP_START..)
+            .map(|(&value, addr)| SfrValue {
+                addr,
+                register: find_register(addr),
+                value,
+            })
+    }
+    /// Lists the registers whose values differ from `old`
+    pub fn diff(&self, old: &SfrDump) -> Vec<SfrChange> {
+        self.iter()
+            .zip(old.iter())
+            .filter(|(new, old)| new.value != old.value)
+            .map(|(new, old)| SfrChange {
+                addr: new.addr,
+                register: new.register,
+                old: old.value,
+                new: new.value,
+            })
+            .collect()
+    }
+}
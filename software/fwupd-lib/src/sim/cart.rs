@@ -0,0 +1,435 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::fmt;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+/// A cartridge connected to the emulated cartridge bus.
+///
+/// ROM is selected by A15 being low, and RAM (0xa000-0xbfff) by /CS, so `cs` is true when an
+/// access asserts /CS. `vin` is true when a write is strobed with VIN instead of /WR.
+pub trait Cartridge: fmt::Debug + Send {
+    fn read(&mut self, addr: u16, cs: bool) -> u8;
+    fn write(&mut self, addr: u16, data: u8, cs: bool, vin: bool);
+    /// Called when the cartridge is powered on or its reset line is asserted
+    fn reset(&mut self) {}
+    /// Called before reads with whether chip select stays asserted between bytes
+    fn set_fast_reads(&mut self, _fast: bool) {}
+}
+
+fn is_rom(addr: u16) -> bool {
+    addr < 0x8000
+}
+
+fn is_ram(addr: u16, cs: bool) -> bool {
+    cs && (0xa000..0xc000).contains(&addr)
+}
+
+fn rom_offset(rom: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if rom.is_empty() {
+        None
+    } else {
+        Some((bank * ROM_BANK_SIZE + (addr as usize & 0x3fff)) % rom.len())
+    }
+}
+
+fn ram_offset(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        None
+    } else {
+        Some((bank * RAM_BANK_SIZE + (addr as usize & 0x1fff)) % ram.len())
+    }
+}
+
+fn read_rom(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    rom_offset(rom, bank, addr).map_or(0xff, |offset| rom[offset])
+}
+
+fn read_ram(ram: &[u8], bank: usize, addr: u16) -> u8 {
+    ram_offset(ram, bank, addr).map_or(0xff, |offset| ram[offset])
+}
+
+fn write_ram(ram: &mut [u8], bank: usize, addr: u16, data: u8) {
+    if let Some(offset) = ram_offset(ram, bank, addr) {
+        ram[offset] = data;
+    }
+}
+
+/// A 32 KiB cartridge without a mapper, optionally with RAM
+#[derive(Clone, Debug)]
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>) -> RomOnly {
+        RomOnly { rom, ram: vec![] }
+    }
+    pub fn with_ram(mut self, ram: Vec<u8>) -> RomOnly {
+        self.ram = ram;
+        self
+    }
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+}
+
+impl Cartridge for RomOnly {
+    fn read(&mut self, addr: u16, cs: bool) -> u8 {
+        if is_rom(addr) {
+            read_rom(&self.rom, (addr >> 14) as usize, addr)
+        } else if is_ram(addr, cs) {
+            read_ram(&self.ram, 0, addr)
+        } else {
+            0xff
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8, cs: bool, vin: bool) {
+        if !vin && is_ram(addr, cs) {
+            write_ram(&mut self.ram, 0, addr, data);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    bank1: u8,
+    bank2: u8,
+    mode: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> Mbc1 {
+        Mbc1 {
+            rom,
+            ram,
+            ram_enable: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+        }
+    }
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_bank(&self) -> usize {
+        if self.mode {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Cartridge for Mbc1 {
+    fn read(&mut self, addr: u16, cs: bool) -> u8 {
+        match addr {
+            0x0000..=0x3fff => {
+                let bank = if self.mode { self.bank2 << 5 } else { 0 };
+                read_rom(&self.rom, bank as usize, addr)
+            }
+            0x4000..=0x7fff => {
+                let bank = self.bank2 << 5 | self.bank1;
+                read_rom(&self.rom, bank as usize, addr)
+            }
+            _ if is_ram(addr, cs) && self.ram_enable => read_ram(&self.ram, self.ram_bank(), addr),
+            _ => 0xff,
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8, cs: bool, vin: bool) {
+        if vin {
+            return;
+        }
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = data & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.bank1 = (data & 0x1f).max(1),
+            0x4000..=0x5fff => self.bank2 = data & 0x03,
+            0x6000..=0x7fff => self.mode = data & 0x01 != 0,
+            _ if is_ram(addr, cs) && self.ram_enable => {
+                let bank = self.ram_bank();
+                write_ram(&mut self.ram, bank, addr, data)
+            }
+            _ => (),
+        }
+    }
+    fn reset(&mut self) {
+        self.ram_enable = false;
+        self.bank1 = 1;
+        self.bank2 = 0;
+        self.mode = false;
+    }
+}
+
+/// MBC3 real-time clock registers
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rtc {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,
+    pub halt: bool,
+    pub carry: bool,
+}
+
+impl Rtc {
+    /// Advances the clock by `seconds` unless it is halted
+    pub fn advance(&mut self, seconds: u64) {
+        if self.halt {
+            return;
+        }
+        let total = self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 3600
+            + self.days as u64 * 86400
+            + seconds;
+        let days = total / 86400;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        self.days = (days % 512) as u16;
+        self.carry |= days >= 512;
+    }
+    fn register(&self, index: u8) -> u8 {
+        match index {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0a => self.hours,
+            0x0b => self.days as u8,
+            _ => (self.days >> 8) as u8 & 0x01 | (self.halt as u8) << 6 | (self.carry as u8) << 7,
+        }
+    }
+    fn set_register(&mut self, index: u8, data: u8) {
+        match index {
+            0x08 => self.seconds = data & 0x3f,
+            0x09 => self.minutes = data & 0x3f,
+            0x0a => self.hours = data & 0x1f,
+            0x0b => self.days = self.days & 0x100 | data as u16,
+            _ => {
+                self.days = self.days & 0xff | (data as u16 & 0x01) << 8;
+                self.halt = data & 0x40 != 0;
+                self.carry = data & 0x80 != 0;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    latched: Rtc,
+    ram_enable: bool,
+    rom_bank: u8,
+    ram_select: u8,
+    latch: u8,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> Mbc3 {
+        Mbc3 {
+            rom,
+            ram,
+            rtc: None,
+            latched: Rtc::default(),
+            ram_enable: false,
+            rom_bank: 1,
+            ram_select: 0,
+            latch: 0xff,
+        }
+    }
+    pub fn with_rtc(mut self, rtc: Rtc) -> Mbc3 {
+        self.rtc = Some(rtc);
+        self
+    }
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+impl Cartridge for Mbc3 {
+    fn read(&mut self, addr: u16, cs: bool) -> u8 {
+        match addr {
+            0x0000..=0x3fff => read_rom(&self.rom, 0, addr),
+            0x4000..=0x7fff => read_rom(&self.rom, self.rom_bank as usize, addr),
+            _ if is_ram(addr, cs) && self.ram_enable => match self.ram_select {
+                0x00..=0x03 => read_ram(&self.ram, self.ram_select as usize, addr),
+                0x08..=0x0c if self.rtc.is_some() => self.latched.register(self.ram_select),
+                _ => 0xff,
+            },
+            _ => 0xff,
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8, cs: bool, vin: bool) {
+        if vin {
+            return;
+        }
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = data & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = (data & 0x7f).max(1),
+            0x4000..=0x5fff => self.ram_select = data & 0x0f,
+            0x6000..=0x7fff => {
+                if let (0x00, 0x01, Some(rtc)) = (self.latch, data, self.rtc) {
+                    self.latched = rtc;
+                }
+                self.latch = data;
+            }
+            _ if is_ram(addr, cs) && self.ram_enable => match self.ram_select {
+                0x00..=0x03 => {
+                    let bank = self.ram_select as usize;
+                    write_ram(&mut self.ram, bank, addr, data)
+                }
+                0x08..=0x0c => {
+                    if let Some(rtc) = &mut self.rtc {
+                        rtc.set_register(self.ram_select, data);
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    fn reset(&mut self) {
+        self.ram_enable = false;
+        self.rom_bank = 1;
+        self.ram_select = 0;
+        self.latch = 0xff;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> Mbc5 {
+        Mbc5 {
+            rom,
+            ram,
+            ram_enable: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+}
+
+impl Cartridge for Mbc5 {
+    fn read(&mut self, addr: u16, cs: bool) -> u8 {
+        match addr {
+            0x0000..=0x3fff => read_rom(&self.rom, 0, addr),
+            0x4000..=0x7fff => read_rom(&self.rom, self.rom_bank as usize, addr),
+            _ if is_ram(addr, cs) && self.ram_enable => {
+                read_ram(&self.ram, self.ram_bank as usize, addr)
+            }
+            _ => 0xff,
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8, cs: bool, vin: bool) {
+        if vin {
+            return;
+        }
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = data == 0x0a,
+            0x2000..=0x2fff => self.rom_bank = self.rom_bank & 0x100 | data as u16,
+            0x3000..=0x3fff => self.rom_bank = self.rom_bank & 0xff | (data as u16 & 0x01) << 8,
+            0x4000..=0x5fff => self.ram_bank = data & 0x0f,
+            _ if is_ram(addr, cs) && self.ram_enable => {
+                let bank = self.ram_bank as usize;
+                write_ram(&mut self.ram, bank, addr, data)
+            }
+            _ => (),
+        }
+    }
+    fn reset(&mut self) {
+        self.ram_enable = false;
+        self.rom_bank = 1;
+        self.ram_bank = 0;
+    }
+}
+
+/// Wraps a cartridge whose data lines lose contact now and then.
+///
+/// Every `period`th ROM read flips the bits in `lines`, which makes failures deterministic.
+#[derive(Clone, Debug)]
+pub struct DirtyContacts<C> {
+    cart: C,
+    lines: u8,
+    period: usize,
+    reads: usize,
+    fast_only: bool,
+    fast: bool,
+}
+
+impl<C: Cartridge> DirtyContacts<C> {
+    pub fn new(cart: C, lines: u8, period: usize) -> DirtyContacts<C> {
+        DirtyContacts {
+            cart,
+            lines,
+            period: period.max(1),
+            reads: 0,
+            fast_only: false,
+            fast: false,
+        }
+    }
+    /// Only fails burst reads that keep chip select asserted, like cartridges that can't keep
+    /// up with the fast read path
+    pub fn fast_only(mut self) -> DirtyContacts<C> {
+        self.fast_only = true;
+        self
+    }
+}
+
+impl<C: Cartridge> Cartridge for DirtyContacts<C> {
+    fn read(&mut self, addr: u16, cs: bool) -> u8 {
+        let data = self.cart.read(addr, cs);
+        if !is_rom(addr) || (self.fast_only && !self.fast) {
+            return data;
+        }
+        self.reads += 1;
+        if self.reads % self.period == 0 {
+            data ^ self.lines
+        } else {
+            data
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8, cs: bool, vin: bool) {
+        self.cart.write(addr, data, cs, vin)
+    }
+    fn reset(&mut self) {
+        self.cart.reset()
+    }
+    fn set_fast_reads(&mut self, fast: bool) {
+        self.fast = fast;
+        self.cart.set_fast_reads(fast)
+    }
+}
@@ -0,0 +1,220 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use super::cart::Cartridge;
+
+const UNLOCK_ADDR1: usize = 0x555;
+const UNLOCK_ADDR2: usize = 0x2aa;
+
+pub const AMD_MANUFACTURER_ID: u8 = 0x01;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CommandState {
+    Read,
+    Unlock1,
+    Unlock2,
+    Program,
+    EraseSetup,
+    EraseUnlock1,
+    EraseUnlock2,
+    Autoselect,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Busy {
+    /// Expected value of DQ7 once the operation has finished
+    d7: u8,
+    toggle: bool,
+    remaining_reads: u32,
+}
+
+/// An AMD-style parallel flash chip (e.g. Am29F016) in byte mode.
+///
+/// Commands are recognized with the usual `0xaa@0x555`, `0x55@0x2aa` unlock cycles. Program and
+/// erase operations stay busy for a configurable number of status reads, during which reads
+/// return the inverted DQ7 of the final data and a toggling DQ6.
+#[derive(Clone, Debug)]
+pub struct AmdFlash {
+    data: Vec<u8>,
+    sector_size: usize,
+    device_id: u8,
+    state: CommandState,
+    busy: Option<Busy>,
+    program_reads: u32,
+    erase_reads: u32,
+}
+
+impl AmdFlash {
+    /// Creates an erased chip
+    pub fn new(size: usize, sector_size: usize, device_id: u8) -> AmdFlash {
+        AmdFlash {
+            data: vec![0xff; size],
+            sector_size,
+            device_id,
+            state: CommandState::Read,
+            busy: None,
+            program_reads: 2,
+            erase_reads: 16,
+        }
+    }
+    /// A 2 MiB Am29F016 with 64 KiB sectors
+    pub fn am29f016() -> AmdFlash {
+        AmdFlash::new(0x20_0000, 0x1_0000, 0xad)
+    }
+    pub fn with_contents(mut self, contents: &[u8]) -> AmdFlash {
+        let len = contents.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&contents[..len]);
+        self
+    }
+    /// Sets how many status reads program and erase operations stay busy for
+    pub fn with_timing(mut self, program_reads: u32, erase_reads: u32) -> AmdFlash {
+        self.program_reads = program_reads;
+        self.erase_reads = erase_reads;
+        self
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn is_busy(&self) -> bool {
+        self.busy.is_some()
+    }
+    pub fn read(&mut self, addr: usize) -> u8 {
+        if let Some(busy) = &mut self.busy {
+            let status = busy.d7 ^ 0x80 | (busy.toggle as u8) << 6;
+            busy.toggle = !busy.toggle;
+            busy.remaining_reads = busy.remaining_reads.saturating_sub(1);
+            if busy.remaining_reads == 0 {
+                self.busy = None;
+            }
+            return status;
+        }
+        match self.state {
+            CommandState::Autoselect => match addr & 0xff {
+                0x00 => AMD_MANUFACTURER_ID,
+                0x01 => self.device_id,
+                _ => 0x00,
+            },
+            _ => self.data[addr % self.data.len()],
+        }
+    }
+    pub fn write(&mut self, addr: usize, data: u8) {
+        if self.busy.is_some() {
+            return;
+        }
+        let addr = addr % self.data.len();
+        let cmd_addr = addr & 0xfff;
+        self.state = match (self.state, cmd_addr, data) {
+            (_, _, 0xf0) => CommandState::Read,
+            (CommandState::Read | CommandState::Autoselect, UNLOCK_ADDR1, 0xaa) => {
+                CommandState::Unlock1
+            }
+            (CommandState::Autoselect, _, _) => CommandState::Autoselect,
+            (CommandState::Unlock1, UNLOCK_ADDR2, 0x55) => CommandState::Unlock2,
+            (CommandState::Unlock2, UNLOCK_ADDR1, 0xa0) => CommandState::Program,
+            (CommandState::Unlock2, UNLOCK_ADDR1, 0x80) => CommandState::EraseSetup,
+            (CommandState::Unlock2, UNLOCK_ADDR1, 0x90) => CommandState::Autoselect,
+            (CommandState::Program, _, _) => {
+                // programming can only clear bits
+                let value = self.data[addr] & data;
+                self.data[addr] = value;
+                self.start(value, self.program_reads);
+                CommandState::Read
+            }
+            (CommandState::EraseSetup, UNLOCK_ADDR1, 0xaa) => CommandState::EraseUnlock1,
+            (CommandState::EraseUnlock1, UNLOCK_ADDR2, 0x55) => CommandState::EraseUnlock2,
+            (CommandState::EraseUnlock2, UNLOCK_ADDR1, 0x10) => {
+                self.data.fill(0xff);
+                self.start(0xff, self.erase_reads);
+                CommandState::Read
+            }
+            (CommandState::EraseUnlock2, _, 0x30) => {
+                let start = addr - addr % self.sector_size;
+                let end = (start + self.sector_size).min(self.data.len());
+                self.data[start..end].fill(0xff);
+                self.start(0xff, self.erase_reads);
+                CommandState::Read
+            }
+            _ => CommandState::Read,
+        };
+    }
+    pub fn reset(&mut self) {
+        self.state = CommandState::Read;
+    }
+    fn start(&mut self, value: u8, reads: u32) {
+        if reads > 0 {
+            self.busy = Some(Busy {
+                d7: value & 0x80,
+                toggle: false,
+                remaining_reads: reads,
+            });
+        }
+    }
+}
+
+/// Which strobe is wired to the flash chip's /WE
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FlashWriteStrobe {
+    /// /WR, so mapper register writes also reach the chip
+    Wr,
+    /// VIN, leaving /WR to the mapper
+    Vin,
+}
+
+/// A flash cartridge with an MBC5-compatible mapper in front of an `AmdFlash` chip
+#[derive(Clone, Debug)]
+pub struct FlashCart {
+    chip: AmdFlash,
+    strobe: FlashWriteStrobe,
+    rom_bank: u16,
+}
+
+impl FlashCart {
+    pub fn new(chip: AmdFlash, strobe: FlashWriteStrobe) -> FlashCart {
+        FlashCart {
+            chip,
+            strobe,
+            rom_bank: 1,
+        }
+    }
+    pub fn chip(&self) -> &AmdFlash {
+        &self.chip
+    }
+    fn chip_addr(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3fff => addr as usize,
+            _ => self.rom_bank as usize * 0x4000 + (addr as usize & 0x3fff),
+        }
+    }
+}
+
+impl Cartridge for FlashCart {
+    fn read(&mut self, addr: u16, _cs: bool) -> u8 {
+        if addr < 0x8000 {
+            let addr = self.chip_addr(addr);
+            self.chip.read(addr)
+        } else {
+            0xff
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8, _cs: bool, vin: bool) {
+        if addr >= 0x8000 {
+            return;
+        }
+        if !vin {
+            match addr {
+                0x2000..=0x2fff => self.rom_bank = self.rom_bank & 0x100 | data as u16,
+                0x3000..=0x3fff => self.rom_bank = self.rom_bank & 0xff | (data as u16 & 0x01) << 8,
+                _ => (),
+            }
+        }
+        if vin == (self.strobe == FlashWriteStrobe::Vin) {
+            let addr = self.chip_addr(addr);
+            self.chip.write(addr, data);
+        }
+    }
+    fn reset(&mut self) {
+        self.rom_bank = 1;
+        self.chip.reset();
+    }
+}
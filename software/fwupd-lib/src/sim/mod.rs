@@ -0,0 +1,376 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A host-side re-implementation of the main firmware's command pipe.
+//!
+//! `FirmwareSim` consumes the EP2 OUT byte stream and produces the EP2 IN byte stream exactly like
+//! `fetch_command` and `execute_commands` in `firmware/main.c`, with cartridge accesses going to
+//! an emulated `Cartridge`.
+
+use std::{collections::VecDeque, mem};
+
+use crate::{
+    cmd::{
+        CartMode, Command, FlashWrite, CMD_FORCE_CE, CMD_USE_CS, CMD_USE_VIN, DIAGNOSTICS_LEN,
+        MAX_FLASH_WRITE_SEQUENCE, UNLOCK_MAGIC,
+    },
+    FirmwareVersion,
+};
+
+mod cart;
+mod flash;
+
+pub use self::cart::*;
+pub use self::flash::*;
+
+/// Number of reads after which flash polling gives up.
+///
+/// The firmware polls forever, but a broken cartridge model should not hang the host.
+const POLL_LIMIT: usize = 100_000;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum State {
+    Idle,
+    Header {
+        cmd: Command,
+        flags: u8,
+    },
+    WriteBurst {
+        addr: u16,
+        len: u16,
+        cs: bool,
+        vin: bool,
+    },
+    FlashSequence {
+        count: usize,
+        bytes: Vec<u8>,
+    },
+    FlashBurst {
+        addr: u16,
+        len: u16,
+        vin: bool,
+    },
+}
+
+/// The device side of the EP2 command pipe
+#[derive(Debug)]
+pub struct FirmwareSim {
+    version: FirmwareVersion,
+    bootloader_version: FirmwareVersion,
+    cart: Option<Box<dyn Cartridge>>,
+    active: bool,
+    unlocked: bool,
+    mode: CartMode,
+    mode_changes: Vec<CartMode>,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    state: State,
+    flash_sequence: Vec<FlashWrite>,
+    diagnostics: [u8; DIAGNOSTICS_LEN],
+    reset_magic: Option<u8>,
+}
+
+impl FirmwareSim {
+    pub fn new(version: FirmwareVersion, bootloader_version: FirmwareVersion) -> FirmwareSim {
+        FirmwareSim {
+            version,
+            bootloader_version,
+            cart: None,
+            active: false,
+            unlocked: false,
+            mode: CartMode::RESET,
+            mode_changes: Vec::new(),
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            state: State::Idle,
+            flash_sequence: Vec::new(),
+            diagnostics: [0; DIAGNOSTICS_LEN],
+            reset_magic: None,
+        }
+    }
+    /// Inserts a cartridge, returning the previous one
+    pub fn insert_cartridge(&mut self, cart: Box<dyn Cartridge>) -> Option<Box<dyn Cartridge>> {
+        self.cart.replace(cart)
+    }
+    pub fn remove_cartridge(&mut self) -> Option<Box<dyn Cartridge>> {
+        self.cart.take()
+    }
+    pub fn set_diagnostics(&mut self, diagnostics: [u8; DIAGNOSTICS_LEN]) {
+        self.diagnostics = diagnostics;
+    }
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+    /// Activates or deactivates the pipe, like the host setting or clearing DTR.
+    ///
+    /// Deactivation resets the command state, locks the device and powers off the cartridge.
+    pub fn set_active(&mut self, active: bool) {
+        if self.active && !active {
+            self.reset_state();
+        }
+        self.active = active;
+    }
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked
+    }
+    pub fn mode(&self) -> CartMode {
+        self.mode
+    }
+    /// Returns the modes the cartridge has been put in since the last call, oldest first
+    pub fn take_mode_changes(&mut self) -> Vec<CartMode> {
+        mem::take(&mut self.mode_changes)
+    }
+    /// Returns the reset magic if a `Reset` command has been executed
+    pub fn take_reset(&mut self) -> Option<u8> {
+        self.reset_magic.take()
+    }
+    pub fn reset_state(&mut self) {
+        self.unlocked = false;
+        self.state = State::Idle;
+        self.set_mode(CartMode::RESET);
+        self.rx.clear();
+        self.tx.clear();
+    }
+    /// Handles data sent to EP2 OUT
+    pub fn write(&mut self, data: &[u8]) {
+        if self.reset_magic.is_some() {
+            return;
+        }
+        self.rx.extend(data);
+        self.process();
+    }
+    /// Reads pending EP2 IN data into `buf`, returning the number of bytes read
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.tx.len());
+        for (dst, src) in buf.iter_mut().zip(self.tx.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+    pub fn pending_output(&self) -> usize {
+        self.tx.len()
+    }
+    fn process(&mut self) {
+        loop {
+            match &mut self.state {
+                State::Idle => {
+                    let byte = match self.rx.pop_front() {
+                        Some(byte) => byte,
+                        None => return,
+                    };
+                    match Command::from_byte(byte) {
+                        Some(cmd) if self.unlocked || !cmd.requires_unlock() => {
+                            self.state = State::Header { cmd, flags: byte }
+                        }
+                        _ => (),
+                    }
+                }
+                State::Header { cmd, flags } => {
+                    let (cmd, flags) = (*cmd, *flags);
+                    let len = cmd.header_len();
+                    if self.rx.len() < len {
+                        return;
+                    }
+                    let header = self.rx.drain(..len).collect::<Vec<_>>();
+                    self.state = State::Idle;
+                    self.execute(cmd, flags, &header);
+                    if self.reset_magic.is_some() {
+                        self.rx.clear();
+                        return;
+                    }
+                }
+                State::WriteBurst { addr, len, cs, vin } => {
+                    while *len > 0 {
+                        let data = match self.rx.pop_front() {
+                            Some(data) => data,
+                            None => return,
+                        };
+                        let (bus_addr, cs, vin) = (*addr, *cs, *vin);
+                        *addr = addr.wrapping_add(1);
+                        *len -= 1;
+                        bus_write(&mut self.cart, self.mode, bus_addr, data, cs, vin);
+                    }
+                    self.state = State::Idle;
+                }
+                State::FlashSequence { count, bytes } => {
+                    while bytes.len() < *count * 4 {
+                        match self.rx.pop_front() {
+                            Some(byte) => bytes.push(byte),
+                            None => return,
+                        }
+                    }
+                    self.flash_sequence = bytes
+                        .chunks_exact(4)
+                        .map(|chunk| {
+                            FlashWrite::from_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])
+                        })
+                        .collect();
+                    self.state = State::Idle;
+                }
+                State::FlashBurst { addr, len, vin } => {
+                    while *len > 0 {
+                        let data = match self.rx.pop_front() {
+                            Some(data) => data,
+                            None => return,
+                        };
+                        let (bus_addr, vin) = (*addr, *vin);
+                        *addr = addr.wrapping_add(1);
+                        *len -= 1;
+                        if data != 0xff {
+                            for write in &self.flash_sequence {
+                                bus_write(
+                                    &mut self.cart,
+                                    self.mode,
+                                    write.addr,
+                                    write.data,
+                                    false,
+                                    write.use_vin,
+                                );
+                            }
+                            bus_write(&mut self.cart, self.mode, bus_addr, data, false, vin);
+                            poll_flash_data(&mut self.cart, self.mode, bus_addr, data & 0x80);
+                        }
+                    }
+                    // the response is the remaining length, which is always zero by now
+                    self.tx.extend([0x00, 0x00]);
+                    self.state = State::Idle;
+                }
+            }
+        }
+    }
+    fn execute(&mut self, cmd: Command, flags: u8, header: &[u8]) {
+        let addr = || u16::from_le_bytes([header[0], header[1]]);
+        let len = || u16::from_le_bytes([header[2], header[3]]);
+        let cs = flags & CMD_USE_CS != 0;
+        let vin = flags & CMD_USE_VIN != 0;
+        match cmd {
+            Command::Ping => self.tx.extend(header),
+            Command::Unlock => {
+                if header == UNLOCK_MAGIC {
+                    self.tx.extend(header);
+                    self.unlocked = true;
+                }
+            }
+            Command::SetMode => self.set_mode(CartMode::from_bits_truncate(header[0])),
+            Command::GetMode => {
+                let reset_pin = self.mode == CartMode::VCART;
+                self.tx.extend([self.mode.bits(), reset_pin as u8]);
+            }
+            Command::Read => {
+                if let Some(cart) = &mut self.cart {
+                    cart.set_fast_reads(false);
+                }
+                let data = bus_read(&mut self.cart, self.mode, addr(), cs);
+                self.tx.push_back(data);
+            }
+            Command::ReadBurst => {
+                // the fast path (no CMD_FORCE_CE) only differs in bus timing, so only cartridges
+                // that model timing see the difference
+                if let Some(cart) = &mut self.cart {
+                    cart.set_fast_reads(flags & CMD_FORCE_CE == 0);
+                }
+                let mut addr = addr();
+                for _ in 0..len() {
+                    let data = bus_read(&mut self.cart, self.mode, addr, cs);
+                    self.tx.push_back(data);
+                    addr = addr.wrapping_add(1);
+                }
+            }
+            Command::Write => bus_write(&mut self.cart, self.mode, addr(), header[2], cs, vin),
+            Command::WriteBurst => {
+                self.state = State::WriteBurst {
+                    addr: addr(),
+                    len: len(),
+                    cs,
+                    vin,
+                }
+            }
+            Command::PollFlashData => {
+                let data = poll_flash_data(&mut self.cart, self.mode, addr(), header[2] & 0x80);
+                self.tx.push_back(data);
+            }
+            Command::SetFlashWriteSequence => {
+                // the firmware compares against the size of the sequence in bytes, which would
+                // let a longer sequence overflow it
+                let count = header[0] as usize;
+                if count <= MAX_FLASH_WRITE_SEQUENCE {
+                    self.state = State::FlashSequence {
+                        count,
+                        bytes: Vec::with_capacity(count * 4),
+                    }
+                }
+            }
+            Command::FlashBurst => {
+                self.state = State::FlashBurst {
+                    addr: addr(),
+                    len: len(),
+                    vin,
+                }
+            }
+            Command::Diagnostics => self.tx.extend(self.diagnostics),
+            Command::Reset => self.reset_magic = Some(header[0]),
+            Command::Identify => self.tx.extend([
+                0x99,
+                self.bootloader_version.minor,
+                self.bootloader_version.major,
+                self.version.minor,
+                self.version.major,
+            ]),
+        }
+    }
+    fn set_mode(&mut self, mode: CartMode) {
+        let powered_on = mode.contains(CartMode::VCART) && !self.mode.contains(CartMode::VCART);
+        let reset = mode.contains(CartMode::RESET) && !self.mode.contains(CartMode::RESET);
+        if mode != self.mode {
+            self.mode_changes.push(mode);
+        }
+        self.mode = mode;
+        if let (true, Some(cart)) = (powered_on || reset, &mut self.cart) {
+            cart.reset();
+        }
+    }
+}
+
+// The data bus pull-ups are disabled while the cartridge is unpowered, so 0xff is as good a
+// value as any for a floating bus
+fn bus_read(cart: &mut Option<Box<dyn Cartridge>>, mode: CartMode, addr: u16, cs: bool) -> u8 {
+    match cart {
+        Some(cart) if mode.contains(CartMode::VCART) => cart.read(addr, cs),
+        _ => 0xff,
+    }
+}
+
+// Mappers ignore writes while held in reset
+fn bus_write(
+    cart: &mut Option<Box<dyn Cartridge>>,
+    mode: CartMode,
+    addr: u16,
+    data: u8,
+    cs: bool,
+    vin: bool,
+) {
+    match cart {
+        Some(cart) if mode == CartMode::VCART => cart.write(addr, data, cs, vin),
+        _ => (),
+    }
+}
+
+fn poll_flash_data(
+    cart: &mut Option<Box<dyn Cartridge>>,
+    mode: CartMode,
+    addr: u16,
+    expected_d7: u8,
+) -> u8 {
+    let mut old_data = bus_read(cart, mode, addr, false);
+    for _ in 0..POLL_LIMIT {
+        let new_data = bus_read(cart, mode, addr, false);
+        if new_data & 0x80 == expected_d7 {
+            if new_data == old_data {
+                return new_data;
+            }
+            old_data = new_data;
+        }
+    }
+    old_data
+}
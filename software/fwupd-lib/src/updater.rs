@@ -0,0 +1,475 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{fmt, sync::Arc, time::Duration};
+use thiserror::Error;
+
+use crate::{
+    boot::{BootDecision, BootloaderReason, RomCrc, APPLICATION_END, APPLICATION_START},
+    bootloader::{BlockChange, BootloaderDriver, ByteChange},
+    fw_image::FirmwareImage,
+    usb::{DeviceInfo, Unclaimed, Usb, UsbDevice, UsbDeviceKind, RESET_TIMEOUT},
+    DriverError, FirmwareVersion, VerifyResult,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum UpdateRegion {
+    Flash,
+    Id,
+    Config,
+}
+
+impl fmt::Display for UpdateRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateRegion::Flash => write!(f, "flash"),
+            UpdateRegion::Id => write!(f, "ID bytes"),
+            UpdateRegion::Config => write!(f, "config bytes"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UpdateError {
+    #[error("No GB-CARTPP-XC devices detected")]
+    NoDevices,
+    #[error(
+        "{0} GB-CARTPP-XC devices detected, but only one can be connected during firmware update"
+    )]
+    MultipleDevices(usize),
+    #[error(transparent)]
+    Driver {
+        #[from]
+        source: DriverError,
+    },
+    #[error("Verifying {region} failed: {errors} errors, starting at {first_error_addr:#06x}")]
+    VerifyFailed {
+        region: UpdateRegion,
+        errors: u32,
+        first_error_addr: u32,
+    },
+    #[error("Device will not start the updated firmware after reset: {0}")]
+    WillNotBoot(BootloaderReason),
+    #[error("Device already has newer firmware v{device_version} than the image v{image_version}")]
+    Downgrade {
+        device_version: FirmwareVersion,
+        image_version: FirmwareVersion,
+    },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum UpdateStage {
+    EnterBootloader,
+    Checksum,
+    WriteFlash,
+    WriteId,
+    VerifyFlash,
+    VerifyId,
+    VerifyConfig,
+    BootCheck,
+    Reset,
+}
+
+impl fmt::Display for UpdateStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateStage::EnterBootloader => write!(f, "Entering bootloader"),
+            UpdateStage::Checksum => write!(f, "Calculating checksum"),
+            UpdateStage::WriteFlash => write!(f, "Updating flash"),
+            UpdateStage::WriteId => write!(f, "Updating ID bytes"),
+            UpdateStage::VerifyFlash => write!(f, "Verifying flash"),
+            UpdateStage::VerifyId => write!(f, "Verifying ID bytes"),
+            UpdateStage::VerifyConfig => write!(f, "Verifying config bytes"),
+            UpdateStage::BootCheck => write!(f, "Checking ROM CRC"),
+            UpdateStage::Reset => write!(f, "Resetting device"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+pub enum UpdateEvent {
+    /// A candidate device was found, whether it is usable or not
+    DeviceDetected(DeviceInfo),
+    /// The device that will be updated
+    DeviceSelected(DeviceInfo),
+    StageStarted {
+        stage: UpdateStage,
+    },
+    /// A flash block was erased or written
+    BlockWritten {
+        addr: u32,
+    },
+    /// A byte read back from the device did not match the image
+    VerifyError {
+        region: UpdateRegion,
+        addr: u32,
+        expected: u8,
+        actual: u8,
+    },
+    /// Progress within `WriteFlash` or `VerifyFlash`, in bytes
+    Progress {
+        stage: UpdateStage,
+        done: u32,
+        total: u32,
+        errors: u32,
+    },
+    /// Versions and checksums of the image and the firmware currently on the device
+    Compared {
+        image_version: FirmwareVersion,
+        image_checksum: u16,
+        device_version: FirmwareVersion,
+        device_checksum: u16,
+    },
+    /// The image has an invalid ROM CRC, so the device will stay in the bootloader after updating
+    InvalidImageRomCrc(RomCrc),
+    /// A config byte on the device differs from the image. Updates never write config bytes
+    ConfigMismatch {
+        addr: u32,
+        expected: u8,
+        actual: u8,
+    },
+}
+
+/// What an update would change, gathered without erasing or writing anything
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DryRunReport {
+    pub device_checksum: u16,
+    pub image_checksum: u16,
+    pub flash: VerifyResult,
+    pub id: VerifyResult,
+    pub config: VerifyResult,
+    pub flash_changes: Vec<BlockChange>,
+    pub id_changes: Vec<ByteChange>,
+    /// Config bytes are never written by an update, so these are not counted as changes
+    pub config_changes: Vec<ByteChange>,
+}
+
+impl DryRunReport {
+    pub fn changed_bytes(&self) -> usize {
+        self.flash_changes
+            .iter()
+            .map(|block| block.bytes.len())
+            .sum::<usize>()
+            + self.id_changes.len()
+    }
+    pub fn is_up_to_date(&self) -> bool {
+        self.flash_changes.is_empty() && self.id_changes.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum UpdateOutcome {
+    /// The device already had the same firmware, so nothing was written
+    UpToDate(DeviceInfo),
+    Updated(DeviceInfo),
+    /// Nothing was written, and the device was returned to the mode it was found in
+    DryRun(DeviceInfo, DryRunReport),
+}
+
+/// Updates the firmware of a GB-CARTPP device, reporting progress through a callback
+#[derive(Clone, Debug)]
+pub struct Updater {
+    usb: Arc<Usb>,
+    reset_timeout: Duration,
+    dry_run: bool,
+    allow_downgrade: bool,
+}
+
+impl Updater {
+    pub fn new(usb: &Arc<Usb>) -> Updater {
+        Updater {
+            usb: usb.clone(),
+            reset_timeout: RESET_TIMEOUT,
+            dry_run: false,
+            allow_downgrade: false,
+        }
+    }
+    /// Sets how long to wait for the device after each reset
+    pub fn reset_timeout(mut self, timeout: Duration) -> Updater {
+        self.reset_timeout = timeout;
+        self
+    }
+    /// Compares the device with the image instead of updating it
+    pub fn dry_run(mut self, dry_run: bool) -> Updater {
+        self.dry_run = dry_run;
+        self
+    }
+    /// Allows writing an image that is older than the firmware on the device
+    pub fn allow_downgrade(mut self, allow_downgrade: bool) -> Updater {
+        self.allow_downgrade = allow_downgrade;
+        self
+    }
+    /// Finds the only connected device that is in bootloader or firmware mode
+    pub fn select_device<F: FnMut(UpdateEvent)>(
+        &self,
+        mut cb: F,
+    ) -> Result<UsbDevice<Unclaimed>, UpdateError> {
+        let mut devices = Vec::new();
+        for device in Usb::list_devices(&self.usb)? {
+            cb(UpdateEvent::DeviceDetected(device.info()));
+            if matches!(
+                device.kind,
+                UsbDeviceKind::Bootloader { .. } | UsbDeviceKind::Firmware { .. }
+            ) {
+                devices.push(device);
+            }
+        }
+        match devices.len() {
+            0 => Err(UpdateError::NoDevices),
+            1 => Ok(devices.remove(0)),
+            count => Err(UpdateError::MultipleDevices(count)),
+        }
+    }
+    pub fn update<F: FnMut(UpdateEvent)>(
+        &self,
+        fw: &FirmwareImage,
+        mut cb: F,
+    ) -> Result<UpdateOutcome, UpdateError> {
+        let device = self.select_device(&mut cb)?;
+        self.update_device(device, fw, cb)
+    }
+    pub fn update_device<F: FnMut(UpdateEvent)>(
+        &self,
+        mut device: UsbDevice<Unclaimed>,
+        fw: &FirmwareImage,
+        mut cb: F,
+    ) -> Result<UpdateOutcome, UpdateError> {
+        cb(UpdateEvent::DeviceSelected(device.info()));
+        // the firmware reports its version, so a downgrade is refused without a reset
+        if let UsbDeviceKind::Firmware { fw_version, .. } = device.kind {
+            if is_downgrade(fw_version, fw.version()) && !self.allow_downgrade {
+                return Err(UpdateError::Downgrade {
+                    device_version: fw_version,
+                    image_version: fw.version(),
+                });
+            }
+        }
+        let was_bootloader = device.kind.is_bootloader();
+        if !was_bootloader {
+            cb(UpdateEvent::StageStarted {
+                stage: UpdateStage::EnterBootloader,
+            });
+            device = device.enter_bootloader_and_wait(self.reset_timeout)?;
+        }
+        let drv = BootloaderDriver::initialize(device)?;
+
+        cb(UpdateEvent::StageStarted {
+            stage: UpdateStage::Checksum,
+        });
+        let device_checksum = drv.calc_flash_checksum()?;
+        let image_checksum = fw.checksum();
+        let image_version = fw.version();
+        let device_version = drv.firmware_version();
+        cb(UpdateEvent::Compared {
+            image_version,
+            image_checksum,
+            device_version,
+            device_checksum,
+        });
+        if !fw.rom_crc().is_valid() {
+            cb(UpdateEvent::InvalidImageRomCrc(fw.rom_crc()));
+        }
+
+        if is_downgrade(device_version, image_version) && !self.allow_downgrade {
+            if was_bootloader {
+                drv.finish()?;
+            } else {
+                cb(UpdateEvent::StageStarted {
+                    stage: UpdateStage::Reset,
+                });
+                drv.reset_and_wait(self.reset_timeout)?;
+            }
+            return Err(UpdateError::Downgrade {
+                device_version,
+                image_version,
+            });
+        }
+
+        if self.dry_run {
+            let (flash, flash_changes) = verify_flash(&drv, fw, &mut cb)?;
+            let (id, id_changes) = verify_id(&drv, fw, &mut cb)?;
+            let (config, config_changes) = verify_config(&drv, fw, &mut cb)?;
+            let report = DryRunReport {
+                device_checksum,
+                image_checksum,
+                flash,
+                id,
+                config,
+                flash_changes,
+                id_changes,
+                config_changes,
+            };
+            let device = if was_bootloader {
+                drv.finish()?
+            } else {
+                cb(UpdateEvent::StageStarted {
+                    stage: UpdateStage::Reset,
+                });
+                drv.reset_and_wait(self.reset_timeout)?
+            };
+            return Ok(UpdateOutcome::DryRun(device.info(), report));
+        }
+
+        if device_version == image_version && device_checksum == image_checksum {
+            cb(UpdateEvent::StageStarted {
+                stage: UpdateStage::Reset,
+            });
+            let device = drv.reset_and_wait(self.reset_timeout)?;
+            return Ok(UpdateOutcome::UpToDate(device.info()));
+        }
+
+        for change in drv.diff_cfg(fw)? {
+            cb(UpdateEvent::ConfigMismatch {
+                addr: change.addr,
+                expected: change.new,
+                actual: change.current,
+            });
+        }
+
+        let total = APPLICATION_END - APPLICATION_START;
+        cb(UpdateEvent::StageStarted {
+            stage: UpdateStage::WriteFlash,
+        });
+        drv.write_flash(fw, |addr| {
+            cb(UpdateEvent::BlockWritten { addr });
+            cb(UpdateEvent::Progress {
+                stage: UpdateStage::WriteFlash,
+                done: addr - APPLICATION_START,
+                total,
+                errors: 0,
+            })
+        })?;
+
+        cb(UpdateEvent::StageStarted {
+            stage: UpdateStage::WriteId,
+        });
+        drv.write_id(fw)?;
+
+        let (result, _) = verify_flash(&drv, fw, &mut cb)?;
+        check_verify(UpdateRegion::Flash, result)?;
+
+        let (result, _) = verify_id(&drv, fw, &mut cb)?;
+        check_verify(UpdateRegion::Id, result)?;
+
+        cb(UpdateEvent::StageStarted {
+            stage: UpdateStage::BootCheck,
+        });
+        if let BootDecision::Bootloader(reason) = drv.boot_check()? {
+            return Err(UpdateError::WillNotBoot(reason));
+        }
+
+        cb(UpdateEvent::StageStarted {
+            stage: UpdateStage::Reset,
+        });
+        let device = drv.reset_and_wait(self.reset_timeout)?;
+        Ok(UpdateOutcome::Updated(device.info()))
+    }
+}
+
+// Erased versions can't be compared, so they never block an update
+fn is_downgrade(device_version: FirmwareVersion, image_version: FirmwareVersion) -> bool {
+    !device_version.is_unknown() && !image_version.is_unknown() && image_version < device_version
+}
+
+// Reads the flash once, returning both the verify result and the changed blocks
+fn verify_flash<F: FnMut(UpdateEvent)>(
+    drv: &BootloaderDriver,
+    fw: &FirmwareImage,
+    mut cb: F,
+) -> Result<(VerifyResult, Vec<BlockChange>), DriverError> {
+    cb(UpdateEvent::StageStarted {
+        stage: UpdateStage::VerifyFlash,
+    });
+    let mut result = VerifyResult::Valid;
+    let mut changes = Vec::new();
+    drv.compare_flash(fw, |block_addr, actual, expected| {
+        if let Some(change) = BlockChange::compare(block_addr, actual, expected) {
+            for byte in &change.bytes {
+                result.mark_error(byte.addr);
+                cb(UpdateEvent::VerifyError {
+                    region: UpdateRegion::Flash,
+                    addr: byte.addr,
+                    expected: byte.new,
+                    actual: byte.current,
+                });
+            }
+            changes.push(change);
+        }
+        cb(UpdateEvent::Progress {
+            stage: UpdateStage::VerifyFlash,
+            done: block_addr - APPLICATION_START,
+            total: APPLICATION_END - APPLICATION_START,
+            errors: result.errors(),
+        })
+    })?;
+    Ok((result, changes))
+}
+
+fn verify_id<F: FnMut(UpdateEvent)>(
+    drv: &BootloaderDriver,
+    fw: &FirmwareImage,
+    mut cb: F,
+) -> Result<(VerifyResult, Vec<ByteChange>), DriverError> {
+    cb(UpdateEvent::StageStarted {
+        stage: UpdateStage::VerifyId,
+    });
+    let changes = drv.diff_id(fw)?;
+    Ok((report_byte_changes(UpdateRegion::Id, &changes, cb), changes))
+}
+
+fn verify_config<F: FnMut(UpdateEvent)>(
+    drv: &BootloaderDriver,
+    fw: &FirmwareImage,
+    mut cb: F,
+) -> Result<(VerifyResult, Vec<ByteChange>), DriverError> {
+    cb(UpdateEvent::StageStarted {
+        stage: UpdateStage::VerifyConfig,
+    });
+    let changes = drv.diff_cfg(fw)?;
+    Ok((
+        report_byte_changes(UpdateRegion::Config, &changes, cb),
+        changes,
+    ))
+}
+
+fn report_byte_changes<F: FnMut(UpdateEvent)>(
+    region: UpdateRegion,
+    changes: &[ByteChange],
+    mut cb: F,
+) -> VerifyResult {
+    let mut result = VerifyResult::Valid;
+    for change in changes {
+        result.mark_error(change.addr);
+        cb(UpdateEvent::VerifyError {
+            region,
+            addr: change.addr,
+            expected: change.new,
+            actual: change.current,
+        });
+    }
+    result
+}
+
+fn check_verify(region: UpdateRegion, result: VerifyResult) -> Result<(), UpdateError> {
+    match result {
+        VerifyResult::Valid => Ok(()),
+        VerifyResult::Invalid {
+            errors,
+            first_error_addr,
+        } => Err(UpdateError::VerifyFailed {
+            region,
+            errors,
+            first_error_addr,
+        }),
+    }
+}
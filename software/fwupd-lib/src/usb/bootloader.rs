@@ -2,14 +2,35 @@
 //
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use crate::usb::{CtrlRequestParams, UsbDevice, UsbDeviceMode, VendorCtrlRequest};
+use crate::usb::{
+    CtrlRequestParams, Unclaimed, UsbDevice, UsbDeviceMode, VendorCtrlRequest, LIBUSB_MAX_PAYLOAD,
+};
 use crate::DriverError;
 
+pub(crate) const BOOTLOADER_INTERFACES: &[u8] = &[0];
+
+// Checks that `len` bytes at `addr` fit in `range` and in a single request
+fn check_range(addr: u32, len: usize, range: (u32, u32)) -> Result<(), DriverError> {
+    let (start, end) = range;
+    if len == 0
+        || len > LIBUSB_MAX_PAYLOAD
+        || addr < start
+        || u64::from(addr) + len as u64 > u64::from(end)
+    {
+        Err(DriverError::AddressOutOfRange { addr, len })
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum BootloaderMode {}
 impl UsbDeviceMode for BootloaderMode {}
 
 impl UsbDevice<BootloaderMode> {
+    pub fn release(self) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        self.release_interfaces(BOOTLOADER_INTERFACES)
+    }
     pub fn unlock(&self) -> Result<(), DriverError> {
         self.handle.ctrl_request(
             VendorCtrlRequest::Unlock,
@@ -33,7 +54,12 @@ impl UsbDevice<BootloaderMode> {
         Ok(())
     }
     pub fn read(&self, addr: u32, buffer: &mut [u8]) -> Result<usize, DriverError> {
-        assert!(!buffer.is_empty());
+        if buffer.is_empty() || buffer.len() > LIBUSB_MAX_PAYLOAD {
+            return Err(DriverError::AddressOutOfRange {
+                addr,
+                len: buffer.len(),
+            });
+        }
         self.handle.ctrl_request(
             VendorCtrlRequest::Read,
             CtrlRequestParams::In {
@@ -44,7 +70,7 @@ impl UsbDevice<BootloaderMode> {
         )
     }
     pub fn erase_flash(&self, addr: u32) -> Result<(), DriverError> {
-        assert!(addr < 0x00_8000);
+        check_range(addr, 1, (0x00_0000, 0x00_8000))?;
         self.handle.ctrl_request(
             VendorCtrlRequest::EraseFlash,
             CtrlRequestParams::Out {
@@ -56,9 +82,13 @@ impl UsbDevice<BootloaderMode> {
         Ok(())
     }
     pub fn write_flash(&self, addr: u32, data: &[u8]) -> Result<(), DriverError> {
-        assert!(!data.is_empty());
-        assert!(addr & 0x3f == 0);
-        assert!((addr as usize + data.len()) <= 0x00_8000);
+        if addr & 0x3f != 0 {
+            return Err(DriverError::AddressOutOfRange {
+                addr,
+                len: data.len(),
+            });
+        }
+        check_range(addr, data.len(), (0x00_0000, 0x00_8000))?;
         self.handle.ctrl_request(
             VendorCtrlRequest::WriteFlash,
             CtrlRequestParams::Out {
@@ -70,9 +100,7 @@ impl UsbDevice<BootloaderMode> {
         Ok(())
     }
     pub fn write_cfg(&self, addr: u32, data: &[u8]) -> Result<(), DriverError> {
-        assert!(!data.is_empty() && data.len() <= 16);
-        assert!(addr >= 0x30_0000);
-        assert!((addr as usize + data.len()) <= 0x30_0010);
+        check_range(addr, data.len(), (0x30_0000, 0x30_0010))?;
         self.handle.ctrl_request(
             VendorCtrlRequest::WriteCfg,
             CtrlRequestParams::Out {
@@ -84,9 +112,7 @@ impl UsbDevice<BootloaderMode> {
         Ok(())
     }
     pub fn write_id(&self, addr: u32, data: &[u8]) -> Result<(), DriverError> {
-        assert!(!data.is_empty() && data.len() <= 8);
-        assert!(addr >= 0x20_0000);
-        assert!((addr as usize + data.len()) <= 0x20_0008);
+        check_range(addr, data.len(), (0x20_0000, 0x20_0008))?;
         self.handle.ctrl_request(
             VendorCtrlRequest::WriteId,
             CtrlRequestParams::Out {
@@ -99,12 +125,24 @@ impl UsbDevice<BootloaderMode> {
     }
     pub fn read_byte(&self, addr: u32) -> Result<u8, DriverError> {
         let mut buffer = [0u8];
-        assert_eq!(self.read(addr, &mut buffer)?, 1);
+        let len = self.read(addr, &mut buffer)?;
+        if len != 1 {
+            return Err(DriverError::ShortRead {
+                expected: 1,
+                actual: len,
+            });
+        }
         Ok(buffer[0])
     }
     pub fn read_to_vec(&self, addr: u32, len: usize) -> Result<Vec<u8>, DriverError> {
         let mut buffer = vec![0; len];
-        assert_eq!(self.read(addr, &mut buffer)?, len);
+        let actual = self.read(addr, &mut buffer)?;
+        if actual != len {
+            return Err(DriverError::ShortRead {
+                expected: len,
+                actual,
+            });
+        }
         Ok(buffer)
     }
 }
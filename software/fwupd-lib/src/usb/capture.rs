@@ -0,0 +1,704 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Recording of USB traffic to a text file, and replaying it without hardware.
+//!
+//! Every line of a capture is one transfer:
+//!
+//! ```text
+//! <time in us> <bus>:<address> <event> <result> <data in hex, or ->
+//! ```
+//!
+//! For example `5012 001:007 ctrl-in:c0:41:0000:0000:5 ok:5 4200010100`. Lines starting with `#`
+//! are comments.
+
+use libusb1_sys::constants::*;
+use libusb1_sys::libusb_device_handle;
+use log::warn;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::usb::{
+    BulkCompletion, DeviceDescriptor, Transport, Usb, UsbDeviceId, VirtualBus, VirtualDevice,
+};
+use crate::DriverError;
+
+const CAPTURE_HEADER: &str = "# gb-cartpp USB capture v1";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CaptureEvent {
+    Open {
+        descriptor: DeviceDescriptor,
+    },
+    ControlIn {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        len: usize,
+    },
+    ControlOut {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+    },
+    BulkIn {
+        endpoint: u8,
+        len: usize,
+    },
+    BulkOut {
+        endpoint: u8,
+    },
+    ClaimInterface(u8),
+    ReleaseInterface(u8),
+}
+
+impl fmt::Display for CaptureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CaptureEvent::Open { descriptor } => write!(
+                f,
+                "open:{:04x}:{:04x}:{:04x}:{}:{}",
+                descriptor.vendor_id,
+                descriptor.product_id,
+                descriptor.bcd_device,
+                descriptor.manufacturer,
+                descriptor.product
+            ),
+            CaptureEvent::ControlIn {
+                request_type,
+                request,
+                value,
+                index,
+                len,
+            } => write!(
+                f,
+                "ctrl-in:{:02x}:{:02x}:{:04x}:{:04x}:{}",
+                request_type, request, value, index, len
+            ),
+            CaptureEvent::ControlOut {
+                request_type,
+                request,
+                value,
+                index,
+            } => write!(
+                f,
+                "ctrl-out:{:02x}:{:02x}:{:04x}:{:04x}",
+                request_type, request, value, index
+            ),
+            CaptureEvent::BulkIn { endpoint, len } => write!(f, "bulk-in:{:02x}:{}", endpoint, len),
+            CaptureEvent::BulkOut { endpoint } => write!(f, "bulk-out:{:02x}", endpoint),
+            CaptureEvent::ClaimInterface(interface) => write!(f, "claim:{}", interface),
+            CaptureEvent::ReleaseInterface(interface) => write!(f, "release:{}", interface),
+        }
+    }
+}
+
+impl FromStr for CaptureEvent {
+    type Err = String;
+    fn from_str(s: &str) -> Result<CaptureEvent, String> {
+        let fields = s.split(':').collect::<Vec<_>>();
+        let hex8 = |idx: usize| parse_field(&fields, idx, |s| u8::from_str_radix(s, 16));
+        let hex16 = |idx: usize| parse_field(&fields, idx, |s| u16::from_str_radix(s, 16));
+        let dec8 = |idx: usize| parse_field(&fields, idx, u8::from_str);
+        let len = |idx: usize| parse_field(&fields, idx, usize::from_str);
+        match fields[0] {
+            "open" => Ok(CaptureEvent::Open {
+                descriptor: DeviceDescriptor {
+                    vendor_id: hex16(1)?,
+                    product_id: hex16(2)?,
+                    bcd_device: hex16(3)?,
+                    manufacturer: dec8(4)?,
+                    product: dec8(5)?,
+                },
+            }),
+            "ctrl-in" => Ok(CaptureEvent::ControlIn {
+                request_type: hex8(1)?,
+                request: hex8(2)?,
+                value: hex16(3)?,
+                index: hex16(4)?,
+                len: len(5)?,
+            }),
+            "ctrl-out" => Ok(CaptureEvent::ControlOut {
+                request_type: hex8(1)?,
+                request: hex8(2)?,
+                value: hex16(3)?,
+                index: hex16(4)?,
+            }),
+            "bulk-in" => Ok(CaptureEvent::BulkIn {
+                endpoint: hex8(1)?,
+                len: len(2)?,
+            }),
+            "bulk-out" => Ok(CaptureEvent::BulkOut { endpoint: hex8(1)? }),
+            "claim" => Ok(CaptureEvent::ClaimInterface(dec8(1)?)),
+            "release" => Ok(CaptureEvent::ReleaseInterface(dec8(1)?)),
+            other => Err(format!("unknown event {}", other)),
+        }
+    }
+}
+
+fn parse_field<T, E, F: Fn(&str) -> Result<T, E>>(
+    fields: &[&str],
+    idx: usize,
+    parse: F,
+) -> Result<T, String> {
+    let field = fields.get(idx).ok_or("missing field")?;
+    parse(field).map_err(|_| format!("invalid field {}", field))
+}
+
+/// One recorded transfer.
+///
+/// `data` is the payload sent by the host for OUT transfers and the data received for IN
+/// transfers. `result` is the number of bytes transferred, or the error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CaptureRecord {
+    pub time: Duration,
+    pub device: UsbDeviceId,
+    pub event: CaptureEvent,
+    pub result: Result<usize, DriverError>,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for CaptureRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} ",
+            self.time.as_micros(),
+            self.device,
+            self.event
+        )?;
+        match &self.result {
+            Ok(len) => write!(f, "ok:{}", len)?,
+            Err(err) => write!(f, "err:{}", error_token(err))?,
+        }
+        if self.data.is_empty() {
+            write!(f, " -")
+        } else {
+            f.write_char(' ')?;
+            self.data
+                .iter()
+                .try_for_each(|byte| write!(f, "{:02x}", byte))
+        }
+    }
+}
+
+impl FromStr for CaptureRecord {
+    type Err = String;
+    fn from_str(s: &str) -> Result<CaptureRecord, String> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err("expected 5 fields".to_owned());
+        }
+        let time = fields[0]
+            .parse()
+            .map(Duration::from_micros)
+            .map_err(|_| format!("invalid time {}", fields[0]))?;
+        let device = match fields[1]
+            .split_once(':')
+            .map(|(bus, address)| (bus.parse(), address.parse()))
+        {
+            Some((Ok(bus), Ok(address))) => UsbDeviceId { bus, address },
+            _ => return Err(format!("invalid device {}", fields[1])),
+        };
+        let result = match fields[3].split_once(':') {
+            Some(("ok", len)) => Ok(len.parse().map_err(|_| format!("invalid length {}", len))?),
+            Some(("err", token)) => Err(parse_error_token(token)?),
+            _ => return Err(format!("invalid result {}", fields[3])),
+        };
+        let data = match fields[4] {
+            "-" => Vec::new(),
+            hex => parse_hex(hex).ok_or_else(|| format!("invalid data {}", hex))?,
+        };
+        Ok(CaptureRecord {
+            time,
+            device,
+            event: fields[2].parse()?,
+            result,
+            data,
+        })
+    }
+}
+
+// keep in sync with DriverError
+fn error_token(err: &DriverError) -> String {
+    match err {
+        DriverError::UsbPipe => "pipe".to_owned(),
+        DriverError::UsbTimeout => "timeout".to_owned(),
+        DriverError::UsbIo => "io".to_owned(),
+        DriverError::NoDevice => "nodev".to_owned(),
+        DriverError::UnsupportedUsbOperation => "unsupported".to_owned(),
+        DriverError::ReconnectTimeout => "reconnect".to_owned(),
+        DriverError::Access => "access".to_owned(),
+        DriverError::Busy => "busy".to_owned(),
+        DriverError::Overflow => "overflow".to_owned(),
+        DriverError::NotFound => "notfound".to_owned(),
+        DriverError::Other(code, _) => code.to_string(),
+        // detected above the transport layer, so captures never contain these
+        DriverError::ShortRead { .. }
+        | DriverError::ShortWrite { .. }
+        | DriverError::AddressOutOfRange { .. }
+        | DriverError::WrongMode { .. }
+        | DriverError::CartNotPowered => LIBUSB_ERROR_OTHER.to_string(),
+        // transports don't add context, but keep the underlying error if one gets here
+        DriverError::Context { source, .. } => error_token(source),
+    }
+}
+
+fn parse_error_token(token: &str) -> Result<DriverError, String> {
+    match token {
+        "pipe" => Ok(DriverError::UsbPipe),
+        "timeout" => Ok(DriverError::UsbTimeout),
+        "io" => Ok(DriverError::UsbIo),
+        "nodev" => Ok(DriverError::NoDevice),
+        "unsupported" => Ok(DriverError::UnsupportedUsbOperation),
+        "reconnect" => Ok(DriverError::ReconnectTimeout),
+        "access" => Ok(DriverError::Access),
+        "busy" => Ok(DriverError::Busy),
+        "overflow" => Ok(DriverError::Overflow),
+        "notfound" => Ok(DriverError::NotFound),
+        code => code
+            .parse()
+            .map(|code| DriverError::Other(code, "USB error (replayed from capture)"))
+            .map_err(|_| format!("invalid error {}", code)),
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+/// A sink for captured USB traffic, shared by all devices of a `Usb` context
+pub struct Capture {
+    start: Instant,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Capture")
+            .field("start", &self.start)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Capture {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<Arc<Capture>> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+        writeln!(writer, "{}", CAPTURE_HEADER)?;
+        Ok(Arc::new(Capture {
+            start: Instant::now(),
+            writer: Mutex::new(writer),
+        }))
+    }
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Arc<Capture>> {
+        Capture::new(BufWriter::new(File::create(path)?))
+    }
+    /// Records a bulk transfer that was made asynchronously, without going through the transport
+    pub(crate) fn record_bulk(
+        &self,
+        device: UsbDeviceId,
+        endpoint: u8,
+        len: usize,
+        written: &[u8],
+        result: &Result<BulkCompletion, DriverError>,
+    ) {
+        if endpoint & LIBUSB_ENDPOINT_IN != 0 {
+            let (result, data) = match result {
+                Ok(BulkCompletion::Read(data)) => (Ok(data.len()), &data[..]),
+                Ok(BulkCompletion::Write(len)) => (Ok(*len), &[][..]),
+                Err(err) => (Err(err.clone()), &[][..]),
+            };
+            self.record(
+                device,
+                CaptureEvent::BulkIn { endpoint, len },
+                &result,
+                data,
+            );
+        } else {
+            let result = match result {
+                Ok(BulkCompletion::Write(len)) => Ok(*len),
+                Ok(BulkCompletion::Read(data)) => Ok(data.len()),
+                Err(err) => Err(err.clone()),
+            };
+            self.record(device, CaptureEvent::BulkOut { endpoint }, &result, written);
+        }
+    }
+    fn record(
+        &self,
+        device: UsbDeviceId,
+        event: CaptureEvent,
+        result: &Result<usize, DriverError>,
+        data: &[u8],
+    ) {
+        let record = CaptureRecord {
+            time: self.start.elapsed(),
+            device,
+            event,
+            result: result.clone(),
+            data: data.to_vec(),
+        };
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        // a capture is a debugging aid, so failing to write one must not fail the operation
+        if let Err(err) = writeln!(writer, "{}", record).and_then(|_| writer.flush()) {
+            warn!("Failed to write USB capture: {}", err);
+        }
+    }
+    pub(crate) fn record_open(
+        self: &Arc<Capture>,
+        device: UsbDeviceId,
+        descriptor: DeviceDescriptor,
+        transport: Result<Option<Box<dyn Transport>>, DriverError>,
+    ) -> Result<Option<Box<dyn Transport>>, DriverError> {
+        // the result is the number of opened handles, so a device without a driver is ok:0
+        let result = match &transport {
+            Ok(Some(_)) => Ok(1),
+            Ok(None) => Ok(0),
+            Err(err) => Err(err.clone()),
+        };
+        self.record(device, CaptureEvent::Open { descriptor }, &result, &[]);
+        transport.map(|transport| {
+            transport.map(|inner| {
+                Box::new(RecordingTransport {
+                    inner,
+                    device,
+                    capture: self.clone(),
+                }) as Box<dyn Transport>
+            })
+        })
+    }
+}
+
+/// Records every transfer made through the wrapped transport.
+///
+/// The libusb handle is passed through, so asynchronous bulk transfers bypass the transport. They
+/// are recorded by `BulkQueue` when they complete, which replays them as synchronous transfers.
+#[derive(Debug)]
+struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    device: UsbDeviceId,
+    capture: Arc<Capture>,
+}
+
+impl Transport for RecordingTransport {
+    fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, DriverError> {
+        let result = self
+            .inner
+            .control_in(request_type, request, value, index, data, timeout);
+        let event = CaptureEvent::ControlIn {
+            request_type,
+            request,
+            value,
+            index,
+            len: data.len(),
+        };
+        let received = result.as_ref().map_or(0, |&len| len);
+        self.capture
+            .record(self.device, event, &result, &data[..received]);
+        result
+    }
+    fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: u32,
+    ) -> Result<usize, DriverError> {
+        let result = self
+            .inner
+            .control_out(request_type, request, value, index, data, timeout);
+        let event = CaptureEvent::ControlOut {
+            request_type,
+            request,
+            value,
+            index,
+        };
+        self.capture.record(self.device, event, &result, data);
+        result
+    }
+    fn bulk_in(&self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, DriverError> {
+        let result = self.inner.bulk_in(endpoint, data, timeout);
+        let event = CaptureEvent::BulkIn {
+            endpoint,
+            len: data.len(),
+        };
+        let received = result.as_ref().map_or(0, |&len| len);
+        self.capture
+            .record(self.device, event, &result, &data[..received]);
+        result
+    }
+    fn bulk_out(&self, endpoint: u8, data: &[u8], timeout: u32) -> Result<usize, DriverError> {
+        let result = self.inner.bulk_out(endpoint, data, timeout);
+        let event = CaptureEvent::BulkOut { endpoint };
+        self.capture.record(self.device, event, &result, data);
+        result
+    }
+    fn claim_interface(&self, interface: u8) -> Result<(), DriverError> {
+        let result = self.inner.claim_interface(interface);
+        let event = CaptureEvent::ClaimInterface(interface);
+        self.capture
+            .record(self.device, event, &result.clone().map(|_| 0), &[]);
+        result
+    }
+    fn release_interface(&self, interface: u8) -> Result<(), DriverError> {
+        let result = self.inner.release_interface(interface);
+        let event = CaptureEvent::ReleaseInterface(interface);
+        self.capture
+            .record(self.device, event, &result.clone().map(|_| 0), &[]);
+        result
+    }
+    fn kernel_driver_active(&self, interface: u8) -> Result<bool, DriverError> {
+        self.inner.kernel_driver_active(interface)
+    }
+    fn libusb_handle(&self) -> Option<*mut libusb_device_handle> {
+        self.inner.libusb_handle()
+    }
+    fn capture(&self) -> Option<&Arc<Capture>> {
+        Some(&self.capture)
+    }
+}
+
+#[derive(Debug)]
+struct ReplayState {
+    records: Vec<CaptureRecord>,
+    next: usize,
+    current: Vec<VirtualDevice>,
+    divergence: Option<String>,
+}
+
+impl ReplayState {
+    fn take(
+        &mut self,
+        device: UsbDeviceId,
+        event: &CaptureEvent,
+        data: Option<&[u8]>,
+    ) -> Result<CaptureRecord, DriverError> {
+        if self.divergence.is_some() {
+            return Err(DriverError::Other(LIBUSB_ERROR_OTHER, DIVERGED));
+        }
+        let expected = self.records.get(self.next);
+        let matches = expected.is_some_and(|record| {
+            record.device == device
+                && record.event == *event
+                && data.map_or(true, |data| data == record.data.as_slice())
+        });
+        if !matches {
+            let divergence = match expected {
+                Some(record) => format!(
+                    "record {}: expected {} {}, got {} {}",
+                    self.next + 1,
+                    record.device,
+                    record.event,
+                    device,
+                    event
+                ),
+                None => format!("end of capture: got {} {}", device, event),
+            };
+            warn!("USB traffic diverged from the capture at {}", divergence);
+            self.divergence = Some(divergence);
+            return Err(DriverError::Other(LIBUSB_ERROR_OTHER, DIVERGED));
+        }
+        self.next += 1;
+        Ok(self.records[self.next - 1].clone())
+    }
+}
+
+const DIVERGED: &str = "USB traffic diverged from the capture";
+
+/// A virtual bus that answers transfers from a capture instead of a device.
+///
+/// Transfers must happen in the same order as in the capture. Devices appear as they were opened
+/// in the capture, so re-enumeration after a reset is replayed too.
+#[derive(Debug)]
+pub struct ReplayBus {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+impl ReplayBus {
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Arc<ReplayBus>> {
+        let mut records = Vec::new();
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let record = line.parse().map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid capture line {}: {}", idx + 1, err),
+                )
+            })?;
+            records.push(record);
+        }
+        Ok(Arc::new(ReplayBus {
+            state: Arc::new(Mutex::new(ReplayState {
+                records,
+                next: 0,
+                current: Vec::new(),
+                divergence: None,
+            })),
+        }))
+    }
+    pub fn open_file<P: AsRef<Path>>(path: P) -> io::Result<Arc<ReplayBus>> {
+        ReplayBus::from_reader(io::BufReader::new(File::open(path)?))
+    }
+    pub fn usb(self: &Arc<ReplayBus>) -> Arc<Usb> {
+        Usb::with_virtual_bus(self.clone())
+    }
+    fn state(&self) -> std::sync::MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Describes where the replayed traffic first differed from the capture
+    pub fn divergence(&self) -> Option<String> {
+        self.state().divergence.clone()
+    }
+    /// Number of captured transfers that have not been replayed yet
+    pub fn remaining(&self) -> usize {
+        let state = self.state();
+        state.records.len() - state.next
+    }
+}
+
+impl VirtualBus for ReplayBus {
+    fn devices(&self) -> Vec<VirtualDevice> {
+        let mut state = self.state();
+        match state.records.get(state.next) {
+            Some(CaptureRecord {
+                device,
+                event: CaptureEvent::Open { descriptor },
+                ..
+            }) => {
+                let device = VirtualDevice {
+                    id: *device,
+                    descriptor: *descriptor,
+                };
+                if !state.current.contains(&device) {
+                    // a new device replaces the others, e.g. after a reset
+                    state.current = vec![device];
+                }
+            }
+            None => state.current.clear(),
+            _ => (),
+        }
+        state.current.clone()
+    }
+    fn open(&self, id: UsbDeviceId) -> Result<Option<Box<dyn Transport>>, DriverError> {
+        let mut state = self.state();
+        let descriptor = match state.current.iter().find(|device| device.id == id) {
+            Some(device) => device.descriptor,
+            None => return Err(DriverError::NoDevice),
+        };
+        let record = state.take(id, &CaptureEvent::Open { descriptor }, None)?;
+        if record.result? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(ReplayTransport {
+            state: self.state.clone(),
+            device: id,
+        })))
+    }
+}
+
+#[derive(Debug)]
+struct ReplayTransport {
+    state: Arc<Mutex<ReplayState>>,
+    device: UsbDeviceId,
+}
+
+impl ReplayTransport {
+    fn take(&self, event: CaptureEvent, data: Option<&[u8]>) -> Result<CaptureRecord, DriverError> {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take(self.device, &event, data)
+    }
+    fn take_in(&self, event: CaptureEvent, data: &mut [u8]) -> Result<usize, DriverError> {
+        let record = self.take(event, None)?;
+        let len = record.result?.min(record.data.len()).min(data.len());
+        data[..len].copy_from_slice(&record.data[..len]);
+        Ok(len)
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        _timeout: u32,
+    ) -> Result<usize, DriverError> {
+        let event = CaptureEvent::ControlIn {
+            request_type,
+            request,
+            value,
+            index,
+            len: data.len(),
+        };
+        self.take_in(event, data)
+    }
+    fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        _timeout: u32,
+    ) -> Result<usize, DriverError> {
+        let event = CaptureEvent::ControlOut {
+            request_type,
+            request,
+            value,
+            index,
+        };
+        self.take(event, Some(data))?.result
+    }
+    fn bulk_in(&self, endpoint: u8, data: &mut [u8], _timeout: u32) -> Result<usize, DriverError> {
+        let event = CaptureEvent::BulkIn {
+            endpoint,
+            len: data.len(),
+        };
+        self.take_in(event, data)
+    }
+    fn bulk_out(&self, endpoint: u8, data: &[u8], _timeout: u32) -> Result<usize, DriverError> {
+        self.take(CaptureEvent::BulkOut { endpoint }, Some(data))?
+            .result
+    }
+    fn claim_interface(&self, interface: u8) -> Result<(), DriverError> {
+        self.take(CaptureEvent::ClaimInterface(interface), None)?
+            .result
+            .map(|_| ())
+    }
+    fn release_interface(&self, interface: u8) -> Result<(), DriverError> {
+        self.take(CaptureEvent::ReleaseInterface(interface), None)?
+            .result
+            .map(|_| ())
+    }
+}
@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use libusb1_sys::constants::*;
+
+use crate::usb::transfer::bulk_timeout;
+use crate::usb::{
+    BulkQueue, Unclaimed, UsbDevice, UsbDeviceKind, UsbDeviceMode, EP2_IN, EP2_OUT,
+    LIBUSB_MAX_PAYLOAD,
+};
+use crate::DriverError;
+
+// CDC control interface and the data interface that carries EP2
+pub(crate) const FIRMWARE_INTERFACES: &[u8] = &[0, 1];
+
+const CDC_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const CDC_DTR: u16 = 1 << 0;
+
+#[derive(Debug)]
+pub enum FirmwareMode {}
+impl UsbDeviceMode for FirmwareMode {}
+
+impl UsbDevice<FirmwareMode> {
+    /// Deactivates the command pipe and releases the CDC interfaces
+    pub fn release(self) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        // the firmware resets its command state when DTR is cleared
+        self.set_dtr(false)?;
+        self.release_interfaces(FIRMWARE_INTERFACES)
+    }
+    pub(crate) fn set_dtr(&self, dtr: bool) -> Result<(), DriverError> {
+        let _lock = self.handle.lock();
+        self.handle.transport()?.control_out(
+            LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_CLASS | LIBUSB_RECIPIENT_INTERFACE,
+            CDC_SET_CONTROL_LINE_STATE,
+            if dtr { CDC_DTR } else { 0 },
+            u16::from(FIRMWARE_INTERFACES[0]),
+            &[],
+            1000,
+        )?;
+        Ok(())
+    }
+    /// Asks the firmware to identify itself again, and updates `kind` with the result
+    pub fn identify(&mut self) -> Result<UsbDeviceKind, DriverError> {
+        self.kind = self.handle.identify()?;
+        Ok(self.kind)
+    }
+    /// Reads whatever the firmware has sent on EP2, up to `buffer.len()` bytes
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, DriverError> {
+        let _lock = self.handle.lock();
+        self.handle
+            .transport()?
+            .bulk_in(EP2_IN, buffer, bulk_timeout(buffer.len()))
+    }
+    pub fn write(&self, data: &[u8]) -> Result<usize, DriverError> {
+        let _lock = self.handle.lock();
+        self.handle
+            .transport()?
+            .bulk_out(EP2_OUT, data, bulk_timeout(data.len()))
+    }
+    /// Creates a queue for pipelined bulk transfers on EP2
+    pub fn bulk_queue(&self) -> Result<BulkQueue<'_>, DriverError> {
+        BulkQueue::new(
+            &self.handle,
+            EP2_IN,
+            EP2_OUT,
+            bulk_timeout(LIBUSB_MAX_PAYLOAD),
+        )
+    }
+}
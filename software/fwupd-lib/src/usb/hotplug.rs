@@ -0,0 +1,331 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use libusb1_sys::constants::*;
+use libusb1_sys::*;
+use log::debug;
+use std::collections::{HashSet, VecDeque};
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::usb::{check_libusb, Unclaimed, Usb, UsbDevice, UsbDeviceId, UsbDeviceMode, VENDOR_ID};
+use crate::DriverError;
+
+/// How long to wait for a device to come back after a reset
+pub const RESET_TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub enum DeviceEvent {
+    Arrived(UsbDevice<Unclaimed>),
+    Left(UsbDeviceId),
+}
+
+struct DevicePtr(*mut libusb_device);
+
+// the device is referenced while the pointer is in flight, and libusb devices can be used from
+// any thread
+unsafe impl Send for DevicePtr {}
+
+enum RawEvent {
+    Arrived(DevicePtr),
+    Left(UsbDeviceId),
+}
+
+extern "system" fn hotplug_callback(
+    _ctx: *mut libusb_context,
+    device: *mut libusb_device,
+    event: libusb_hotplug_event,
+    user_data: *mut c_void,
+) -> c_int {
+    let sender = unsafe { &*(user_data as *const Mutex<Sender<RawEvent>>) };
+    let raw_event = if event == LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED {
+        RawEvent::Arrived(DevicePtr(unsafe { libusb_ref_device(device) }))
+    } else {
+        RawEvent::Left(UsbDeviceId::of(device))
+    };
+    let sender = sender.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Err(mpsc::SendError(RawEvent::Arrived(DevicePtr(device)))) = sender.send(raw_event) {
+        unsafe { libusb_unref_device(device) };
+    }
+    0
+}
+
+// An arrived device that couldn't be identified yet, for example because udev hasn't updated its
+// permissions. It is retried until it succeeds, leaves, or the caller's timeout expires
+struct FailedArrival {
+    device: DevicePtr,
+    id: UsbDeviceId,
+    error: DriverError,
+}
+
+impl Drop for FailedArrival {
+    fn drop(&mut self) {
+        unsafe { libusb_unref_device(self.device.0) };
+    }
+}
+
+enum EventSource {
+    Hotplug {
+        handle: libusb_hotplug_callback_handle,
+        receiver: Receiver<RawEvent>,
+        failed: Vec<FailedArrival>,
+        next_retry: Instant,
+        // referenced by the registered callback, so it must stay alive until deregistration
+        _sender: Box<Mutex<Sender<RawEvent>>>,
+    },
+    Polling {
+        next_poll: Instant,
+    },
+}
+
+/// Watches GB-CARTPP devices arriving and leaving.
+///
+/// Uses libusb hotplug notifications if they are supported, and polls the device list otherwise.
+/// Devices that are already connected are reported as arrived when watching starts.
+pub struct DeviceWatcher {
+    usb: Arc<Usb>,
+    source: EventSource,
+    known: HashSet<UsbDeviceId>,
+    events: VecDeque<DeviceEvent>,
+}
+
+impl Usb {
+    pub fn watch(usb: &Arc<Usb>) -> Result<DeviceWatcher, DriverError> {
+        let hotplug_ctx = usb
+            .libusb_ctx()
+            .filter(|_| unsafe { libusb_has_capability(LIBUSB_CAP_HAS_HOTPLUG) } != 0);
+        let source = if let Some(ctx) = hotplug_ctx {
+            usb.start_event_thread()?;
+            let (sender, receiver) = mpsc::channel();
+            let sender = Box::new(Mutex::new(sender));
+            let mut handle = 0;
+            check_libusb(unsafe {
+                libusb_hotplug_register_callback(
+                    ctx,
+                    LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED | LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+                    LIBUSB_HOTPLUG_ENUMERATE,
+                    VENDOR_ID as c_int,
+                    LIBUSB_HOTPLUG_MATCH_ANY,
+                    LIBUSB_HOTPLUG_MATCH_ANY,
+                    hotplug_callback,
+                    &*sender as *const Mutex<Sender<RawEvent>> as *mut c_void,
+                    &mut handle,
+                )
+            })?;
+            EventSource::Hotplug {
+                handle,
+                receiver,
+                failed: Vec::new(),
+                next_retry: Instant::now(),
+                _sender: sender,
+            }
+        } else {
+            debug!("Hotplug is not available, falling back to polling");
+            EventSource::Polling {
+                next_poll: Instant::now(),
+            }
+        };
+        Ok(DeviceWatcher {
+            usb: usb.clone(),
+            source,
+            known: HashSet::new(),
+            events: VecDeque::new(),
+        })
+    }
+}
+
+impl DeviceWatcher {
+    pub fn is_hotplug(&self) -> bool {
+        matches!(self.source, EventSource::Hotplug { .. })
+    }
+    /// Waits for the next event, or returns `None` if nothing happened before the timeout.
+    ///
+    /// Arrived devices that fail to be identified are retried until the timeout, and the error
+    /// is only returned if one of them still fails then.
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>, DriverError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                if let EventSource::Hotplug { failed, .. } = &self.source {
+                    if let Some(arrival) = failed.last() {
+                        return Err(arrival.error.clone());
+                    }
+                }
+                return Ok(None);
+            }
+            match &mut self.source {
+                EventSource::Hotplug {
+                    receiver,
+                    failed,
+                    next_retry,
+                    ..
+                } => {
+                    if !failed.is_empty() && now >= *next_retry {
+                        *next_retry = now + POLL_INTERVAL;
+                        for arrival in mem::take(failed) {
+                            let device = arrival.device.0;
+                            identify_arrival(
+                                &self.usb,
+                                device,
+                                &mut self.known,
+                                &mut self.events,
+                                failed,
+                            );
+                        }
+                        continue;
+                    }
+                    let wait_until = if failed.is_empty() {
+                        deadline
+                    } else {
+                        (*next_retry).min(deadline)
+                    };
+                    match receiver.recv_timeout(wait_until - now) {
+                        Ok(RawEvent::Arrived(DevicePtr(device))) => {
+                            if failed.is_empty() {
+                                *next_retry = now + POLL_INTERVAL;
+                            }
+                            identify_arrival(
+                                &self.usb,
+                                device,
+                                &mut self.known,
+                                &mut self.events,
+                                failed,
+                            );
+                            unsafe { libusb_unref_device(device) };
+                        }
+                        Ok(RawEvent::Left(id)) => {
+                            failed.retain(|arrival| arrival.id != id);
+                            if self.known.remove(&id) {
+                                self.events.push_back(DeviceEvent::Left(id));
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => (),
+                        Err(RecvTimeoutError::Disconnected) => return Err(DriverError::NoDevice),
+                    }
+                }
+                EventSource::Polling { next_poll } => {
+                    if now < *next_poll {
+                        thread::sleep((*next_poll).min(deadline) - now);
+                        continue;
+                    }
+                    *next_poll = now + POLL_INTERVAL;
+                    let devices = Usb::list_devices(&self.usb)?;
+                    let present = devices
+                        .iter()
+                        .map(|device| device.usb_id())
+                        .collect::<HashSet<_>>();
+                    for &id in self.known.difference(&present) {
+                        self.events.push_back(DeviceEvent::Left(id));
+                    }
+                    for device in devices {
+                        if !self.known.contains(&device.usb_id()) {
+                            self.events.push_back(DeviceEvent::Arrived(device));
+                        }
+                    }
+                    self.known = present;
+                }
+            }
+        }
+    }
+    /// Waits until a device matching the predicate arrives
+    pub fn wait_for<F: FnMut(&UsbDevice<Unclaimed>) -> bool>(
+        &mut self,
+        timeout: Duration,
+        mut f: F,
+    ) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.next_event(remaining)? {
+                Some(DeviceEvent::Arrived(device)) if f(&device) => return Ok(device),
+                Some(_) => (),
+                None => return Err(DriverError::ReconnectTimeout),
+            }
+        }
+    }
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        if let EventSource::Hotplug {
+            handle, receiver, ..
+        } = &self.source
+        {
+            if let Some(ctx) = self.usb.libusb_ctx() {
+                unsafe { libusb_hotplug_deregister_callback(ctx, *handle) };
+            }
+            for event in receiver.try_iter() {
+                if let RawEvent::Arrived(DevicePtr(device)) = event {
+                    unsafe { libusb_unref_device(device) };
+                }
+            }
+        }
+    }
+}
+
+impl<T: UsbDeviceMode> UsbDevice<T> {
+    /// Resets the device into the bootloader and waits until it is detected again
+    pub fn enter_bootloader_and_wait(
+        self,
+        timeout: Duration,
+    ) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        let mut watcher = Usb::watch(&self.handle.usb)?;
+        let id = self.usb_id();
+        ignore_disconnect(self.enter_bootloader())?;
+        watcher.wait_for(timeout, |device| {
+            device.usb_id() != id && device.kind.is_bootloader()
+        })
+    }
+    /// Resets the device into the main firmware and waits until it is detected again
+    pub fn reset_and_wait(self, timeout: Duration) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        let mut watcher = Usb::watch(&self.handle.usb)?;
+        let id = self.usb_id();
+        ignore_disconnect(self.reset())?;
+        watcher.wait_for(timeout, |device| {
+            device.usb_id() != id && device.kind.is_firmware()
+        })
+    }
+}
+
+fn identify_arrival(
+    usb: &Arc<Usb>,
+    device: *mut libusb_device,
+    known: &mut HashSet<UsbDeviceId>,
+    events: &mut VecDeque<DeviceEvent>,
+    failed: &mut Vec<FailedArrival>,
+) {
+    match Usb::identify_device(usb, device) {
+        Ok(Some(device)) => {
+            known.insert(device.usb_id());
+            events.push_back(DeviceEvent::Arrived(device));
+        }
+        Ok(None) => (),
+        Err(error) => {
+            debug!("Failed to identify arrived device, retrying: {}", error);
+            failed.push(FailedArrival {
+                device: DevicePtr(unsafe { libusb_ref_device(device) }),
+                id: UsbDeviceId::of(device),
+                error,
+            });
+        }
+    }
+}
+
+// The device may disconnect before the reset request has been completed
+fn ignore_disconnect(result: Result<(), DriverError>) -> Result<(), DriverError> {
+    match result {
+        Err(err) if err.is_disconnect() => Ok(()),
+        result => result,
+    }
+}
@@ -4,50 +4,163 @@
 
 use libusb1_sys::constants::*;
 use libusb1_sys::*;
+use log::{debug, warn};
 use std::char;
 use std::ffi::CStr;
 use std::fmt;
 use std::marker::PhantomData;
 use std::mem;
 use std::ptr;
-use std::rc::Rc;
 use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 mod bootloader;
+mod capture;
+mod firmware;
+mod hotplug;
+mod probe;
+mod transfer;
+mod transport;
 
 pub use crate::usb::bootloader::BootloaderMode;
-use crate::{DriverError, FirmwareVersion};
+use crate::usb::bootloader::BOOTLOADER_INTERFACES;
+pub use crate::usb::capture::{Capture, CaptureEvent, CaptureRecord, ReplayBus};
+pub use crate::usb::firmware::FirmwareMode;
+use crate::usb::firmware::FIRMWARE_INTERFACES;
+pub use crate::usb::hotplug::{DeviceEvent, DeviceWatcher, RESET_TIMEOUT};
+pub use crate::usb::probe::DeviceProbe;
+use crate::usb::transfer::EventThread;
+pub use crate::usb::transfer::{BulkCompletion, BulkQueue, EP2_IN, EP2_OUT, EP2_PACKET_SIZE};
+use crate::usb::transport::LibusbTransport;
+pub use crate::usb::transport::{DeviceDescriptor, Transport, VirtualBus, VirtualDevice};
+use crate::{
+    boot::{RESET_MAGIC_APPLICATION, RESET_MAGIC_BOOTLOADER},
+    DriverError, ErrorContext, FirmwareVersion,
+};
+
+pub const VENDOR_ID: u16 = 0x16c0;
+pub const BOOTLOADER_PRODUCT_ID: u16 = 0x05dc;
+pub const FIRMWARE_PRODUCT_ID: u16 = 0x05e1;
+
+#[derive(Debug)]
+enum Backend {
+    Libusb(*mut libusb_context),
+    Virtual(Arc<dyn VirtualBus>),
+}
 
 #[derive(Debug)]
 pub struct Usb {
-    ctx: *mut libusb_context,
+    backend: Backend,
+    event_thread: Mutex<Option<EventThread>>,
+    capture: Mutex<Option<Arc<Capture>>>,
 }
 
 impl Drop for Usb {
     fn drop(&mut self) {
-        unsafe { libusb_exit(self.ctx) };
+        let event_thread = self
+            .event_thread
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(event_thread) = event_thread {
+            event_thread.stop();
+        }
+        if let Backend::Libusb(ctx) = self.backend {
+            unsafe { libusb_exit(ctx) };
+        }
+    }
+}
+
+// libusb contexts are safe to use from multiple threads. The raw pointer is only handed to libusb
+// functions, and libusb synchronizes access to the context internally
+unsafe impl Send for Usb {}
+unsafe impl Sync for Usb {}
+
+/// Identifies a device by its bus and address, which change whenever the device re-enumerates
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct UsbDeviceId {
+    pub bus: u8,
+    pub address: u8,
+}
+
+impl UsbDeviceId {
+    fn of(device: *mut libusb_device) -> UsbDeviceId {
+        UsbDeviceId {
+            bus: unsafe { libusb_get_bus_number(device) },
+            address: unsafe { libusb_get_device_address(device) },
+        }
+    }
+}
+
+impl fmt::Display for UsbDeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03}:{:03}", self.bus, self.address)
+    }
+}
+
+/// Identifies the physical port a device is plugged into, which stays the same across
+/// re-enumeration
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct UsbPortPath {
+    pub bus: u8,
+    /// Hub port numbers from the root hub to the device
+    pub ports: Vec<u8>,
+}
+
+impl UsbPortPath {
+    fn of(device: *mut libusb_device) -> Result<UsbPortPath, DriverError> {
+        let mut ports = [0; 7];
+        let len = check_libusb(unsafe {
+            libusb_get_port_numbers(device, ports.as_mut_ptr(), ports.len() as i32)
+        })?;
+        Ok(UsbPortPath {
+            bus: unsafe { libusb_get_bus_number(device) },
+            ports: ports[..len].to_vec(),
+        })
+    }
+}
+
+impl fmt::Display for UsbPortPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // same format as Linux sysfs, e.g. 1-2.4
+        write!(f, "{}", self.bus)?;
+        for (idx, port) in self.ports.iter().enumerate() {
+            write!(f, "{}{}", if idx == 0 { '-' } else { '.' }, port)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug)]
 pub struct UsbDeviceHandle {
-    raw: *mut libusb_device_handle,
-    _port_numbers: [u8; 7],
-    pub(crate) address: u8,
+    // None if the device could not be opened because no driver is installed
+    transport: Option<Box<dyn Transport>>,
+    pub(crate) port_path: UsbPortPath,
+    pub(crate) id: UsbDeviceId,
     pub(crate) version: (u8, u8),
-    _usb: Rc<Usb>,
+    // serializes requests made through one handle so that e.g. a multi-stage control request is
+    // never interleaved with another one
+    lock: Mutex<()>,
+    usb: Arc<Usb>,
 }
 
-impl Drop for UsbDeviceHandle {
-    fn drop(&mut self) {
-        if !self.raw.is_null() {
-            unsafe { libusb_close(self.raw) }
-        }
-    }
+#[allow(dead_code)]
+fn assert_thread_safe() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Arc<Usb>>();
+    assert_send_sync::<UsbDevice<Unclaimed>>();
+    assert_send_sync::<UsbDevice<BootloaderMode>>();
+    assert_send_sync::<UsbDevice<FirmwareMode>>();
+    assert_send_sync::<crate::BootloaderDriver>();
 }
 
 pub(crate) const LIBUSB_MAX_PAYLOAD: usize = 4096;
 
+fn ctrl_timeout(len: usize) -> u32 {
+    1000 + len as u32 / 16
+}
+
 pub(crate) fn check_libusb(ret: i32) -> Result<usize, DriverError> {
     match ret {
         LIBUSB_SUCCESS => Ok(ret as usize),
@@ -57,6 +170,10 @@ pub(crate) fn check_libusb(ret: i32) -> Result<usize, DriverError> {
         LIBUSB_ERROR_NOT_SUPPORTED => Err(DriverError::UnsupportedUsbOperation),
         LIBUSB_ERROR_NO_DEVICE => Err(DriverError::NoDevice),
         LIBUSB_ERROR_IO => Err(DriverError::UsbIo),
+        LIBUSB_ERROR_ACCESS => Err(DriverError::Access),
+        LIBUSB_ERROR_BUSY => Err(DriverError::Busy),
+        LIBUSB_ERROR_OVERFLOW => Err(DriverError::Overflow),
+        LIBUSB_ERROR_NOT_FOUND => Err(DriverError::NotFound),
         _ => Err(DriverError::Other(ret, unsafe {
             CStr::from_ptr(libusb_strerror(ret))
                 .to_str()
@@ -71,109 +188,199 @@ pub fn list_devices() -> Result<Vec<UsbDevice<Unclaimed>>, DriverError> {
 }
 
 impl Usb {
-    pub fn init() -> Result<Rc<Usb>, DriverError> {
+    pub fn init() -> Result<Arc<Usb>, DriverError> {
         let mut ctx: *mut libusb_context = ptr::null_mut();
         check_libusb(unsafe { libusb_init(&mut ctx) })?;
-        Ok(Rc::new(Usb { ctx }))
-    }
-    pub fn list_devices(usb: &Rc<Usb>) -> Result<Vec<UsbDevice<Unclaimed>>, DriverError> {
-        let mut raw: *const *mut libusb_device = ptr::null_mut();
-        let count = check_libusb(unsafe { libusb_get_device_list(usb.ctx, &mut raw) as i32 })?;
-        let list = unsafe { slice::from_raw_parts(raw, count) };
-        let result = Self::detect_devices(usb, list);
-        unsafe { libusb_free_device_list(raw, 1) };
-        result
-    }
-    fn open(
-        usb: &Rc<Usb>,
-        device: *mut libusb_device,
-        descriptor: &libusb_device_descriptor,
-    ) -> Result<UsbDeviceHandle, DriverError> {
-        let mut port_numbers = [0; 7];
-        check_libusb(unsafe {
-            libusb_get_port_numbers(device, port_numbers.as_mut_ptr(), port_numbers.len() as i32)
-        })?;
-        let address = unsafe { libusb_get_device_address(device) };
-        let mut handle = ptr::null_mut();
-        match check_libusb(unsafe { libusb_open(device, &mut handle) }) {
-            Ok(_) => (),
-            // Windows returns this if a driver isn't yet installed
-            Err(DriverError::UnsupportedUsbOperation) => (),
-            Err(err) => return Err(err),
+        Ok(Arc::new(Usb {
+            backend: Backend::Libusb(ctx),
+            event_thread: Mutex::new(None),
+            capture: Mutex::new(None),
+        }))
+    }
+    /// Creates a context whose devices come from `bus` instead of libusb
+    pub fn with_virtual_bus(bus: Arc<dyn VirtualBus>) -> Arc<Usb> {
+        Arc::new(Usb {
+            backend: Backend::Virtual(bus),
+            event_thread: Mutex::new(None),
+            capture: Mutex::new(None),
+        })
+    }
+    /// Records all traffic of devices opened from now on to `capture`
+    pub fn record_to(&self, capture: Arc<Capture>) {
+        *self.capture.lock().unwrap_or_else(PoisonError::into_inner) = Some(capture);
+    }
+    fn capture(&self) -> Option<Arc<Capture>> {
+        self.capture
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+    pub(crate) fn libusb_ctx(&self) -> Option<*mut libusb_context> {
+        match self.backend {
+            Backend::Libusb(ctx) => Some(ctx),
+            Backend::Virtual(_) => None,
         }
-        let [ver_l, ver_h] = descriptor.bcdDevice.to_le_bytes();
-        Ok(UsbDeviceHandle {
-            raw: handle,
-            _port_numbers: port_numbers,
-            address,
+    }
+    pub(crate) fn start_event_thread(&self) -> Result<(), DriverError> {
+        let mut event_thread = self
+            .event_thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let (None, Some(ctx)) = (&*event_thread, self.libusb_ctx()) {
+            *event_thread = Some(EventThread::spawn(ctx)?);
+        }
+        Ok(())
+    }
+    pub fn list_devices(usb: &Arc<Usb>) -> Result<Vec<UsbDevice<Unclaimed>>, DriverError> {
+        match &usb.backend {
+            Backend::Libusb(ctx) => {
+                let mut raw: *const *mut libusb_device = ptr::null_mut();
+                let count = check_libusb(unsafe { libusb_get_device_list(*ctx, &mut raw) as i32 })?;
+                let list = unsafe { slice::from_raw_parts(raw, count) };
+                let result =
+                    Self::detect_devices(list.iter().map(|&device| {
+                        (UsbDeviceId::of(device), Self::identify_device(usb, device))
+                    }));
+                unsafe { libusb_free_device_list(raw, 1) };
+                result
+            }
+            Backend::Virtual(bus) => {
+                Self::detect_devices(bus.devices().into_iter().map(|device| {
+                    let port_path = UsbPortPath {
+                        bus: device.id.bus,
+                        ports: Vec::new(),
+                    };
+                    let result =
+                        Self::identify(usb, device.id, port_path, &device.descriptor, || {
+                            bus.open(device.id)
+                        });
+                    (device.id, result)
+                }))
+            }
+        }
+    }
+    fn identify<F: FnOnce() -> Result<Option<Box<dyn Transport>>, DriverError>>(
+        usb: &Arc<Usb>,
+        id: UsbDeviceId,
+        port_path: UsbPortPath,
+        descriptor: &DeviceDescriptor,
+        open: F,
+    ) -> Result<Option<UsbDevice<Unclaimed>>, DriverError> {
+        if descriptor.vendor_id != VENDOR_ID
+            || (descriptor.product_id != BOOTLOADER_PRODUCT_ID
+                && descriptor.product_id != FIRMWARE_PRODUCT_ID)
+            || descriptor.manufacturer == 0
+            || descriptor.product == 0
+        {
+            return Ok(None);
+        }
+        let [ver_l, ver_h] = descriptor.bcd_device.to_le_bytes();
+        let transport = match usb.capture() {
+            Some(capture) => capture.record_open(id, *descriptor, open()),
+            None => open(),
+        };
+        let handle = UsbDeviceHandle {
+            transport: transport?,
+            port_path,
+            id,
             version: (ver_h, ver_l),
-            _usb: usb.clone(),
-        })
+            lock: Mutex::new(()),
+            usb: usb.clone(),
+        };
+        if handle.transport.is_none() {
+            Ok(Some(UsbDevice::from_handle(
+                handle,
+                UsbDeviceKind::Unusable,
+            )))
+        } else {
+            let vendor = handle.get_string_descriptor(descriptor.manufacturer)?;
+            let product = handle.get_string_descriptor(descriptor.product)?;
+            let kind = match (vendor.as_str(), product.as_str()) {
+                ("gekkio.fi", "GB-CARTPP-XC") => handle.identify()?,
+                _ => return Ok(None),
+            };
+            Ok(Some(UsbDevice::from_handle(handle, kind)))
+        }
     }
     fn identify_device(
-        usb: &Rc<Usb>,
+        usb: &Arc<Usb>,
         device: *mut libusb_device,
     ) -> Result<Option<UsbDevice<Unclaimed>>, DriverError> {
         let mut descriptor = unsafe { mem::zeroed() };
         check_libusb(unsafe { libusb_get_device_descriptor(device, &mut descriptor) })?;
-        if descriptor.idVendor != 0x16c0
-            || (descriptor.idProduct != 0x05dc && descriptor.idProduct != 0x05e1)
-            || descriptor.iManufacturer == 0
-            || descriptor.iProduct == 0
-        {
-            Ok(None)
-        } else {
-            let handle = Self::open(usb, device, &descriptor)?;
-            if handle.raw.is_null() {
-                Ok(Some(UsbDevice::from_handle(
-                    handle,
-                    UsbDeviceKind::Unusable,
-                )))
-            } else {
-                let vendor = handle.get_string_descriptor(descriptor.iManufacturer)?;
-                let product = handle.get_string_descriptor(descriptor.iProduct)?;
-                let kind = match (vendor.as_str(), product.as_str()) {
-                    ("gekkio.fi", "GB-CARTPP-XC") => handle.identify()?,
-                    _ => return Ok(None),
-                };
-                Ok(Some(UsbDevice::from_handle(handle, kind)))
-            }
-        }
+        Self::identify(
+            usb,
+            UsbDeviceId::of(device),
+            UsbPortPath::of(device)?,
+            &DeviceDescriptor::from(&descriptor),
+            || {
+                let mut handle = ptr::null_mut();
+                match check_libusb(unsafe { libusb_open(device, &mut handle) }) {
+                    Ok(_) => Ok(Some(
+                        Box::new(LibusbTransport::new(handle)) as Box<dyn Transport>
+                    )),
+                    // Windows returns this if a driver isn't yet installed
+                    Err(DriverError::UnsupportedUsbOperation) => Ok(None),
+                    Err(err) => Err(err),
+                }
+            },
+        )
     }
-    fn detect_devices(
-        usb: &Rc<Usb>,
-        device_list: &[*mut libusb_device],
+    // A device that fails to open doesn't hide the others. The first error is returned only if
+    // no device could be detected at all
+    fn detect_devices<
+        I: Iterator<
+            Item = (
+                UsbDeviceId,
+                Result<Option<UsbDevice<Unclaimed>>, DriverError>,
+            ),
+        >,
+    >(
+        results: I,
     ) -> Result<Vec<UsbDevice<Unclaimed>>, DriverError> {
         let mut devices = Vec::new();
-        for &device in device_list {
-            match Self::identify_device(usb, device) {
+        let mut error = None;
+        for (id, result) in results {
+            match result {
                 Ok(Some(device)) => devices.push(device),
                 Ok(None) => (),
-                Err(DriverError::NoDevice)
-                | Err(DriverError::UsbIo)
-                | Err(DriverError::UsbPipe) => (),
-                Err(err) => return Err(err),
+                Err(err) if err.is_disconnect() => {
+                    debug!("USB device {} disconnected during detection", id)
+                }
+                Err(err) => {
+                    warn!("Failed to open USB device {}: {}", id, err);
+                    error.get_or_insert(err);
+                }
             }
         }
-        Ok(devices)
+        match error {
+            Some(err) if devices.is_empty() => Err(err),
+            _ => Ok(devices),
+        }
     }
 }
 
 impl UsbDeviceHandle {
+    pub(crate) fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn transport(&self) -> Result<&dyn Transport, DriverError> {
+        self.transport
+            .as_deref()
+            .ok_or(DriverError::UnsupportedUsbOperation)
+    }
     fn get_string_descriptor(&self, index: u8) -> Result<String, DriverError> {
-        assert!(!self.raw.is_null());
         let mut buffer = [0u8; 64];
-        let len = unsafe {
-            libusb_get_string_descriptor(
-                self.raw,
-                index,
-                0x0000,
-                buffer.as_mut_ptr(),
-                buffer.len() as i32,
-            )
-        };
-        check_libusb(len)?;
-        let code_points = buffer[..(len as usize)]
+        let _lock = self.lock();
+        let len = self.transport()?.control_in(
+            LIBUSB_ENDPOINT_IN,
+            LIBUSB_REQUEST_GET_DESCRIPTOR,
+            (u16::from(LIBUSB_DT_STRING) << 8) | u16::from(index),
+            0x0000,
+            &mut buffer,
+            1000,
+        )?;
+        let code_points = buffer[..len]
             .chunks_exact(2)
             .skip(1)
             .filter_map(|chunk| match *chunk {
@@ -189,36 +396,63 @@ impl UsbDeviceHandle {
         req: VendorCtrlRequest,
         params: CtrlRequestParams,
     ) -> Result<usize, DriverError> {
-        let (req_type, value, index, data) = match params {
-            CtrlRequestParams::Out { value, index, data } => (
-                LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
-                value,
-                index,
-                data.map(|data| (data.as_ptr() as *mut u8, data.len())),
-            ),
-            CtrlRequestParams::In { value, index, data } => (
-                LIBUSB_ENDPOINT_IN | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
-                value,
-                index,
-                data.map(|data| (data.as_mut_ptr(), data.len())),
-            ),
+        let addr = match params {
+            CtrlRequestParams::Out { value, index, .. }
+            | CtrlRequestParams::In { value, index, .. }
+                if req.has_addr() =>
+            {
+                Some(u32::from(index) << 16 | u32::from(value))
+            }
+            _ => None,
         };
-        let (data_ptr, data_len) = data.unwrap_or((ptr::null_mut(), 0));
-        assert!(data_len <= LIBUSB_MAX_PAYLOAD);
-        let timeout = 1000 + data_len as u32 / 16;
-        check_libusb(unsafe {
-            libusb_control_transfer(
-                self.raw,
-                req_type,
-                req as u8,
-                value,
-                index,
-                data_ptr,
-                data_len as u16,
-                timeout,
-            )
+        self.ctrl_transfer(req, params).map_err(|err| {
+            err.with_context(ErrorContext {
+                request: Some(req),
+                addr,
+                device: Some(self.id),
+                port_path: Some(self.port_path.clone()),
+            })
         })
     }
+    fn ctrl_transfer(
+        &self,
+        req: VendorCtrlRequest,
+        params: CtrlRequestParams,
+    ) -> Result<usize, DriverError> {
+        let transport = self.transport()?;
+        match params {
+            CtrlRequestParams::Out { value, index, data } => {
+                let data = data.unwrap_or_default();
+                if data.len() > LIBUSB_MAX_PAYLOAD {
+                    return Err(DriverError::Overflow);
+                }
+                let _lock = self.lock();
+                transport.control_out(
+                    LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
+                    req as u8,
+                    value,
+                    index,
+                    data,
+                    ctrl_timeout(data.len()),
+                )
+            }
+            CtrlRequestParams::In { value, index, data } => {
+                let data = data.unwrap_or_default();
+                if data.len() > LIBUSB_MAX_PAYLOAD {
+                    return Err(DriverError::Overflow);
+                }
+                let _lock = self.lock();
+                transport.control_in(
+                    LIBUSB_ENDPOINT_IN | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
+                    req as u8,
+                    value,
+                    index,
+                    data,
+                    ctrl_timeout(data.len()),
+                )
+            }
+        }
+    }
     fn identify(&self) -> Result<UsbDeviceKind, DriverError> {
         let mut buffer = [0x00; 5];
         self.ctrl_request(
@@ -258,6 +492,8 @@ pub trait UsbDeviceMode {}
 impl UsbDeviceMode for Unclaimed {}
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum UsbDeviceKind {
     Bootloader {
         bl_version: FirmwareVersion,
@@ -288,7 +524,7 @@ pub struct UsbDevice<T: UsbDeviceMode> {
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-enum VendorCtrlRequest {
+pub enum VendorCtrlRequest {
     Reset = 0x40,
     Identify = 0x41,
     Unlock = 0x42,
@@ -300,6 +536,20 @@ enum VendorCtrlRequest {
     WriteId = 0x48,
 }
 
+impl VendorCtrlRequest {
+    // requests that pass a memory address in wValue and wIndex
+    fn has_addr(self) -> bool {
+        matches!(
+            self,
+            VendorCtrlRequest::Read
+                | VendorCtrlRequest::EraseFlash
+                | VendorCtrlRequest::WriteFlash
+                | VendorCtrlRequest::WriteCfg
+                | VendorCtrlRequest::WriteId
+        )
+    }
+}
+
 #[derive(Debug)]
 enum CtrlRequestParams<'a> {
     Out {
@@ -319,10 +569,28 @@ impl<T: UsbDeviceMode> UsbDevice<T> {
         self.handle.version
     }
     pub fn usb_address(&self) -> u8 {
-        self.handle.address
+        self.handle.id.address
     }
-    pub fn release(self) -> Result<UsbDevice<Unclaimed>, DriverError> {
-        check_libusb(unsafe { libusb_release_interface(self.handle.raw, 0) })?;
+    pub fn usb_id(&self) -> UsbDeviceId {
+        self.handle.id
+    }
+    pub fn port_path(&self) -> &UsbPortPath {
+        &self.handle.port_path
+    }
+    pub fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            id: self.usb_id(),
+            kind: self.kind,
+        }
+    }
+    fn release_interfaces(self, interfaces: &[u8]) -> Result<UsbDevice<Unclaimed>, DriverError> {
+        {
+            let _lock = self.handle.lock();
+            let transport = self.handle.transport()?;
+            for &interface in interfaces.iter().rev() {
+                transport.release_interface(interface)?;
+            }
+        }
         Ok(UsbDevice::<Unclaimed> {
             handle: self.handle,
             kind: self.kind,
@@ -333,7 +601,7 @@ impl<T: UsbDeviceMode> UsbDevice<T> {
         self.handle.ctrl_request(
             VendorCtrlRequest::Reset,
             CtrlRequestParams::Out {
-                value: 0x42,
+                value: RESET_MAGIC_BOOTLOADER as u16,
                 index: 0,
                 data: None,
             },
@@ -344,7 +612,7 @@ impl<T: UsbDeviceMode> UsbDevice<T> {
         self.handle.ctrl_request(
             VendorCtrlRequest::Reset,
             CtrlRequestParams::Out {
-                value: 0x99,
+                value: RESET_MAGIC_APPLICATION as u16,
                 index: 0,
                 data: None,
             },
@@ -361,26 +629,63 @@ impl UsbDevice<Unclaimed> {
             _mode: PhantomData,
         }
     }
-    fn claim_interface(&self) -> Result<(), DriverError> {
-        if unsafe { libusb_has_capability(LIBUSB_CAP_SUPPORTS_DETACH_KERNEL_DRIVER) } != 0 {
-            check_libusb(unsafe {
-                libusb_set_auto_detach_kernel_driver(self.handle.raw, true as i32)
-            })?;
+    fn claim_interfaces(&self, interfaces: &[u8]) -> Result<(), DriverError> {
+        let _lock = self.handle.lock();
+        let transport = self.handle.transport()?;
+        for (idx, &interface) in interfaces.iter().enumerate() {
+            if let Err(err) = transport.claim_interface(interface) {
+                for &claimed in interfaces[..idx].iter().rev() {
+                    let _ = transport.release_interface(claimed);
+                }
+                return Err(err);
+            }
         }
-        check_libusb(unsafe { libusb_claim_interface(self.handle.raw, 0) })?;
         Ok(())
     }
     pub fn claim_bootloader(self) -> Result<UsbDevice<BootloaderMode>, DriverError> {
-        self.claim_interface()?;
+        self.claim_interfaces(BOOTLOADER_INTERFACES)?;
         Ok(UsbDevice::<BootloaderMode> {
             handle: self.handle,
             kind: self.kind,
             _mode: PhantomData,
         })
     }
+    /// Claims the CDC interfaces of the main firmware and activates its command pipe on EP2.
+    ///
+    /// Kernel drivers (e.g. cdc_acm) are detached while the interfaces are claimed.
+    pub fn claim_firmware(self) -> Result<UsbDevice<FirmwareMode>, DriverError> {
+        if !self.kind.is_firmware() {
+            return Err(DriverError::WrongMode { kind: self.kind });
+        }
+        self.claim_interfaces(FIRMWARE_INTERFACES)?;
+        let device = UsbDevice::<FirmwareMode> {
+            handle: self.handle,
+            kind: self.kind,
+            _mode: PhantomData,
+        };
+        if let Err(err) = device.set_dtr(true) {
+            let _ = device.release_interfaces(FIRMWARE_INTERFACES);
+            return Err(err);
+        }
+        Ok(device)
+    }
 }
 
 impl fmt::Display for UsbDevice<Unclaimed> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.info().fmt(f)
+    }
+}
+
+/// Identity and kind of a detected device, without the open handle
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DeviceInfo {
+    pub id: UsbDeviceId,
+    pub kind: UsbDeviceKind,
+}
+
+impl fmt::Display for DeviceInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.kind {
             UsbDeviceKind::Bootloader {
@@ -389,20 +694,17 @@ impl fmt::Display for UsbDevice<Unclaimed> {
             } => write!(
                 f,
                 "USB device {:03}: GB-CARTPP-XC v{} (bootloader v{})",
-                self.usb_address(),
-                fw_version,
-                bl_version,
+                self.id.address, fw_version, bl_version,
             ),
             UsbDeviceKind::Firmware { fw_version, .. } => write!(
                 f,
                 "USB device {:03}: GB-CARTPP-XC v{}",
-                self.usb_address(),
-                fw_version,
+                self.id.address, fw_version,
             ),
             UsbDeviceKind::Unusable => write!(
                 f,
                 "USB device {:03}: GB-CARTPP-XC? (no driver installed)",
-                self.usb_address()
+                self.id.address
             ),
         }
     }
@@ -0,0 +1,122 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use libusb1_sys::*;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use crate::usb::transport::LibusbTransport;
+use crate::usb::{
+    check_libusb, Backend, DeviceDescriptor, Transport, Usb, UsbDeviceId, BOOTLOADER_PRODUCT_ID,
+    FIRMWARE_PRODUCT_ID, VENDOR_ID,
+};
+use crate::DriverError;
+
+/// What happened when opening a device that looks like a GB-CARTPP by its USB ids.
+///
+/// Unlike `Usb::list_devices`, probing reports devices that can't be opened instead of skipping
+/// them, so permission and driver problems can be diagnosed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceProbe {
+    pub id: UsbDeviceId,
+    pub product_id: u16,
+    pub open: Result<(), DriverError>,
+    /// Whether a kernel driver (e.g. cdc_acm) is bound to each interface of the device
+    pub kernel_drivers: Vec<(u8, Result<bool, DriverError>)>,
+}
+
+impl DeviceProbe {
+    pub fn is_bootloader(&self) -> bool {
+        self.product_id == BOOTLOADER_PRODUCT_ID
+    }
+    /// Returns true if the device could not be opened due to missing permissions
+    pub fn access_denied(&self) -> bool {
+        matches!(&self.open, Err(err) if *err.root() == DriverError::Access)
+    }
+    fn interfaces(&self) -> &'static [u8] {
+        // the firmware is a CDC device with a control and a data interface
+        if self.is_bootloader() {
+            &[0]
+        } else {
+            &[0, 1]
+        }
+    }
+}
+
+fn is_gb_cartpp(descriptor: &DeviceDescriptor) -> bool {
+    descriptor.vendor_id == VENDOR_ID
+        && (descriptor.product_id == BOOTLOADER_PRODUCT_ID
+            || descriptor.product_id == FIRMWARE_PRODUCT_ID)
+}
+
+fn probe(
+    id: UsbDeviceId,
+    descriptor: &DeviceDescriptor,
+    transport: Result<Box<dyn Transport>, DriverError>,
+) -> DeviceProbe {
+    let mut probe = DeviceProbe {
+        id,
+        product_id: descriptor.product_id,
+        open: Ok(()),
+        kernel_drivers: Vec::new(),
+    };
+    match transport {
+        Ok(transport) => {
+            probe.kernel_drivers = probe
+                .interfaces()
+                .iter()
+                .map(|&interface| (interface, transport.kernel_driver_active(interface)))
+                .collect();
+        }
+        Err(err) => probe.open = Err(err),
+    }
+    probe
+}
+
+impl Usb {
+    pub fn probe_devices(usb: &Arc<Usb>) -> Result<Vec<DeviceProbe>, DriverError> {
+        match &usb.backend {
+            Backend::Libusb(ctx) => {
+                let mut raw: *const *mut libusb_device = ptr::null_mut();
+                let count = check_libusb(unsafe { libusb_get_device_list(*ctx, &mut raw) as i32 })?;
+                let list = unsafe { slice::from_raw_parts(raw, count) };
+                let mut probes = Vec::new();
+                for &device in list {
+                    let mut descriptor = unsafe { mem::zeroed() };
+                    if check_libusb(unsafe {
+                        libusb_get_device_descriptor(device, &mut descriptor)
+                    })
+                    .is_err()
+                    {
+                        continue;
+                    }
+                    let descriptor = DeviceDescriptor::from(&descriptor);
+                    if !is_gb_cartpp(&descriptor) {
+                        continue;
+                    }
+                    let mut handle = ptr::null_mut();
+                    let transport = check_libusb(unsafe { libusb_open(device, &mut handle) })
+                        .map(|_| Box::new(LibusbTransport::new(handle)) as Box<dyn Transport>);
+                    probes.push(probe(UsbDeviceId::of(device), &descriptor, transport));
+                }
+                unsafe { libusb_free_device_list(raw, 1) };
+                Ok(probes)
+            }
+            Backend::Virtual(bus) => Ok(bus
+                .devices()
+                .into_iter()
+                .filter(|device| is_gb_cartpp(&device.descriptor))
+                .map(|device| {
+                    // libusb reports a missing driver as an open error too
+                    let transport = bus.open(device.id).and_then(|transport| {
+                        transport.ok_or(DriverError::UnsupportedUsbOperation)
+                    });
+                    probe(device.id, &device.descriptor, transport)
+                })
+                .collect()),
+        }
+    }
+}
@@ -0,0 +1,399 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use libusb1_sys::constants::*;
+use libusb1_sys::*;
+use std::collections::VecDeque;
+use std::os::raw::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use crate::usb::{check_libusb, UsbDeviceHandle};
+use crate::DriverError;
+
+/// Bulk IN endpoint of the CDC data interface
+pub const EP2_IN: u8 = 0x82;
+/// Bulk OUT endpoint of the CDC data interface
+pub const EP2_OUT: u8 = 0x02;
+/// Maximum packet size of EP2
+pub const EP2_PACKET_SIZE: usize = 64;
+
+const EVENT_POLL_INTERVAL_US: i64 = 100_000;
+
+struct ContextPtr(*mut libusb_context);
+
+// libusb contexts can be used from any thread
+unsafe impl Send for ContextPtr {}
+
+#[derive(Debug)]
+pub(crate) struct EventThread {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl EventThread {
+    pub(crate) fn spawn(ctx: *mut libusb_context) -> Result<EventThread, DriverError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let ctx = ContextPtr(ctx);
+        let thread = thread::Builder::new()
+            .name("libusb-events".into())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    let ctx = ctx;
+                    let tv = libc::timeval {
+                        tv_sec: 0,
+                        tv_usec: EVENT_POLL_INTERVAL_US as _,
+                    };
+                    while !stop.load(Ordering::Acquire) {
+                        unsafe {
+                            libusb_handle_events_timeout_completed(ctx.0, &tv, ptr::null_mut())
+                        };
+                    }
+                }
+            })
+            .map_err(|_| DriverError::Other(LIBUSB_ERROR_OTHER, "Failed to spawn event thread"))?;
+        Ok(EventThread { stop, thread })
+    }
+    pub(crate) fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        let _ = self.thread.join();
+    }
+}
+
+#[derive(Debug, Default)]
+struct Completion {
+    done: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Completion {
+    fn wait(&self) {
+        let mut done = self.done.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*done {
+            done = self.cond.wait(done).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+extern "system" fn transfer_callback(transfer: *mut libusb_transfer) {
+    let completion = unsafe { &*((*transfer).user_data as *const Completion) };
+    *completion
+        .done
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = true;
+    completion.cond.notify_all();
+}
+
+#[derive(Debug)]
+struct Transfer {
+    raw: *mut libusb_transfer,
+    endpoint: u8,
+    buffer: Vec<u8>,
+    completion: Arc<Completion>,
+    is_read: bool,
+}
+
+impl Transfer {
+    fn submit(
+        handle: &UsbDeviceHandle,
+        raw_handle: *mut libusb_device_handle,
+        endpoint: u8,
+        mut buffer: Vec<u8>,
+        is_read: bool,
+        timeout: u32,
+    ) -> Result<Transfer, DriverError> {
+        let _lock = handle.lock();
+        let raw = unsafe { libusb_alloc_transfer(0) };
+        if raw.is_null() {
+            return Err(DriverError::Other(
+                LIBUSB_ERROR_NO_MEM,
+                "Failed to allocate transfer",
+            ));
+        }
+        let completion = Arc::new(Completion::default());
+        unsafe {
+            libusb_fill_bulk_transfer(
+                raw,
+                raw_handle,
+                endpoint,
+                buffer.as_mut_ptr(),
+                buffer.len() as i32,
+                transfer_callback,
+                Arc::as_ptr(&completion) as *mut c_void,
+                timeout,
+            )
+        };
+        if let Err(err) = check_libusb(unsafe { libusb_submit_transfer(raw) }) {
+            unsafe { libusb_free_transfer(raw) };
+            return Err(err);
+        }
+        Ok(Transfer {
+            raw,
+            endpoint,
+            buffer,
+            completion,
+            is_read,
+        })
+    }
+    fn finish(&mut self) -> Result<BulkCompletion, DriverError> {
+        self.completion.wait();
+        let (status, len) = unsafe { ((*self.raw).status, (*self.raw).actual_length as usize) };
+        match status {
+            LIBUSB_TRANSFER_COMPLETED if self.is_read => {
+                let mut buffer = std::mem::take(&mut self.buffer);
+                buffer.truncate(len);
+                Ok(BulkCompletion::Read(buffer))
+            }
+            LIBUSB_TRANSFER_COMPLETED => Ok(BulkCompletion::Write(len)),
+            LIBUSB_TRANSFER_TIMED_OUT => Err(DriverError::UsbTimeout),
+            LIBUSB_TRANSFER_STALL => Err(DriverError::UsbPipe),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(DriverError::NoDevice),
+            LIBUSB_TRANSFER_OVERFLOW => Err(DriverError::Overflow),
+            _ => Err(DriverError::UsbIo),
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        let done = *self
+            .completion
+            .done
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !done {
+            // the buffer and completion must outlive the transfer, so wait for the cancellation
+            // to finish
+            unsafe { libusb_cancel_transfer(self.raw) };
+            self.completion.wait();
+        }
+        unsafe { libusb_free_transfer(self.raw) };
+    }
+}
+
+#[derive(Debug)]
+enum Pending {
+    Submitted(Transfer),
+    // transports without asynchronous transfers complete them at submission time
+    Completed(Result<BulkCompletion, DriverError>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BulkCompletion {
+    Read(Vec<u8>),
+    Write(usize),
+}
+
+/// A queue of bulk transfers that are kept in flight at the same time.
+///
+/// Transfers complete in submission order, so results are returned by `wait` in that order too.
+/// Dropping the queue cancels all pending transfers. If the device transport doesn't support
+/// asynchronous transfers, each transfer is done synchronously when it is submitted.
+#[derive(Debug)]
+pub struct BulkQueue<'a> {
+    handle: &'a UsbDeviceHandle,
+    endpoint_in: u8,
+    endpoint_out: u8,
+    timeout: u32,
+    // requested lengths are kept so reads can be checked for short completions
+    pending: VecDeque<(usize, Pending)>,
+}
+
+impl<'a> BulkQueue<'a> {
+    pub(crate) fn new(
+        handle: &'a UsbDeviceHandle,
+        endpoint_in: u8,
+        endpoint_out: u8,
+        timeout: u32,
+    ) -> Result<BulkQueue<'a>, DriverError> {
+        handle.usb.start_event_thread()?;
+        Ok(BulkQueue {
+            handle,
+            endpoint_in,
+            endpoint_out,
+            timeout,
+            pending: VecDeque::new(),
+        })
+    }
+    pub fn in_flight(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn submit_read(&mut self, len: usize) -> Result<(), DriverError> {
+        let transport = self.handle.transport()?;
+        let pending = match transport.libusb_handle() {
+            Some(raw_handle) => Pending::Submitted(Transfer::submit(
+                self.handle,
+                raw_handle,
+                self.endpoint_in,
+                vec![0; len],
+                true,
+                self.timeout,
+            )?),
+            None => {
+                let mut buffer = vec![0; len];
+                let _lock = self.handle.lock();
+                Pending::Completed(
+                    transport
+                        .bulk_in(self.endpoint_in, &mut buffer, self.timeout)
+                        .map(|len| {
+                            buffer.truncate(len);
+                            BulkCompletion::Read(buffer)
+                        }),
+                )
+            }
+        };
+        self.pending.push_back((len, pending));
+        Ok(())
+    }
+    pub fn submit_write(&mut self, data: &[u8]) -> Result<(), DriverError> {
+        let transport = self.handle.transport()?;
+        let pending = match transport.libusb_handle() {
+            Some(raw_handle) => Pending::Submitted(Transfer::submit(
+                self.handle,
+                raw_handle,
+                self.endpoint_out,
+                data.to_vec(),
+                false,
+                self.timeout,
+            )?),
+            None => {
+                let _lock = self.handle.lock();
+                Pending::Completed(
+                    transport
+                        .bulk_out(self.endpoint_out, data, self.timeout)
+                        .map(BulkCompletion::Write),
+                )
+            }
+        };
+        self.pending.push_back((data.len(), pending));
+        Ok(())
+    }
+    /// Waits for the oldest pending transfer, or returns `None` if nothing is in flight
+    pub fn wait(&mut self) -> Result<Option<BulkCompletion>, DriverError> {
+        Ok(self.wait_requested()?.map(|(_, completion)| completion))
+    }
+    fn wait_requested(&mut self) -> Result<Option<(usize, BulkCompletion)>, DriverError> {
+        let (requested, pending) = match self.pending.pop_front() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let result = match pending {
+            Pending::Submitted(mut transfer) => {
+                let result = transfer.finish();
+                let transport = self.handle.transport().ok();
+                if let Some(capture) = transport.and_then(|transport| transport.capture()) {
+                    // read buffers have been moved to the result, so only written data is left
+                    capture.record_bulk(
+                        self.handle.id,
+                        transfer.endpoint,
+                        requested,
+                        &transfer.buffer,
+                        &result,
+                    );
+                }
+                result
+            }
+            Pending::Completed(result) => result,
+        };
+        result.map(|completion| Some((requested, completion)))
+    }
+    /// Reads `len` bytes using up to `depth` concurrent transfers of `transfer_len` bytes.
+    ///
+    /// Transfers that are already in flight are waited for first, and data from reads among
+    /// them is included in the result. Stops early if the device sends a short packet. On
+    /// error, all remaining transfers are cancelled.
+    pub fn read_pipelined(
+        &mut self,
+        len: usize,
+        transfer_len: usize,
+        depth: usize,
+    ) -> Result<Vec<u8>, DriverError> {
+        let result = self.read_pipelined_inner(len, transfer_len, depth);
+        if result.is_err() {
+            self.pending.clear();
+        }
+        result
+    }
+    fn read_pipelined_inner(
+        &mut self,
+        len: usize,
+        transfer_len: usize,
+        depth: usize,
+    ) -> Result<Vec<u8>, DriverError> {
+        let mut data = Vec::with_capacity(len);
+        let mut submitted = 0;
+        loop {
+            while self.in_flight() < depth.max(1) && submitted < len {
+                let chunk = transfer_len.max(1).min(len - submitted);
+                self.submit_read(chunk)?;
+                submitted += chunk;
+            }
+            match self.wait_requested()? {
+                Some((requested, BulkCompletion::Read(chunk))) => {
+                    data.extend_from_slice(&chunk);
+                    if chunk.len() < requested {
+                        // the device has nothing more to send, so the rest would just time out
+                        self.pending.clear();
+                        break;
+                    }
+                }
+                Some((_, BulkCompletion::Write(_))) => (),
+                None => break,
+            }
+        }
+        Ok(data)
+    }
+    /// Writes `data` using up to `depth` concurrent transfers of `transfer_len` bytes.
+    ///
+    /// Transfers that are already in flight are waited for first. A transfer that writes less
+    /// than requested is an error. On error, all remaining transfers are cancelled.
+    pub fn write_pipelined(
+        &mut self,
+        data: &[u8],
+        transfer_len: usize,
+        depth: usize,
+    ) -> Result<usize, DriverError> {
+        let result = self.write_pipelined_inner(data, transfer_len, depth);
+        if result.is_err() {
+            self.pending.clear();
+        }
+        result
+    }
+    fn write_pipelined_inner(
+        &mut self,
+        data: &[u8],
+        transfer_len: usize,
+        depth: usize,
+    ) -> Result<usize, DriverError> {
+        let mut written = 0;
+        let mut chunks = data.chunks(transfer_len.max(1));
+        loop {
+            while self.in_flight() < depth.max(1) {
+                match chunks.next() {
+                    Some(chunk) => self.submit_write(chunk)?,
+                    None => break,
+                }
+            }
+            match self.wait_requested()? {
+                Some((requested, BulkCompletion::Write(len))) if len < requested => {
+                    return Err(DriverError::ShortWrite {
+                        expected: requested,
+                        actual: len,
+                    });
+                }
+                Some((_, BulkCompletion::Write(len))) => written += len,
+                Some((_, BulkCompletion::Read(_))) => (),
+                None => break,
+            }
+        }
+        Ok(written)
+    }
+}
+
+pub(crate) fn bulk_timeout(len: usize) -> u32 {
+    1000 + len as u32 / 16
+}
@@ -0,0 +1,201 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use libusb1_sys::constants::*;
+use libusb1_sys::*;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::usb::{check_libusb, Capture, UsbDeviceId};
+use crate::DriverError;
+
+/// Raw access to one opened USB device.
+///
+/// `UsbDeviceHandle` performs all of its I/O through this trait, so devices can be backed by
+/// something other than libusb (e.g. `mock::MockBus`).
+pub trait Transport: fmt::Debug + Send + Sync {
+    fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, DriverError>;
+    fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: u32,
+    ) -> Result<usize, DriverError>;
+    fn bulk_in(&self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, DriverError>;
+    fn bulk_out(&self, endpoint: u8, data: &[u8], timeout: u32) -> Result<usize, DriverError>;
+    fn claim_interface(&self, interface: u8) -> Result<(), DriverError>;
+    fn release_interface(&self, interface: u8) -> Result<(), DriverError>;
+    /// Returns true if a kernel driver is bound to the interface
+    fn kernel_driver_active(&self, _interface: u8) -> Result<bool, DriverError> {
+        Err(DriverError::UnsupportedUsbOperation)
+    }
+    /// Returns the libusb handle if there is one, which enables asynchronous bulk transfers
+    fn libusb_handle(&self) -> Option<*mut libusb_device_handle> {
+        None
+    }
+    /// Returns the capture that transfers bypassing the transport must be recorded to
+    fn capture(&self) -> Option<&Arc<Capture>> {
+        None
+    }
+}
+
+/// The parts of the USB device descriptor needed to identify a device
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DeviceDescriptor {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bcd_device: u16,
+    pub manufacturer: u8,
+    pub product: u8,
+}
+
+impl From<&libusb_device_descriptor> for DeviceDescriptor {
+    fn from(descriptor: &libusb_device_descriptor) -> DeviceDescriptor {
+        DeviceDescriptor {
+            vendor_id: descriptor.idVendor,
+            product_id: descriptor.idProduct,
+            bcd_device: descriptor.bcdDevice,
+            manufacturer: descriptor.iManufacturer,
+            product: descriptor.iProduct,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VirtualDevice {
+    pub id: UsbDeviceId,
+    pub descriptor: DeviceDescriptor,
+}
+
+/// A bus of devices that are not accessed through libusb
+pub trait VirtualBus: fmt::Debug + Send + Sync {
+    fn devices(&self) -> Vec<VirtualDevice>;
+    /// Opens a device, or returns `None` if it has no driver installed
+    fn open(&self, id: UsbDeviceId) -> Result<Option<Box<dyn Transport>>, DriverError>;
+}
+
+#[derive(Debug)]
+pub(crate) struct LibusbTransport {
+    raw: *mut libusb_device_handle,
+}
+
+// libusb device handles may be used from any thread. `UsbDeviceHandle` serializes requests
+unsafe impl Send for LibusbTransport {}
+unsafe impl Sync for LibusbTransport {}
+
+impl LibusbTransport {
+    pub(crate) fn new(raw: *mut libusb_device_handle) -> LibusbTransport {
+        LibusbTransport { raw }
+    }
+}
+
+impl Drop for LibusbTransport {
+    fn drop(&mut self) {
+        unsafe { libusb_close(self.raw) }
+    }
+}
+
+impl Transport for LibusbTransport {
+    fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, DriverError> {
+        check_libusb(unsafe {
+            libusb_control_transfer(
+                self.raw,
+                request_type,
+                request,
+                value,
+                index,
+                data.as_mut_ptr(),
+                data.len() as u16,
+                timeout,
+            )
+        })
+    }
+    fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: u32,
+    ) -> Result<usize, DriverError> {
+        check_libusb(unsafe {
+            libusb_control_transfer(
+                self.raw,
+                request_type,
+                request,
+                value,
+                index,
+                data.as_ptr() as *mut u8,
+                data.len() as u16,
+                timeout,
+            )
+        })
+    }
+    fn bulk_in(&self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, DriverError> {
+        let mut transferred = 0;
+        check_libusb(unsafe {
+            libusb_bulk_transfer(
+                self.raw,
+                endpoint,
+                data.as_mut_ptr(),
+                data.len() as i32,
+                &mut transferred,
+                timeout,
+            )
+        })?;
+        Ok(transferred as usize)
+    }
+    fn bulk_out(&self, endpoint: u8, data: &[u8], timeout: u32) -> Result<usize, DriverError> {
+        let mut transferred = 0;
+        check_libusb(unsafe {
+            libusb_bulk_transfer(
+                self.raw,
+                endpoint,
+                data.as_ptr() as *mut u8,
+                data.len() as i32,
+                &mut transferred,
+                timeout,
+            )
+        })?;
+        Ok(transferred as usize)
+    }
+    fn claim_interface(&self, interface: u8) -> Result<(), DriverError> {
+        if unsafe { libusb_has_capability(LIBUSB_CAP_SUPPORTS_DETACH_KERNEL_DRIVER) } != 0 {
+            check_libusb(unsafe { libusb_set_auto_detach_kernel_driver(self.raw, true as i32) })?;
+        }
+        check_libusb(unsafe { libusb_claim_interface(self.raw, interface as i32) })?;
+        Ok(())
+    }
+    fn release_interface(&self, interface: u8) -> Result<(), DriverError> {
+        check_libusb(unsafe { libusb_release_interface(self.raw, interface as i32) })?;
+        Ok(())
+    }
+    fn kernel_driver_active(&self, interface: u8) -> Result<bool, DriverError> {
+        check_libusb(unsafe { libusb_kernel_driver_active(self.raw, interface as i32) })
+            .map(|active| active == 1)
+    }
+    fn libusb_handle(&self) -> Option<*mut libusb_device_handle> {
+        Some(self.raw)
+    }
+}
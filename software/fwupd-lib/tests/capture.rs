@@ -0,0 +1,147 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+mod common;
+
+use gb_cartpp_fwupd::{
+    mock::{Fault, MockBus, MockDevice},
+    Capture, CaptureEvent, CaptureRecord, DriverError, FirmwareImage, ReplayBus, UpdateError,
+    UpdateOutcome, Updater, Usb, UsbDeviceKind, VendorCtrlRequest,
+};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::test_image;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn record_update(
+    device: MockDevice,
+    fw: &FirmwareImage,
+) -> (Result<UpdateOutcome, UpdateError>, Vec<u8>) {
+    let bus = MockBus::new();
+    bus.attach(device);
+    let usb = bus.usb();
+    let buffer = SharedBuffer::default();
+    usb.record_to(Capture::new(buffer.clone()).unwrap());
+    let result = Updater::new(&usb).reset_timeout(TIMEOUT).update(fw, |_| ());
+    (result, buffer.contents())
+}
+
+#[test]
+fn records_round_trip() {
+    let record = CaptureRecord {
+        time: Duration::from_micros(1234),
+        device: gb_cartpp_fwupd::UsbDeviceId { bus: 1, address: 7 },
+        event: CaptureEvent::ControlIn {
+            request_type: 0xc0,
+            request: 0x41,
+            value: 0,
+            index: 0,
+            len: 5,
+        },
+        result: Ok(5),
+        data: vec![0x42, 0x00, 0x01, 0x01, 0x00],
+    };
+    let line = record.to_string();
+    assert_eq!(
+        line,
+        "1234 001:007 ctrl-in:c0:41:0000:0000:5 ok:5 4200010100"
+    );
+    assert_eq!(line.parse::<CaptureRecord>(), Ok(record));
+    let failed = "1 001:007 bulk-out:02 err:timeout 0102".parse::<CaptureRecord>();
+    assert_eq!(failed.unwrap().result, Err(DriverError::UsbTimeout));
+}
+
+#[test]
+fn update_is_replayed() {
+    let fw = test_image(1, 1, 3);
+    let (result, capture) = record_update(MockDevice::with_image(&test_image(1, 0, 0)), &fw);
+    assert!(matches!(result, Ok(UpdateOutcome::Updated(_))));
+
+    let replay = ReplayBus::from_reader(capture.as_slice()).unwrap();
+    let replayed = Updater::new(&replay.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&fw, |_| ());
+    assert_eq!(replay.divergence(), None);
+    assert_eq!(replay.remaining(), 0);
+    assert!(matches!(replayed, Ok(UpdateOutcome::Updated(_))));
+}
+
+#[test]
+fn failure_is_reproduced() {
+    let fw = test_image(1, 1, 3);
+    let device = MockDevice::blank();
+    device.inject_fault(VendorCtrlRequest::WriteFlash, 3, Fault::Timeout);
+    let (result, capture) = record_update(device, &fw);
+    assert!(matches!(
+        result,
+        Err(UpdateError::Driver { ref source }) if source.root() == &DriverError::UsbTimeout
+    ));
+
+    let replay = ReplayBus::from_reader(capture.as_slice()).unwrap();
+    let replayed = Updater::new(&replay.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&fw, |_| ());
+    assert_eq!(replay.divergence(), None);
+    assert_eq!(
+        replayed.unwrap_err().to_string(),
+        result.unwrap_err().to_string()
+    );
+}
+
+#[test]
+fn divergence_is_reported() {
+    let (_, capture) = record_update(
+        MockDevice::with_image(&test_image(1, 0, 0)),
+        &test_image(1, 1, 3),
+    );
+    let replay = ReplayBus::from_reader(capture.as_slice()).unwrap();
+    let result = Updater::new(&replay.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&test_image(1, 2, 5), |_| ());
+    assert!(result.is_err());
+    assert!(replay.divergence().is_some());
+}
+
+#[test]
+fn device_without_driver_is_replayed() {
+    let device = MockDevice::blank();
+    device.set_driver_installed(false);
+    let (result, capture) = record_update(device, &test_image(1, 1, 3));
+    assert!(matches!(result, Err(UpdateError::NoDevices)));
+    let text = String::from_utf8(capture.clone()).unwrap();
+    assert!(text
+        .lines()
+        .any(|line| line.contains(" open:") && line.contains(" ok:0 ")));
+
+    let replay = ReplayBus::from_reader(capture.as_slice()).unwrap();
+    let devices = Usb::list_devices(&replay.usb()).unwrap();
+    assert_eq!(replay.divergence(), None);
+    assert_eq!(replay.remaining(), 0);
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].kind, UsbDeviceKind::Unusable);
+}
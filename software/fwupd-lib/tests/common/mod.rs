@@ -0,0 +1,22 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gb_cartpp_fwupd::{calc_rom_crc, FirmwareImage};
+
+/// Builds an image of firmware v`major`.`minor` with a valid ROM CRC. Images with different
+/// seeds have different flash contents
+pub fn test_image(major: u8, minor: u8, seed: u8) -> FirmwareImage {
+    let mut flash = Box::new([0xff; 0x8000]);
+    for (idx, byte) in flash[0x800..0x4000].iter_mut().enumerate() {
+        *byte = (idx as u8).wrapping_mul(31).wrapping_add(seed);
+    }
+    let [crc_l, crc_h] = calc_rom_crc(&flash[0x800..]).to_le_bytes();
+    FirmwareImage {
+        flash,
+        id: [crc_l, crc_h, minor, major, 0xff, 0xff, 0xff, 0xff],
+        id_mask: [true, true, true, true, false, false, false, false],
+        config: [0xff; 14],
+        config_mask: [false; 14],
+    }
+}
@@ -0,0 +1,179 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gb_cartpp_fwupd::{
+    to_hex, CartHeader, Dat, DatError, DatMatch, RomHashes, HEADER_START, NINTENDO_LOGO,
+};
+
+fn test_rom() -> Vec<u8> {
+    let mut rom = (0..0x8000).map(|idx| (idx * 7) as u8).collect::<Vec<_>>();
+    rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x144].fill(0);
+    rom[0x134..0x13e].copy_from_slice(b"SUPER GAME");
+    rom[0x147] = 0x00;
+    rom[0x148] = 0x00;
+    rom[0x14d] = CartHeader::from_rom(&rom).unwrap().calc_header_checksum();
+    rom
+}
+
+fn test_dat(rom: &[u8]) -> String {
+    let hashes = RomHashes::calculate(rom);
+    format!(
+        r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/Dats/datafile.dtd">
+<datafile>
+  <header>
+    <name>Nintendo - Game Boy</name>
+  </header>
+  <game name="Other Game (Japan)">
+    <description>Other Game (Japan)</description>
+    <rom name="Other Game (Japan).gb" size="32768" crc="12345678"/>
+  </game>
+  <game name="Super Game &amp; Friends (USA, Europe) (Rev 1)">
+    <description>Super Game &amp; Friends (USA, Europe) (Rev 1)</description>
+    <rom name="Super Game &amp; Friends (USA, Europe) (Rev 1).gb" size="{}" crc="{:08X}" md5="{}" sha1="{}" sha256="{}" status="verified"/>
+  </game>
+</datafile>
+"#,
+        hashes.size,
+        hashes.crc32,
+        to_hex(&hashes.md5).to_uppercase(),
+        to_hex(&hashes.sha1),
+        to_hex(&hashes.sha256)
+    )
+}
+
+#[test]
+fn hashes_are_calculated() {
+    let hashes = RomHashes::calculate(b"abc");
+    assert_eq!(hashes.size, 3);
+    assert_eq!(hashes.crc32, 0x3524_41c2);
+    assert_eq!(to_hex(&hashes.md5), "900150983cd24fb0d6963f7d28e17f72");
+    assert_eq!(
+        to_hex(&hashes.sha1),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    assert_eq!(
+        to_hex(&hashes.sha256),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
+
+#[test]
+fn dat_is_parsed() {
+    let rom = test_rom();
+    let dat = Dat::parse(&test_dat(&rom)).unwrap();
+    assert_eq!(dat.name.as_deref(), Some("Nintendo - Game Boy"));
+    assert_eq!(dat.games.len(), 2);
+    let game = &dat.games[1];
+    assert_eq!(game.name, "Super Game & Friends (USA, Europe) (Rev 1)");
+    assert_eq!(game.roms[0].size, Some(0x8000));
+    assert_eq!(game.roms[0].status.as_deref(), Some("verified"));
+    assert!(game.roms[0].matches(&RomHashes::calculate(&rom)));
+    assert_eq!(dat.games[0].roms[0].crc32, Some(0x1234_5678));
+    assert_eq!(dat.games[0].roms[0].sha1, None);
+}
+
+#[test]
+fn dumps_are_identified() {
+    let rom = test_rom();
+    let dat = Dat::parse(&test_dat(&rom)).unwrap();
+    let result = dat.identify(&rom);
+    assert!(result.is_verified());
+    assert_eq!(
+        result.to_string(),
+        "verified (Super Game & Friends (USA, Europe) (Rev 1))"
+    );
+
+    let mut bad = rom.clone();
+    bad[0x4000] ^= 0x08;
+    let result = dat.identify(&bad);
+    assert!(matches!(result, DatMatch::BadDump { game, .. } if game == &dat.games[1]));
+    assert_eq!(
+        result.to_string(),
+        "bad dump of Super Game & Friends (USA, Europe) (Rev 1) (expected 32768 bytes)"
+    );
+    let result = dat.identify(&rom[..0x4000]);
+    assert_eq!(
+        result.to_string(),
+        "bad dump of Super Game & Friends (USA, Europe) (Rev 1) (expected 32768 bytes, got 16384)"
+    );
+
+    let mut unknown = rom.clone();
+    unknown[0x134..0x13e].copy_from_slice(b"SUPER GOAL");
+    assert_eq!(dat.identify(&unknown), DatMatch::Unknown);
+    assert_eq!(
+        dat.identify(&rom[..usize::from(HEADER_START)]),
+        DatMatch::Unknown
+    );
+}
+
+#[test]
+fn known_bad_dumps_are_not_verified() {
+    let rom = test_rom();
+    let dat = test_dat(&rom).replace(r#"status="verified""#, r#"status="baddump""#);
+    let dat = Dat::parse(&dat).unwrap();
+    let result = dat.identify(&rom);
+    assert!(!result.is_verified());
+    assert!(matches!(result, DatMatch::KnownBadDump { game, .. } if game == &dat.games[1]));
+    assert_eq!(
+        result.to_string(),
+        "known bad dump of Super Game & Friends (USA, Europe) (Rev 1)"
+    );
+}
+
+#[test]
+fn ambiguous_titles_report_every_candidate() {
+    let dat = Dat::parse(
+        r#"<datafile>
+  <game name="Super Game 2 (USA)"><rom name="a.gb" size="32768" crc="00000001"/></game>
+  <game name="Super Game Attack (Europe)"><rom name="b.gb" size="32768" crc="00000002"/></game>
+</datafile>"#,
+    )
+    .unwrap();
+    let rom = test_rom();
+    let result = dat.identify(&rom);
+    assert_eq!(
+        result,
+        DatMatch::Ambiguous {
+            games: vec![&dat.games[0], &dat.games[1]]
+        }
+    );
+    assert_eq!(
+        result.to_string(),
+        "unverified, the title matches several games: Super Game 2 (USA), Super Game Attack (Europe)"
+    );
+
+    // an exact title match wins over longer names, and regions of one game aren't ambiguous
+    let dat = Dat::parse(
+        r#"<datafile>
+  <game name="Super Game 2 (USA)"><rom name="a.gb" size="32768" crc="00000001"/></game>
+  <game name="Super Game (Japan)"><rom name="b.gb" size="16384" crc="00000002"/></game>
+  <game name="Super Game (USA)"><rom name="c.gb" size="32768" crc="00000003"/></game>
+</datafile>"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        dat.identify(&rom),
+        DatMatch::BadDump { game, .. } if game == &dat.games[2]
+    ));
+}
+
+#[test]
+fn invalid_dats_are_rejected() {
+    assert!(matches!(
+        Dat::parse("<mame></mame>"),
+        Err(DatError::NotDatafile(name)) if name == "mame"
+    ));
+    assert!(matches!(
+        Dat::parse("<datafile><game>"),
+        Err(DatError::Xml(_))
+    ));
+    assert!(matches!(
+        Dat::parse(
+            r#"<datafile><machine name="x"><rom name="x.gb" sha1="xyz"/></machine></datafile>"#
+        ),
+        Err(DatError::InvalidAttribute { attr: "sha1", .. })
+    ));
+}
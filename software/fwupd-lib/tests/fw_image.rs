@@ -0,0 +1,234 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use flate2::{write::GzEncoder, Compression};
+use gb_cartpp_fwupd::{
+    calc_rom_crc, device_guid, CabRelease, Cabinet, FirmwareArchive, FirmwareError, FirmwareFile,
+    FirmwareFormat, FirmwareImage, HEX_FILE_NAME, METAINFO_FILE_NAME, SIG_FILE_NAME,
+};
+use ihex::Record;
+
+fn test_flash() -> Vec<u8> {
+    let mut flash = vec![0xff; 0x8000];
+    for (idx, byte) in flash[0x800..0x1000].iter_mut().enumerate() {
+        *byte = (idx as u8).wrapping_mul(13);
+    }
+    flash
+}
+
+fn test_hex(flash: &[u8], id: &[u8]) -> Vec<u8> {
+    let mut records = flash[0x800..0x1000]
+        .chunks(16)
+        .enumerate()
+        .map(|(idx, chunk)| Record::Data {
+            offset: 0x800 + (idx * 16) as u16,
+            value: chunk.to_vec(),
+        })
+        .collect::<Vec<_>>();
+    if !id.is_empty() {
+        records.push(Record::ExtendedLinearAddress(0x0020));
+        records.push(Record::Data {
+            offset: 0x0000,
+            value: id.to_vec(),
+        });
+    }
+    records.push(Record::ExtendedLinearAddress(0x0030));
+    records.push(Record::Data {
+        offset: 0x0000,
+        value: vec![0x20; 14],
+    });
+    records.push(Record::EndOfFile);
+    ihex::create_object_file_representation(&records)
+        .unwrap()
+        .into_bytes()
+}
+
+// A minimal ELF32 file with one PT_LOAD segment per (addr, data) pair
+fn test_elf(segments: &[(u32, &[u8])]) -> Vec<u8> {
+    let ph_offset = 0x34;
+    let mut data_offset = ph_offset + 0x20 * segments.len();
+    let mut elf = vec![0; ph_offset];
+    elf[..6].copy_from_slice(b"\x7fELF\x01\x01");
+    elf[0x1c..0x20].copy_from_slice(&(ph_offset as u32).to_le_bytes());
+    elf[0x2a..0x2c].copy_from_slice(&0x20u16.to_le_bytes());
+    elf[0x2c..0x2e].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for &(addr, data) in segments {
+        for field in [1, data_offset as u32, addr, addr, data.len() as u32] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        elf.extend_from_slice(&[0; 12]);
+        data_offset += data.len();
+    }
+    for &(_, data) in segments {
+        elf.extend_from_slice(data);
+    }
+    elf
+}
+
+fn test_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for &(name, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, data).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+fn decode(data: Vec<u8>) -> FirmwareImage {
+    FirmwareFile::from_bytes(data)
+        .unwrap()
+        .unwrap()
+        .decode()
+        .unwrap()
+}
+
+#[test]
+fn formats_are_detected() {
+    let flash = test_flash();
+    assert_eq!(
+        FirmwareFormat::detect(&test_hex(&flash, &[0, 1])),
+        Some(FirmwareFormat::Hex)
+    );
+    assert_eq!(FirmwareFormat::detect(&flash), Some(FirmwareFormat::Bin));
+    assert_eq!(
+        FirmwareFormat::detect(&test_elf(&[])),
+        Some(FirmwareFormat::Elf)
+    );
+    assert_eq!(
+        FirmwareFormat::detect(&[0x1f, 0x8b, 0x08]),
+        Some(FirmwareFormat::Archive)
+    );
+    assert_eq!(FirmwareFormat::detect(&flash[..0x4000]), None);
+}
+
+#[test]
+fn raw_hex_is_loaded() {
+    let flash = test_flash();
+    let image = decode(test_hex(&flash, &[0x34, 0x12, 2, 1]));
+    assert_eq!(&image.flash[..], &flash[..]);
+    assert_eq!(image.id[..4], [0x34, 0x12, 2, 1]);
+    assert_eq!(image.version(), "1.2".parse().unwrap());
+    assert_eq!(image.iter_config_bytes().count(), 12);
+}
+
+#[test]
+fn rom_crc_is_added_to_development_builds() {
+    let flash = test_flash();
+    let crc = calc_rom_crc(&flash[0x800..]);
+
+    let image = decode(flash.clone());
+    assert!(image.rom_crc().is_valid());
+    assert_eq!(image.rom_crc().calculated, crc);
+    assert!(image.version().is_unknown());
+    assert_eq!(image.iter_config_bytes().count(), 0);
+
+    let image = decode(test_hex(&flash, &[]));
+    assert!(image.rom_crc().is_valid());
+
+    // an existing CRC is kept even if it is wrong
+    let image = decode(test_hex(&flash, &[0x34, 0x12]));
+    assert!(!image.rom_crc().is_valid());
+
+    // a half-set CRC can't be completed or kept
+    let elf = test_elf(&[(0x800, &flash[0x800..0x1000]), (0x20_0000, &[0x34])]);
+    assert!(matches!(
+        FirmwareFile::from_bytes(elf).unwrap().unwrap().decode(),
+        Err(FirmwareError::PartialRomCrc)
+    ));
+}
+
+#[test]
+fn elf_segments_are_loaded() {
+    let flash = test_flash();
+    // the version IDLOCs are set with #pragma config, but the CRC is not
+    let elf = test_elf(&[
+        (0x800, &flash[0x800..0x1000]),
+        (0x20_0002, &[0, 3]),
+        (0x30_0000, &[0x20; 14]),
+    ]);
+    let image = decode(elf);
+    assert_eq!(&image.flash[..], &flash[..]);
+    assert_eq!(image.version(), "3.0".parse().unwrap());
+    assert!(image.rom_crc().is_valid());
+    assert_eq!(image.iter_config_bytes().count(), 12);
+}
+
+#[test]
+fn invalid_raw_files_are_rejected() {
+    let flash = test_flash();
+    assert!(matches!(
+        FirmwareImage::from_bin(&flash[..0x4000]),
+        Err(FirmwareError::InvalidBinSize {
+            expected: 0x8000,
+            actual: 0x4000
+        })
+    ));
+    assert!(matches!(
+        FirmwareImage::from_elf(&test_elf(&[(0xf0_0000, &[0])])),
+        Err(FirmwareError::AddressOutOfRange(0xf0_0000))
+    ));
+    assert!(matches!(
+        FirmwareImage::from_elf(&test_elf(&[(0x7fff, &[0, 0])])),
+        Err(FirmwareError::AddressOutOfRange(0x7fff))
+    ));
+    // the end address would overflow
+    assert!(matches!(
+        FirmwareImage::from_elf(&test_elf(&[(0xffff_fff0, &[0; 0x20])])),
+        Err(FirmwareError::AddressOutOfRange(0xffff_fff0))
+    ));
+    let mut truncated = test_elf(&[(0x800, &[0; 16])]);
+    truncated.truncate(0x50);
+    assert!(matches!(
+        FirmwareImage::from_elf(&truncated),
+        Err(FirmwareError::InvalidElf(_))
+    ));
+}
+
+#[test]
+fn archive_round_trips_through_cab() {
+    let flash = test_flash();
+    let hex = test_hex(&flash, &[0x34, 0x12, 2, 0]);
+    let archive = test_archive(&[(HEX_FILE_NAME, &hex), (SIG_FILE_NAME, b"signature")]);
+    let archive = FirmwareArchive::from_reader(&archive[..]).unwrap().unwrap();
+    let release = CabRelease {
+        release_notes: "Fixes <MBC5> writes.\n\nFaster & safer.".to_string(),
+        date: Some("2022-12-24".to_string()),
+    };
+    let mut cab = Vec::new();
+    archive.to_cab(&release, &mut cab).unwrap();
+    assert_eq!(FirmwareFormat::detect(&cab), Some(FirmwareFormat::Cab));
+
+    let files = Cabinet::read(&cab[..]).unwrap();
+    assert_eq!(files.file(HEX_FILE_NAME).unwrap().data, hex);
+    let metainfo = &files.file(METAINFO_FILE_NAME).unwrap().data;
+    let metainfo = String::from_utf8(metainfo.clone()).unwrap();
+    assert_eq!(device_guid(), "8bd601b9-1172-5927-91fe-e2ae8ee8dd3a");
+    assert!(metainfo.contains(&format!(">{}</firmware>", device_guid())));
+    assert!(metainfo.contains(r#"version="0.2" date="2022-12-24""#));
+    assert!(metainfo.contains("<p>Fixes &lt;MBC5&gt; writes.</p>"));
+    assert!(metainfo.contains("<p>Faster &amp; safer.</p>"));
+
+    let fw = match FirmwareFile::from_bytes(cab).unwrap().unwrap() {
+        FirmwareFile::Archive(archive) => archive,
+        fw => panic!("expected an archive, got {:?}", fw.format()),
+    };
+    assert!(fw.has_signature());
+    let image = fw.decode().unwrap();
+    assert_eq!(&image.flash[..], &flash[..]);
+    assert_eq!(image.version(), "0.2".parse().unwrap());
+}
+
+#[test]
+fn cab_export_requires_a_version() {
+    let hex = test_hex(&test_flash(), &[]);
+    let archive = test_archive(&[(HEX_FILE_NAME, &hex)]);
+    let archive = FirmwareArchive::from_reader(&archive[..]).unwrap().unwrap();
+    assert!(matches!(
+        archive.to_cab(&CabRelease::default(), Vec::new()),
+        Err(FirmwareError::MissingVersion)
+    ));
+}
@@ -0,0 +1,695 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+mod common;
+
+use gb_cartpp_fwupd::{
+    decode_panic_message, find_register,
+    mock::{Fault, MockBus, MockDevice},
+    BootloaderDriver, BootloaderReason, ByteChange, DriverError, FirmwareVersion, Rcon, ResetCause,
+    SfrDump, StkPtr, UpdateError, UpdateEvent, UpdateOutcome, UpdateRegion, Updater, Usb,
+    UsbDeviceKind, VendorCtrlRequest, VerifyResult, PANIC_MESSAGE_LEN, REGISTERS, RESET_TIMEOUT,
+    SFR_DUMP_LEN,
+};
+use std::{sync::Arc, time::Duration};
+
+use common::test_image;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+fn updated_version(outcome: &UpdateOutcome) -> Option<FirmwareVersion> {
+    match outcome {
+        UpdateOutcome::Updated(info) => match info.kind {
+            UsbDeviceKind::Firmware { fw_version, .. } => Some(fw_version),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn bootloader_driver(usb: &Arc<Usb>) -> BootloaderDriver {
+    let device = Usb::list_devices(usb).unwrap().remove(0);
+    let device = if device.kind.is_bootloader() {
+        device
+    } else {
+        device.enter_bootloader_and_wait(RESET_TIMEOUT).unwrap()
+    };
+    BootloaderDriver::initialize(device).unwrap()
+}
+
+#[test]
+fn update_from_firmware_mode() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    assert!(!device.is_bootloader());
+    let fw = test_image(1, 1, 7);
+
+    let outcome = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&fw, |_| ())
+        .unwrap();
+
+    assert_eq!(
+        updated_version(&outcome),
+        Some(FirmwareVersion { major: 1, minor: 1 })
+    );
+    assert!(!device.is_bootloader());
+    assert_eq!(device.resets(), 2);
+    assert_eq!(&device.flash()[0x800..], &fw.flash[0x800..]);
+    assert_eq!(device.id()[..4], fw.id[..4]);
+}
+
+#[test]
+fn blank_device_is_updated() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+    assert!(device.is_bootloader());
+    let fw = test_image(2, 0, 1);
+
+    let outcome = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&fw, |_| ())
+        .unwrap();
+
+    assert_eq!(
+        updated_version(&outcome),
+        Some(FirmwareVersion { major: 2, minor: 0 })
+    );
+    assert_eq!(device.resets(), 1);
+}
+
+#[test]
+fn up_to_date_device_is_not_written() {
+    let fw = test_image(1, 0, 0);
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&fw));
+
+    let outcome = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&fw, |_| ())
+        .unwrap();
+
+    assert!(matches!(outcome, UpdateOutcome::UpToDate(_)));
+    assert_eq!(device.flash_writes(), 0);
+    assert!(!device.is_bootloader());
+}
+
+#[test]
+fn downgrade_is_refused() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    let fw = test_image(1, 1, 7);
+
+    let result = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&fw, |_| ());
+
+    match result {
+        Err(UpdateError::Downgrade {
+            device_version,
+            image_version,
+        }) => {
+            assert_eq!(device_version, "1.2".parse().unwrap());
+            assert_eq!(image_version, "1.1".parse().unwrap());
+        }
+        result => panic!("expected a downgrade error, got {:?}", result),
+    }
+    assert_eq!(device.flash_writes(), 0);
+    assert!(!device.is_bootloader());
+    // refused from the firmware-reported version, without entering the bootloader
+    assert_eq!(device.resets(), 0);
+
+    let result = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .dry_run(true)
+        .update(&fw, |_| ());
+    assert!(matches!(result, Err(UpdateError::Downgrade { .. })));
+    assert_eq!(device.resets(), 0);
+
+    let outcome = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .allow_downgrade(true)
+        .update(&fw, |_| ())
+        .unwrap();
+    assert_eq!(
+        updated_version(&outcome),
+        Some(FirmwareVersion { major: 1, minor: 1 })
+    );
+}
+
+#[test]
+fn dry_run_of_downgrade_in_bootloader_is_refused() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    let usb = bus.usb();
+    let bootloader = Usb::list_devices(&usb)
+        .unwrap()
+        .remove(0)
+        .enter_bootloader_and_wait(RESET_TIMEOUT)
+        .unwrap();
+
+    let result = Updater::new(&usb)
+        .reset_timeout(TIMEOUT)
+        .dry_run(true)
+        .update_device(bootloader, &test_image(1, 1, 7), |_| ());
+
+    assert!(matches!(result, Err(UpdateError::Downgrade { .. })));
+    assert_eq!(device.flash_writes(), 0);
+    assert!(device.is_bootloader());
+}
+
+#[test]
+fn firmware_versions_are_ordered() {
+    let v = |s: &str| s.parse::<FirmwareVersion>().unwrap();
+    assert!(v("1.10") > v("1.9"));
+    assert!(v("2.0") > v("1.255"));
+    assert_eq!(v("v1.2"), FirmwareVersion { major: 1, minor: 2 });
+    assert_eq!(v("???"), FirmwareVersion::UNKNOWN);
+    assert_eq!(v("255.255"), FirmwareVersion::UNKNOWN);
+    assert!(FirmwareVersion::UNKNOWN < v("0.0"));
+    assert_eq!(v("1.2").to_string(), "1.2");
+    for invalid in [
+        "", "1", "1.", "1.2.3", "256.0", "a.b", "+1.2", "1.+2", "-1.2", "v+1.2",
+    ] {
+        assert!(invalid.parse::<FirmwareVersion>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn dry_run_does_not_write() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    let fw = test_image(1, 1, 7);
+
+    let outcome = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .dry_run(true)
+        .update(&fw, |_| ())
+        .unwrap();
+
+    let report = match outcome {
+        UpdateOutcome::DryRun(_, report) => report,
+        outcome => panic!("Unexpected outcome {:?}", outcome),
+    };
+    assert!(!report.is_up_to_date());
+    assert_eq!(report.flash_changes[0].addr, 0x800);
+    assert_eq!(report.id_changes.len(), 3);
+    assert_eq!(device.flash_writes(), 0);
+    assert!(!device.is_bootloader());
+}
+
+#[test]
+fn config_differences_do_not_fail_updates() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    let mut fw = test_image(1, 1, 7);
+    fw.config[0] = 0x5a;
+    fw.config_mask[0] = true;
+
+    let mut events = Vec::new();
+    let outcome = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&fw, |event| events.push(event))
+        .unwrap();
+
+    assert_eq!(
+        updated_version(&outcome),
+        Some(FirmwareVersion { major: 1, minor: 1 })
+    );
+    // reported before anything is written
+    let mismatch = events
+        .iter()
+        .position(|event| {
+            *event
+                == UpdateEvent::ConfigMismatch {
+                    addr: 0x30_0000,
+                    expected: 0x5a,
+                    actual: 0xff,
+                }
+        })
+        .unwrap();
+    assert!(events[..mismatch]
+        .iter()
+        .all(|event| !matches!(event, UpdateEvent::BlockWritten { .. })));
+    assert_eq!(device.config()[0], 0xff);
+}
+
+#[test]
+fn dry_run_reports_config_differences() {
+    let bus = MockBus::new();
+    let mut fw = test_image(1, 0, 0);
+    bus.attach(MockDevice::with_image(&fw));
+    fw.config[0] = 0x5a;
+    fw.config_mask[0] = true;
+
+    let outcome = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .dry_run(true)
+        .update(&fw, |_| ())
+        .unwrap();
+
+    let report = match outcome {
+        UpdateOutcome::DryRun(_, report) => report,
+        outcome => panic!("Unexpected outcome {:?}", outcome),
+    };
+    assert!(report.is_up_to_date());
+    assert_eq!(
+        report.config_changes,
+        [ByteChange {
+            addr: 0x30_0000,
+            current: 0xff,
+            new: 0x5a
+        }]
+    );
+    assert_eq!(
+        report.config,
+        VerifyResult::Invalid {
+            errors: 1,
+            first_error_addr: 0x30_0000
+        }
+    );
+}
+
+#[test]
+fn multiple_devices_are_rejected() {
+    let bus = MockBus::new();
+    bus.attach(MockDevice::blank());
+    bus.attach(MockDevice::blank());
+
+    let result = Updater::new(&bus.usb()).update(&test_image(1, 0, 0), |_| ());
+
+    assert!(matches!(result, Err(UpdateError::MultipleDevices(2))));
+}
+
+#[test]
+fn read_size_probe_falls_back_after_pipe_error() {
+    let fw = test_image(1, 0, 0);
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&fw));
+    // the first read is the 4 KiB probe
+    device.inject_fault(VendorCtrlRequest::Read, 0, Fault::Pipe);
+
+    let drv = bootloader_driver(&bus.usb());
+
+    assert_eq!(drv.read_chunk_len(), 0x100);
+    assert_eq!(drv.calc_flash_checksum().unwrap(), fw.checksum());
+}
+
+#[test]
+fn timeout_is_reported() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    device.inject_fault(VendorCtrlRequest::WriteFlash, 5, Fault::Timeout);
+
+    let result = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&test_image(1, 1, 7), |_| ());
+
+    let Err(UpdateError::Driver { source }) = result else {
+        panic!("expected a driver error, got {:?}", result);
+    };
+    assert_eq!(source.root(), &DriverError::UsbTimeout);
+    let context = source.context().unwrap();
+    assert_eq!(context.request, Some(VendorCtrlRequest::WriteFlash));
+    assert!(context.addr.is_some_and(|addr| addr >= 0x800));
+    assert!(source.to_string().contains("WriteFlash at 0x"));
+}
+
+#[test]
+fn corrupted_write_fails_verification() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    device.inject_fault(VendorCtrlRequest::WriteFlash, 3, Fault::CorruptWrite);
+
+    let result = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&test_image(1, 1, 7), |_| ());
+
+    match result {
+        Err(UpdateError::VerifyFailed {
+            region: UpdateRegion::Flash,
+            errors: 1,
+            first_error_addr: 0x8c0,
+        }) => (),
+        result => panic!("Unexpected result {:?}", result),
+    }
+    assert!(device.is_bootloader());
+    assert!(!device.is_unlocked());
+    assert!(device.claimed_interfaces().is_empty());
+}
+
+#[test]
+fn dropped_driver_locks_and_releases() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+
+    let drv = bootloader_driver(&bus.usb());
+    assert!(device.is_unlocked());
+    assert_eq!(device.claimed_interfaces(), [0]);
+    drop(drv);
+    assert!(!device.is_unlocked());
+    assert!(device.claimed_interfaces().is_empty());
+
+    bootloader_driver(&bus.usb()).abort().unwrap();
+    assert!(!device.is_unlocked());
+    assert!(device.claimed_interfaces().is_empty());
+}
+
+#[test]
+fn finish_can_leave_bootloader_unlocked() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+
+    let unclaimed = bootloader_driver(&bus.usb()).finish().unwrap();
+    assert!(!device.is_unlocked());
+    assert!(device.claimed_interfaces().is_empty());
+
+    let drv = BootloaderDriver::initialize(unclaimed).unwrap();
+    drv.relock(false).finish().unwrap();
+    assert!(device.is_unlocked());
+    assert!(device.claimed_interfaces().is_empty());
+}
+
+#[test]
+fn teardown_errors_are_reported() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+
+    let drv = bootloader_driver(&bus.usb());
+    device.disconnect();
+    assert_eq!(
+        drv.finish().err().map(|err| err.root().clone()),
+        Some(DriverError::NoDevice)
+    );
+}
+
+#[test]
+fn invalid_rom_crc_will_not_boot() {
+    let bus = MockBus::new();
+    bus.attach(MockDevice::blank());
+    let mut fw = test_image(1, 0, 0);
+    fw.id[0] ^= 0xff;
+
+    let result = Updater::new(&bus.usb())
+        .reset_timeout(TIMEOUT)
+        .update(&fw, |_| ());
+
+    assert!(matches!(
+        result,
+        Err(UpdateError::WillNotBoot(BootloaderReason::InvalidRomCrc(_)))
+    ));
+}
+
+#[test]
+fn driver_reads_back_written_flash() {
+    let bus = MockBus::new();
+    bus.attach(MockDevice::blank());
+    let usb = bus.usb();
+    let drv = bootloader_driver(&usb);
+    let fw = test_image(1, 0, 3);
+
+    drv.write_flash(&fw, |_| ()).unwrap();
+
+    let mut data = vec![0; 0x100];
+    drv.read_flash(0x800, &mut data).unwrap();
+    assert_eq!(&data[..], &fw.flash[0x800..0x900]);
+    assert_eq!(drv.calc_flash_checksum().unwrap(), fw.checksum());
+}
+
+#[test]
+fn short_read_is_reported() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+    let drv = bootloader_driver(&bus.usb());
+    device.inject_fault(VendorCtrlRequest::Read, 0, Fault::ShortRead);
+
+    let err = drv.read_diagnostics().unwrap_err();
+    assert_eq!(
+        err,
+        DriverError::ShortRead {
+            expected: 1,
+            actual: 0
+        }
+    );
+}
+
+#[test]
+fn short_flash_reads_are_reported() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+    let drv = bootloader_driver(&bus.usb());
+    assert!(drv.read_chunk_len() > 64);
+
+    let mut data = vec![0; 64];
+    device.inject_fault(VendorCtrlRequest::Read, 0, Fault::ShortRead);
+    assert_eq!(
+        drv.read_flash(0x800, &mut data),
+        Err(DriverError::ShortRead {
+            expected: 64,
+            actual: 32
+        })
+    );
+
+    // the fallback to 64-byte reads must check their length too
+    let mut data = vec![0; 0x100];
+    device.inject_fault(VendorCtrlRequest::Read, 0, Fault::ShortRead);
+    device.inject_fault(VendorCtrlRequest::Read, 0, Fault::ShortRead);
+    assert_eq!(
+        drv.read_flash(0x800, &mut data),
+        Err(DriverError::ShortRead {
+            expected: 64,
+            actual: 32
+        })
+    );
+}
+
+#[test]
+fn locked_bootloader_refuses_reads_and_lock() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+    let bl = Usb::list_devices(&bus.usb())
+        .unwrap()
+        .remove(0)
+        .claim_bootloader()
+        .unwrap();
+    assert!(!device.is_unlocked());
+
+    let err = bl.read_byte(0x800).unwrap_err();
+    assert_eq!(err.root(), &DriverError::UsbPipe);
+    let err = bl.lock().unwrap_err();
+    assert_eq!(err.root(), &DriverError::UsbPipe);
+
+    bl.unlock().unwrap();
+    assert_eq!(bl.read_byte(0x800).unwrap(), 0xff);
+    bl.lock().unwrap();
+    assert!(!device.is_unlocked());
+}
+
+#[test]
+fn flash_writes_keep_the_rest_of_the_block() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+    let bl = Usb::list_devices(&bus.usb())
+        .unwrap()
+        .remove(0)
+        .claim_bootloader()
+        .unwrap();
+    bl.unlock().unwrap();
+
+    bl.write_flash(0x0800, &[0x11; 64]).unwrap();
+    bl.write_flash(0x0800, &[0x22; 16]).unwrap();
+    let flash = device.flash();
+    assert_eq!(flash[0x0800..0x0810], [0x22; 16]);
+    assert_eq!(flash[0x0810..0x0840], [0x11; 48]);
+
+    // only the block containing the address is written
+    bl.write_flash(0x0840, &[0x33; 128]).unwrap();
+    let flash = device.flash();
+    assert_eq!(flash[0x0840..0x0880], [0x33; 64]);
+    assert_eq!(flash[0x0880..0x08c0], [0xff; 64]);
+
+    // the boot block is write-protected
+    bl.write_flash(0x0000, &[0; 64]).unwrap();
+    assert_eq!(device.flash()[..0x40], flash[..0x40]);
+}
+
+#[test]
+fn out_of_range_requests_are_rejected() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+    let usb = bus.usb();
+    let bl = Usb::list_devices(&usb)
+        .unwrap()
+        .remove(0)
+        .claim_bootloader()
+        .unwrap();
+    bl.unlock().unwrap();
+
+    let out_of_range = |addr, len| Some(DriverError::AddressOutOfRange { addr, len });
+    assert_eq!(
+        bl.write_flash(0x0820, &[0; 64]).err(),
+        out_of_range(0x0820, 64)
+    );
+    assert_eq!(
+        bl.write_flash(0x7fc0, &[0; 128]).err(),
+        out_of_range(0x7fc0, 128)
+    );
+    assert_eq!(bl.write_flash(0x0800, &[]).err(), out_of_range(0x0800, 0));
+    assert_eq!(bl.erase_flash(0x8000).err(), out_of_range(0x8000, 1));
+    assert_eq!(
+        bl.write_cfg(0x2f_ffff, &[0; 2]).err(),
+        out_of_range(0x2f_ffff, 2)
+    );
+    assert_eq!(
+        bl.write_cfg(0x30_0000, &[0; 17]).err(),
+        out_of_range(0x30_0000, 17)
+    );
+    assert_eq!(
+        bl.write_id(0x20_0004, &[0; 8]).err(),
+        out_of_range(0x20_0004, 8)
+    );
+    assert_eq!(bl.read(0x0800, &mut []).err(), out_of_range(0x0800, 0));
+    assert_eq!(device.flash_writes(), 0);
+
+    // the device is still usable afterwards
+    assert_eq!(bl.read_to_vec(0x0800, 4).unwrap(), [0xff; 4]);
+}
+
+#[test]
+fn initialize_rejects_firmware_mode() {
+    let bus = MockBus::new();
+    bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+
+    match BootloaderDriver::initialize(device) {
+        Err(DriverError::WrongMode { kind }) => assert!(kind.is_firmware()),
+        result => panic!("expected a mode error, got {:?}", result.err()),
+    }
+}
+
+#[test]
+fn crash_is_analyzed() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    device.crash("pipe_tx_flush: no valid slice", Rcon::TO, StkPtr::empty());
+    assert!(device.is_bootloader());
+
+    let drv = bootloader_driver(&bus.usb());
+    let report = drv.post_mortem().unwrap();
+    assert_eq!(report.cause, ResetCause::Watchdog);
+    assert!(report.cause.is_unexpected());
+    assert_eq!(
+        report.panic_message.as_deref(),
+        Some("pipe_tx_flush: no valid slice")
+    );
+
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    device.crash("", Rcon::empty(), StkPtr::STKFUL);
+    let drv = bootloader_driver(&bus.usb());
+    let report = drv.post_mortem().unwrap();
+    assert_eq!(report.cause, ResetCause::StackOverflow);
+    assert_eq!(report.panic_message, None);
+    // the bootloader has cleared the live registers, so the cause comes from the saved copies
+    let sfrs = drv.dump_sfrs().unwrap();
+    assert_eq!(sfrs.get("STKPTR").unwrap().value, 0);
+    assert_eq!(sfrs.get("RCON").unwrap().value, 0b1001_1111);
+}
+
+#[test]
+fn panic_message_garbage_is_ignored() {
+    assert_eq!(decode_panic_message(&[0; PANIC_MESSAGE_LEN]), None);
+    assert_eq!(decode_panic_message(&[0x41, 0x9c, 0x00]), None);
+    assert_eq!(
+        decode_panic_message(&[b'x'; PANIC_MESSAGE_LEN]).map(|msg| msg.len()),
+        Some(PANIC_MESSAGE_LEN)
+    );
+}
+
+#[test]
+fn sfr_dump_is_decoded() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    device.crash("", Rcon::TO, StkPtr::empty());
+
+    let dump = bootloader_driver(&bus.usb()).dump_sfrs().unwrap();
+    assert_eq!(dump.as_bytes().len(), SFR_DUMP_LEN);
+    let rcon = dump.get("RCON").unwrap();
+    assert_eq!(rcon.addr, 0x0fd0);
+    let fields = rcon
+        .fields()
+        .map(|(field, value)| (field.name, value))
+        .collect::<Vec<_>>();
+    // the bootloader has reset the flags, so the watchdog timeout is no longer visible
+    assert!(fields.contains(&("TO", 1)));
+    assert!(fields.contains(&("RI", 1)));
+    assert_eq!(dump.iter().count(), SFR_DUMP_LEN);
+    assert!(REGISTERS.windows(2).all(|regs| regs[0].addr < regs[1].addr));
+    assert_eq!(find_register(0x0fd0), rcon.register);
+    assert_eq!(
+        dump.iter().find(|sfr| sfr.addr == 0x0f57).unwrap().register,
+        None
+    );
+
+    let mut bytes = dump.as_bytes().to_vec();
+    bytes[0x0fd0 - 0x0f57] ^= 0b0000_1000;
+    let changes = SfrDump::new(bytes).diff(&dump);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].register.map(|reg| reg.name), Some("RCON"));
+    assert!(changes[0].to_string().contains("TO=1->0"));
+}
+
+#[test]
+fn probe_reports_access_denied() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+    device.set_open_error(Some(DriverError::Access));
+
+    let probes = Usb::probe_devices(&bus.usb()).unwrap();
+    assert_eq!(probes.len(), 1);
+    assert!(probes[0].is_bootloader());
+    assert!(probes[0].access_denied());
+    assert!(probes[0].kernel_drivers.is_empty());
+    // the only device failed to open, so listing returns the error
+    assert_eq!(
+        Usb::list_devices(&bus.usb()).err(),
+        Some(DriverError::Access)
+    );
+}
+
+#[test]
+fn probe_reports_busy_device() {
+    let bus = MockBus::new();
+    let busy = bus.attach(MockDevice::with_image(&test_image(1, 0, 0)));
+    busy.set_open_error(Some(DriverError::Busy));
+    bus.attach(MockDevice::blank());
+
+    let probes = Usb::probe_devices(&bus.usb()).unwrap();
+    assert_eq!(probes.len(), 2);
+    let busy_probe = probes.iter().find(|probe| !probe.is_bootloader()).unwrap();
+    assert_eq!(busy_probe.open, Err(DriverError::Busy));
+    assert!(!busy_probe.access_denied());
+    let ok_probe = probes.iter().find(|probe| probe.is_bootloader()).unwrap();
+    assert_eq!(ok_probe.open, Ok(()));
+    assert_eq!(ok_probe.kernel_drivers.len(), 1);
+    // a device that fails to open doesn't hide the others
+    let devices = Usb::list_devices(&bus.usb()).unwrap();
+    assert_eq!(devices.len(), 1);
+    assert!(devices[0].kind.is_bootloader());
+}
+
+#[test]
+fn probe_reports_missing_driver() {
+    let bus = MockBus::new();
+    let device = bus.attach(MockDevice::blank());
+    device.set_driver_installed(false);
+
+    let probes = Usb::probe_devices(&bus.usb()).unwrap();
+    assert_eq!(probes.len(), 1);
+    assert_eq!(probes[0].open, Err(DriverError::UnsupportedUsbOperation));
+    assert!(!probes[0].access_denied());
+    let devices = Usb::list_devices(&bus.usb()).unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].kind, UsbDeviceKind::Unusable);
+}
@@ -0,0 +1,677 @@
+// SPDX-FileCopyrightText: 2019-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+mod common;
+
+use gb_cartpp_fwupd::{
+    mock::{MockBus, MockDevice},
+    sim::{
+        AmdFlash, Cartridge, DirtyContacts, FirmwareSim, FlashCart, FlashWriteStrobe, Mbc1, Mbc3,
+        Mbc5, RomOnly, Rtc,
+    },
+    BulkCompletion, CartCheck, CartDriver, CartHeader, CartMode, CartVerdict, Command, DriverError,
+    DumpError, DumpEvent, FirmwareMode, FirmwareVersion, FlashWrite, Mapper, ReadTiming, RomDump,
+    RomDumper, Usb, UsbDevice, UsbDeviceKind, CHECK_PASSES, CMD_USE_CS, CMD_USE_VIN, DUMP_PASSES,
+    NINTENDO_LOGO, UNLOCK_MAGIC,
+};
+use std::{sync::Arc, time::Duration};
+
+use common::test_image;
+
+const VERSION: FirmwareVersion = FirmwareVersion { major: 1, minor: 2 };
+const BOOTLOADER_VERSION: FirmwareVersion = FirmwareVersion { major: 1, minor: 0 };
+
+fn sim_with(cart: impl Cartridge + 'static) -> FirmwareSim {
+    let mut sim = FirmwareSim::new(VERSION, BOOTLOADER_VERSION);
+    sim.insert_cartridge(Box::new(cart));
+    sim.set_active(true);
+    sim.write(&[Command::Unlock as u8]);
+    sim.write(&UNLOCK_MAGIC);
+    assert_eq!(read_all(&mut sim), UNLOCK_MAGIC);
+    sim.write(&[Command::SetMode as u8, CartMode::VCART.bits()]);
+    sim
+}
+
+fn read_all(sim: &mut FirmwareSim) -> Vec<u8> {
+    let mut buf = vec![0; sim.pending_output()];
+    let len = sim.read(&mut buf);
+    buf.truncate(len);
+    buf
+}
+
+fn write(sim: &mut FirmwareSim, addr: u16, data: u8) {
+    let [addr_l, addr_h] = addr.to_le_bytes();
+    sim.write(&[Command::Write as u8, addr_l, addr_h, data]);
+}
+
+fn read_burst(sim: &mut FirmwareSim, flags: u8, addr: u16, len: u16) -> Vec<u8> {
+    let [addr_l, addr_h] = addr.to_le_bytes();
+    let [len_l, len_h] = len.to_le_bytes();
+    sim.write(&[
+        Command::ReadBurst as u8 | flags,
+        addr_l,
+        addr_h,
+        len_l,
+        len_h,
+    ]);
+    read_all(sim)
+}
+
+fn banked_rom(banks: usize) -> Vec<u8> {
+    (0..banks * 0x4000)
+        .map(|idx| (idx / 0x4000) as u8 ^ idx as u8)
+        .collect()
+}
+
+#[test]
+fn locked_commands_are_dropped() {
+    let mut sim = FirmwareSim::new(VERSION, BOOTLOADER_VERSION);
+    sim.set_active(true);
+    let ping = [0x5a; 16];
+    sim.write(&[Command::GetMode as u8, Command::Ping as u8]);
+    sim.write(&ping);
+    assert_eq!(read_all(&mut sim), ping);
+
+    let mut magic = UNLOCK_MAGIC;
+    magic[0] ^= 0xff;
+    sim.write(&[Command::Unlock as u8]);
+    sim.write(&magic);
+    sim.write(&[Command::Identify as u8]);
+    assert_eq!(read_all(&mut sim), [0x99, 0, 1, 2, 1]);
+    assert!(!sim.is_unlocked());
+}
+
+#[test]
+fn commands_can_be_split_across_packets() {
+    let mut sim = sim_with(Mbc5::new(banked_rom(4), vec![]));
+    for byte in [Command::ReadBurst as u8, 0x00, 0x40, 0x04, 0x00] {
+        sim.write(&[byte]);
+    }
+    assert_eq!(read_all(&mut sim), [0x01, 0x00, 0x03, 0x02]);
+    sim.write(&[Command::GetMode as u8]);
+    assert_eq!(read_all(&mut sim), [CartMode::VCART.bits(), 1]);
+}
+
+#[test]
+fn mbc5_banking_and_ram() {
+    let mut sim = sim_with(Mbc5::new(banked_rom(512), vec![0; 0x8000]));
+    write(&mut sim, 0x2000, 0x34);
+    write(&mut sim, 0x3000, 0x01);
+    assert_eq!(read_burst(&mut sim, 0, 0x4000, 2), [0x34, 0x35]);
+
+    // RAM is only accessible when it's enabled and /CS is asserted
+    assert_eq!(read_burst(&mut sim, CMD_USE_CS, 0xa000, 1), [0xff]);
+    write(&mut sim, 0x0000, 0x0a);
+    write(&mut sim, 0x4000, 0x03);
+    let data = [0x12, 0x34, 0x56];
+    sim.write(&[
+        Command::WriteBurst as u8 | CMD_USE_CS,
+        0x00,
+        0xa0,
+        0x03,
+        0x00,
+    ]);
+    sim.write(&data);
+    assert_eq!(read_burst(&mut sim, CMD_USE_CS, 0xa000, 3), data);
+    assert_eq!(read_burst(&mut sim, 0, 0xa000, 1), [0xff]);
+}
+
+#[test]
+fn reset_restores_mbc1_defaults() {
+    let mut sim = sim_with(Mbc1::new(banked_rom(128), vec![0; 0x8000]));
+    write(&mut sim, 0x2000, 0x00);
+    assert_eq!(read_burst(&mut sim, 0, 0x4000, 1), [0x01]);
+    write(&mut sim, 0x2000, 0x05);
+    write(&mut sim, 0x4000, 0x02);
+    assert_eq!(read_burst(&mut sim, 0, 0x4000, 1), [0x45]);
+
+    sim.write(&[
+        Command::SetMode as u8,
+        (CartMode::VCART | CartMode::RESET).bits(),
+    ]);
+    write(&mut sim, 0x2000, 0x07);
+    sim.write(&[Command::SetMode as u8, CartMode::VCART.bits()]);
+    assert_eq!(read_burst(&mut sim, 0, 0x4000, 1), [0x01]);
+}
+
+#[test]
+fn mbc3_rtc_is_latched() {
+    let rtc = Rtc {
+        hours: 23,
+        minutes: 59,
+        seconds: 58,
+        ..Rtc::default()
+    };
+    let mut cart = Mbc3::new(banked_rom(8), vec![0; 0x8000]).with_rtc(rtc);
+    cart.rtc_mut().unwrap().advance(3);
+    let mut sim = sim_with(cart);
+    write(&mut sim, 0x0000, 0x0a);
+    write(&mut sim, 0x6000, 0x00);
+    write(&mut sim, 0x6000, 0x01);
+    let mut registers = Vec::new();
+    for register in 0x08..=0x0c {
+        write(&mut sim, 0x4000, register);
+        registers.extend(read_burst(&mut sim, CMD_USE_CS, 0xa000, 1));
+    }
+    assert_eq!(registers, [1, 0, 0, 1, 0]);
+}
+
+#[test]
+fn flash_burst_programs_amd_flash() {
+    for strobe in [FlashWriteStrobe::Wr, FlashWriteStrobe::Vin] {
+        let chip = AmdFlash::am29f016().with_timing(5, 50);
+        let mut sim = sim_with(FlashCart::new(chip, strobe));
+        let use_vin = strobe == FlashWriteStrobe::Vin;
+        let sequence = [
+            FlashWrite {
+                addr: 0x555,
+                data: 0xaa,
+                use_vin,
+            },
+            FlashWrite {
+                addr: 0x2aa,
+                data: 0x55,
+                use_vin,
+            },
+            FlashWrite {
+                addr: 0x555,
+                data: 0xa0,
+                use_vin,
+            },
+        ];
+        sim.write(&[Command::SetFlashWriteSequence as u8, sequence.len() as u8]);
+        for write in sequence {
+            sim.write(&write.to_bytes());
+        }
+        let flags = if use_vin { CMD_USE_VIN } else { 0 };
+        let data = [0x00, 0xff, 0x7f, 0x80];
+        sim.write(&[Command::FlashBurst as u8 | flags, 0x00, 0x01, 0x04, 0x00]);
+        sim.write(&data);
+        assert_eq!(read_all(&mut sim), [0x00, 0x00]);
+        assert_eq!(read_burst(&mut sim, 0, 0x0100, 4), data);
+    }
+}
+
+#[test]
+fn sector_erase_is_polled() {
+    let chip = AmdFlash::am29f016()
+        .with_contents(&[0x00; 0x20000])
+        .with_timing(5, 50);
+    let mut sim = sim_with(FlashCart::new(chip, FlashWriteStrobe::Wr));
+    // erase the sector at 0x10000, which is in bank 4. Bank switches also reach the chip, so
+    // they must not interrupt the command sequence
+    write(&mut sim, 0x2000, 0x04);
+    for (addr, data) in [
+        (0x555, 0xaa),
+        (0x2aa, 0x55),
+        (0x555, 0x80),
+        (0x555, 0xaa),
+        (0x2aa, 0x55),
+    ] {
+        write(&mut sim, addr, data);
+    }
+    write(&mut sim, 0x4000, 0x30);
+    sim.write(&[Command::PollFlashData as u8, 0x00, 0x40, 0x80]);
+    assert_eq!(read_all(&mut sim), [0xff]);
+    assert_eq!(read_burst(&mut sim, 0, 0x7ffe, 2), [0xff, 0xff]);
+    assert_eq!(read_burst(&mut sim, 0, 0x0000, 1), [0x00]);
+}
+
+#[test]
+fn oversized_flash_write_sequence_is_rejected() {
+    let mut sim = sim_with(Mbc5::new(banked_rom(4), vec![]));
+    sim.write(&[Command::SetFlashWriteSequence as u8, 17]);
+    sim.write(&[Command::Identify as u8]);
+    assert_eq!(read_all(&mut sim), [0x99, 0, 1, 2, 1]);
+}
+
+#[test]
+fn dtr_drop_resets_state() {
+    let mut sim = sim_with(Mbc5::new(banked_rom(4), vec![]));
+    sim.set_active(false);
+    assert!(!sim.is_unlocked());
+    assert_eq!(sim.mode(), CartMode::RESET);
+    sim.set_active(true);
+    sim.write(&[Command::Reset as u8, 0x42]);
+    assert_eq!(sim.take_reset(), None);
+}
+
+#[test]
+fn reset_command_reports_magic() {
+    let mut sim = sim_with(Mbc5::new(banked_rom(4), vec![]));
+    sim.write(&[Command::Reset as u8, 0x42, Command::Identify as u8]);
+    assert_eq!(sim.take_reset(), Some(0x42));
+    assert_eq!(sim.pending_output(), 0);
+}
+
+#[test]
+fn firmware_mode_device_runs_commands() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    mock.insert_cartridge(Mbc5::new(banked_rom(4), vec![]));
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+    let mut device = device.claim_firmware().unwrap();
+    assert!(mock.is_pipe_active());
+    assert!(device.identify().unwrap().is_firmware());
+
+    let mut command = vec![Command::Unlock as u8];
+    command.extend_from_slice(&UNLOCK_MAGIC);
+    command.extend_from_slice(&[Command::SetMode as u8, CartMode::VCART.bits()]);
+    command.extend_from_slice(&[Command::ReadBurst as u8, 0x00, 0x40, 0x04, 0x00]);
+    assert_eq!(device.write(&command).unwrap(), command.len());
+    let mut buffer = [0; 64];
+    let len = device.read(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len - 4], UNLOCK_MAGIC);
+    assert_eq!(&buffer[len - 4..len], [0x01, 0x00, 0x03, 0x02]);
+
+    let mut queue = device.bulk_queue().unwrap();
+    queue.submit_write(&[Command::GetMode as u8]).unwrap();
+    queue.submit_read(64).unwrap();
+    assert_eq!(queue.wait().unwrap(), Some(BulkCompletion::Write(1)));
+    assert_eq!(
+        queue.wait().unwrap(),
+        Some(BulkCompletion::Read(vec![CartMode::VCART.bits(), 1]))
+    );
+    drop(queue);
+
+    let device = device.release().unwrap();
+    assert!(!mock.is_pipe_active());
+    assert!(matches!(device.kind, UsbDeviceKind::Firmware { .. }));
+}
+
+fn unlocked_firmware(bus: &Arc<MockBus>) -> UsbDevice<FirmwareMode> {
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    mock.insert_cartridge(Mbc5::new(banked_rom(4), vec![]));
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+    let device = device.claim_firmware().unwrap();
+    let mut command = vec![Command::Unlock as u8];
+    command.extend_from_slice(&UNLOCK_MAGIC);
+    command.extend_from_slice(&[Command::SetMode as u8, CartMode::VCART.bits()]);
+    device.write(&command).unwrap();
+    let mut buffer = [0; 64];
+    assert_eq!(device.read(&mut buffer).unwrap(), UNLOCK_MAGIC.len());
+    device
+}
+
+fn read_burst_command(addr: u16, len: u16) -> Vec<u8> {
+    let [addr_l, addr_h] = addr.to_le_bytes();
+    let [len_l, len_h] = len.to_le_bytes();
+    vec![Command::ReadBurst as u8, addr_l, addr_h, len_l, len_h]
+}
+
+#[test]
+fn bulk_queue_completes_in_order() {
+    let bus = MockBus::new();
+    let device = unlocked_firmware(&bus);
+    let rom = banked_rom(4);
+    let mut queue = device.bulk_queue().unwrap();
+    queue.submit_write(&read_burst_command(0x0000, 8)).unwrap();
+    queue.submit_read(4).unwrap();
+    queue.submit_write(&read_burst_command(0x0100, 2)).unwrap();
+    queue.submit_read(64).unwrap();
+    assert_eq!(queue.in_flight(), 4);
+    assert_eq!(queue.wait().unwrap(), Some(BulkCompletion::Write(5)));
+    assert_eq!(
+        queue.wait().unwrap(),
+        Some(BulkCompletion::Read(rom[..4].to_vec()))
+    );
+    assert_eq!(queue.wait().unwrap(), Some(BulkCompletion::Write(5)));
+    let mut expected = rom[4..8].to_vec();
+    expected.extend_from_slice(&rom[0x100..0x102]);
+    assert_eq!(queue.wait().unwrap(), Some(BulkCompletion::Read(expected)));
+    assert_eq!(queue.wait().unwrap(), None);
+}
+
+#[test]
+fn pipelined_read_tracks_queued_transfers() {
+    let bus = MockBus::new();
+    let device = unlocked_firmware(&bus);
+    let rom = banked_rom(4);
+    let mut queue = device.bulk_queue().unwrap();
+    queue
+        .submit_write(&read_burst_command(0x0000, 0x90))
+        .unwrap();
+    // a read of a different length is already in flight when the pipelined read starts
+    queue.submit_read(16).unwrap();
+    let data = queue.read_pipelined(0x80, 64, 2).unwrap();
+    assert_eq!(data, &rom[..0x90]);
+    assert_eq!(queue.in_flight(), 0);
+}
+
+#[test]
+fn pipelined_read_stops_at_short_completion() {
+    let bus = MockBus::new();
+    let device = unlocked_firmware(&bus);
+    let rom = banked_rom(4);
+    let mut queue = device.bulk_queue().unwrap();
+    queue
+        .submit_write(&read_burst_command(0x4000, 100))
+        .unwrap();
+    let data = queue.read_pipelined(0x100, 64, 4).unwrap();
+    assert_eq!(data, &rom[0x4000..0x4064]);
+    assert_eq!(queue.in_flight(), 0);
+}
+
+#[test]
+fn pipelined_read_error_cancels_pending_transfers() {
+    let bus = MockBus::new();
+    let device = unlocked_firmware(&bus);
+    let mut queue = device.bulk_queue().unwrap();
+    queue
+        .submit_write(&read_burst_command(0x0000, 0x80))
+        .unwrap();
+    // the device runs out of data at a transfer boundary, so the next read times out
+    assert_eq!(
+        queue.read_pipelined(0x100, 64, 4),
+        Err(DriverError::UsbTimeout)
+    );
+    assert_eq!(queue.in_flight(), 0);
+    assert_eq!(queue.wait().unwrap(), None);
+}
+
+#[test]
+fn pipelined_write_error_cancels_pending_transfers() {
+    let bus = MockBus::new();
+    let device = unlocked_firmware(&bus);
+    let mut queue = device.bulk_queue().unwrap();
+    // the device resets after the first transfer, so the ones queued after it fail
+    let mut data = vec![Command::Reset as u8, 0x42];
+    data.resize(8, 0);
+    assert_eq!(
+        queue.write_pipelined(&data, 2, 4),
+        Err(DriverError::NoDevice)
+    );
+    assert_eq!(queue.in_flight(), 0);
+    assert_eq!(queue.wait().unwrap(), None);
+}
+
+#[test]
+fn bootloader_cannot_be_claimed_as_firmware() {
+    let bus = MockBus::new();
+    bus.attach(MockDevice::blank());
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+    match device.claim_firmware() {
+        Err(DriverError::WrongMode { kind }) => assert!(kind.is_bootloader()),
+        result => panic!("expected a mode error, got {:?}", result.err()),
+    }
+}
+
+#[test]
+fn cart_power_is_sequenced() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    mock.insert_cartridge(Mbc5::new(banked_rom(4), vec![]));
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+    let mut drv = CartDriver::initialize(device)
+        .unwrap()
+        .settle_delay(Duration::ZERO);
+    assert!(!drv.status().unwrap().is_powered());
+    assert_eq!(
+        drv.release_reset().unwrap_err(),
+        DriverError::CartNotPowered
+    );
+
+    // /RES stays asserted while VCART changes
+    let on = [CartMode::VCART | CartMode::RESET, CartMode::VCART];
+    let off = [CartMode::VCART | CartMode::RESET, CartMode::RESET];
+    drv.power_on().unwrap();
+    assert_eq!(mock.take_cart_mode_changes(), on);
+    let status = drv.status().unwrap();
+    assert!(status.is_powered() && !status.is_in_reset() && status.reset_pin);
+    drv.power_on().unwrap();
+    assert!(mock.take_cart_mode_changes().is_empty());
+
+    drv.assert_reset().unwrap();
+    assert!(!drv.status().unwrap().reset_pin);
+    drv.release_reset().unwrap();
+    drv.power_cycle(Duration::ZERO).unwrap();
+    assert_eq!(
+        mock.take_cart_mode_changes(),
+        [on[0], on[1], off[0], off[1], on[0], on[1]]
+    );
+
+    let device = drv.finish().unwrap();
+    assert_eq!(mock.take_cart_mode_changes(), off);
+    assert!(!mock.is_pipe_active());
+    assert!(mock.claimed_interfaces().is_empty());
+    assert!(device.kind.is_firmware());
+}
+
+#[test]
+fn dropped_cart_driver_powers_off() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+    let mut drv = CartDriver::initialize(device)
+        .unwrap()
+        .settle_delay(Duration::ZERO);
+    drv.power_on().unwrap();
+    drop(drv);
+    assert_eq!(mock.cart_mode(), CartMode::RESET);
+    assert!(!mock.is_pipe_active());
+    assert!(mock.claimed_interfaces().is_empty());
+}
+
+// A ROM with a valid header, titled "CARTPP TEST"
+fn rom_with_header(banks: usize) -> Vec<u8> {
+    let mut rom = banked_rom(banks);
+    rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x144].fill(0);
+    rom[0x134..0x13f].copy_from_slice(b"CARTPP TEST");
+    rom[0x147] = 0x19;
+    rom[0x148] = (banks / 2).trailing_zeros() as u8;
+    rom[0x14d] = CartHeader::from_rom(&rom).unwrap().calc_header_checksum();
+    rom
+}
+
+fn check_cart(mock: &MockDevice, bus: &Arc<MockBus>) -> CartCheck {
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+    let mut drv = CartDriver::initialize(device)
+        .unwrap()
+        .settle_delay(Duration::ZERO);
+    let check = drv.check(CHECK_PASSES).unwrap();
+    drv.finish().unwrap();
+    assert_eq!(mock.cart_mode(), CartMode::RESET);
+    check
+}
+
+#[test]
+fn clean_cart_passes_check() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    mock.insert_cartridge(Mbc5::new(rom_with_header(8), vec![]));
+    let check = check_cart(&mock, &bus);
+    assert_eq!(check.passes, CHECK_PASSES);
+    assert_eq!(check.verdict(), CartVerdict::Ok);
+    assert_eq!(check.header.title(), "CARTPP TEST");
+    assert_eq!(check.header.rom_size(), Some(128 * 1024));
+    assert_eq!(check.verdict().to_string(), "OK");
+}
+
+#[test]
+fn missing_cart_is_detected() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    let check = check_cart(&mock, &bus);
+    assert_eq!(check.verdict(), CartVerdict::NoCartridge);
+    assert_eq!(check.verdict().to_string(), "no cartridge");
+}
+
+#[test]
+fn dirty_contacts_are_detected() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    mock.insert_cartridge(DirtyContacts::new(
+        Mbc5::new(rom_with_header(8), vec![]),
+        1 << 3,
+        97,
+    ));
+    let check = check_cart(&mock, &bus);
+    assert_eq!(check.verdict(), CartVerdict::UnstableLines(1 << 3));
+    assert_eq!(check.verdict().to_string(), "dirty contacts (D3 unstable)");
+
+    // a line that never makes contact reads the same wrong value every time
+    let mut rom = rom_with_header(8);
+    rom.iter_mut().for_each(|byte| *byte &= !(1 << 1 | 1 << 6));
+    mock.insert_cartridge(Mbc5::new(rom, vec![]));
+    let check = check_cart(&mock, &bus);
+    assert_eq!(check.verdict(), CartVerdict::StuckLines(1 << 1 | 1 << 6));
+    assert_eq!(check.verdict().to_string(), "dirty contacts (D1, D6 stuck)");
+
+    let mut rom = rom_with_header(8);
+    rom[0x14d] ^= 0xff;
+    mock.insert_cartridge(Mbc5::new(rom, vec![]));
+    assert_eq!(
+        check_cart(&mock, &bus).verdict(),
+        CartVerdict::BadHeaderChecksum
+    );
+}
+
+#[test]
+fn rom_reads_require_power() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    mock.insert_cartridge(Mbc5::new(rom_with_header(8), vec![]));
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+    let mut drv = CartDriver::initialize(device)
+        .unwrap()
+        .settle_delay(Duration::ZERO);
+    let mut buffer = [0; 4];
+    assert_eq!(
+        drv.read_rom(0x0104, &mut buffer),
+        Err(DriverError::CartNotPowered)
+    );
+    drv.power_on().unwrap();
+    drv.read_rom(0x0104, &mut buffer).unwrap();
+    assert_eq!(buffer, NINTENDO_LOGO[..4]);
+    assert!(matches!(
+        drv.read_rom(0x7ffe, &mut buffer),
+        Err(DriverError::AddressOutOfRange {
+            addr: 0x7ffe,
+            len: 4
+        })
+    ));
+}
+
+fn dump_rom(bus: &Arc<MockBus>, dumper: RomDumper) -> Result<RomDump, DumpError> {
+    let device = Usb::list_devices(&bus.usb()).unwrap().remove(0);
+    let mut drv = CartDriver::initialize(device)
+        .unwrap()
+        .settle_delay(Duration::ZERO);
+    let mut events = Vec::new();
+    let dump = dumper.dump(&mut drv, |event| events.push(event))?;
+    assert!(matches!(events[0], DumpEvent::Started { banks, .. } if banks == dump.banks.len()));
+    assert_eq!(events.len(), dump.banks.len() + 1);
+    drv.finish().unwrap();
+    Ok(dump)
+}
+
+#[test]
+fn rom_is_dumped() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    let rom = rom_with_header(8);
+    mock.insert_cartridge(Mbc5::new(rom.clone(), vec![]));
+    let dump = dump_rom(&bus, RomDumper::new()).unwrap();
+    assert_eq!(dump.data, rom);
+    assert_eq!(dump.header.title(), "CARTPP TEST");
+    assert_eq!(dump.inconsistent_banks().count(), 0);
+    assert!(dump
+        .banks
+        .iter()
+        .all(|stats| stats.reads == DUMP_PASSES && stats.timing == ReadTiming::Fast));
+
+    // MBC1 banks 0x20, 0x40 and 0x60 can only be read in mode 1
+    let mut rom = rom_with_header(128);
+    rom[0x147] = 0x01;
+    rom[0x14d] = CartHeader::from_rom(&rom).unwrap().calc_header_checksum();
+    mock.insert_cartridge(Mbc1::new(rom.clone(), vec![]));
+    let dump = dump_rom(&bus, RomDumper::new().passes(3)).unwrap();
+    assert_eq!(dump.data.len(), 2 * 1024 * 1024);
+    assert!(dump.data == rom);
+    assert!(dump.banks.iter().all(|stats| stats.reads == 3));
+}
+
+#[test]
+fn mapper_must_support_the_rom_size() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    let mut rom = rom_with_header(2);
+    rom[0x147] = 0x00;
+    rom[0x14d] = CartHeader::from_rom(&rom).unwrap().calc_header_checksum();
+    mock.insert_cartridge(RomOnly::new(rom.clone()));
+    let dump = dump_rom(&bus, RomDumper::new()).unwrap();
+    assert_eq!(dump.data, rom);
+
+    // banks above 1 would read bank 1 again
+    let dumper = RomDumper::new().rom_size(Some(0x10000));
+    assert!(matches!(
+        dump_rom(&bus, dumper),
+        Err(DumpError::NoMapperRomSize(64))
+    ));
+
+    // an unknown mapper is only dumped if one is chosen explicitly
+    let mut rom = rom_with_header(8);
+    rom[0x147] = 0xfc;
+    rom[0x14d] = CartHeader::from_rom(&rom).unwrap().calc_header_checksum();
+    mock.insert_cartridge(Mbc3::new(rom.clone(), vec![]));
+    assert!(matches!(
+        dump_rom(&bus, RomDumper::new()),
+        Err(DumpError::UnsupportedMapper(Mapper::Other(0xfc)))
+    ));
+    let dump = dump_rom(&bus, RomDumper::new().mapper(Some(Mapper::Mbc3))).unwrap();
+    assert_eq!(dump.data, rom);
+}
+
+#[test]
+fn inconsistent_banks_are_read_again() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    let rom = rom_with_header(8);
+    // roughly every third bank read has one flipped bit
+    mock.insert_cartridge(DirtyContacts::new(
+        Mbc5::new(rom.clone(), vec![]),
+        1 << 3,
+        3 * 0x4000 + 5,
+    ));
+    let dump = dump_rom(&bus, RomDumper::new()).unwrap();
+    assert!(dump.data == rom);
+    assert!(dump.inconsistent_banks().count() > 0);
+    for stats in &dump.banks {
+        assert_eq!(stats.reads, DUMP_PASSES + stats.distinct - 1);
+    }
+
+    // every read is different
+    mock.insert_cartridge(DirtyContacts::new(Mbc5::new(rom, vec![]), 1 << 3, 1000));
+    assert!(matches!(
+        dump_rom(&bus, RomDumper::new().max_reads(4)),
+        Err(DumpError::NoConsensus { bank: 0, reads: 4 })
+    ));
+}
+
+#[test]
+fn force_ce_is_used_as_fallback() {
+    let bus = MockBus::new();
+    let mock = bus.attach(MockDevice::with_image(&test_image(1, 2, 0)));
+    let rom = rom_with_header(4);
+    let cart = DirtyContacts::new(Mbc5::new(rom.clone(), vec![]), 1 << 5, 1000).fast_only();
+    mock.insert_cartridge(cart.clone());
+    assert!(matches!(
+        dump_rom(&bus, RomDumper::new().max_reads(3)),
+        Err(DumpError::NoConsensus { bank: 0, reads: 3 })
+    ));
+
+    mock.insert_cartridge(cart);
+    let dumper = RomDumper::new().max_reads(3).force_ce_fallback(true);
+    let dump = dump_rom(&bus, dumper).unwrap();
+    assert!(dump.data == rom);
+    for stats in &dump.banks {
+        assert_eq!(stats.timing, ReadTiming::ForceCe);
+        assert_eq!(stats.reads, 3 + DUMP_PASSES);
+    }
+
+    mock.insert_cartridge(Mbc5::new(rom.clone(), vec![]));
+    let dump = dump_rom(&bus, RomDumper::new().timing(ReadTiming::ForceCe)).unwrap();
+    assert!(dump.data == rom);
+}